@@ -0,0 +1,495 @@
+use anyhow::{anyhow, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use flate2::read::GzDecoder;
+use sevenz_rust::{Password, SevenZReader};
+use std::fs::{self, File};
+use std::io::{self, sink, BufWriter, Cursor, Read, Seek, SeekFrom};
+use strum::Display;
+use tar::{Archive, EntryType};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+//a zip with no members is only an end of central directory record
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+//both POSIX "ustar\0" and GNU "ustar " headers
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC_END: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+/// The first bytes of a gzip stream
+pub const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+//every member of a canonical zip is stamped with the time TorrentZip uses, 1996-12-24 23:32:00
+const CANONICAL_ZIP_TIME: (u16, u8, u8, u8, u8, u8) = (1996, 12, 24, 23, 32, 0);
+
+/// Archive formats whose members are hashed as files in their own right
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
+pub enum ArchiveKind {
+    #[strum(serialize = "ZIP")]
+    Zip,
+    #[strum(serialize = "7z")]
+    SevenZip,
+    #[strum(serialize = "tar")]
+    Tar,
+    #[strum(serialize = "tar.gz")]
+    TarGz,
+    /// A single compressed file, whose only member is named after the file without its `.gz`
+    #[strum(serialize = "gzip")]
+    Gzip,
+}
+
+/// How far to look into archives inside archives
+#[derive(Copy, Clone, Debug)]
+pub struct Nesting {
+    /// Levels of archive to open, counting the outermost, archives nested deeper are hashed as files
+    pub max_depth: u32,
+    /// Recognise nested archives by their magic bytes as well as their extension
+    pub detect_content: bool,
+}
+
+impl ArchiveKind {
+    /// The kind of archive the file is, from the magic bytes at its start. The extension is only used when
+    /// the file cannot be read or is a short `.tar`, so a misnamed archive is still opened and a file
+    /// misnamed as an archive is hashed as a plain file
+    pub fn detect(path: &Utf8Path) -> Option<Self> {
+        match File::open(path).and_then(read_start) {
+            Ok(start) => Self::from_contents(&start, path, || File::open(path).and_then(|file| read_start(GzDecoder::new(file)))),
+            Err(_) => Self::from_extension(path),
+        }
+    }
+
+    // the kind of archive from the start of its contents, and for gzip the start of what it decompresses to
+    fn from_contents(start: &[u8], path: &Utf8Path, gzip_start: impl FnOnce() -> std::io::Result<Vec<u8>>) -> Option<Self> {
+        if start.starts_with(ZIP_MAGIC) || start.starts_with(EMPTY_ZIP_MAGIC) {
+            Some(ArchiveKind::Zip)
+        } else if start.starts_with(SEVEN_ZIP_MAGIC) {
+            Some(ArchiveKind::SevenZip)
+        } else if start.get(TAR_MAGIC_OFFSET..TAR_MAGIC_END) == Some(TAR_MAGIC) {
+            Some(ArchiveKind::Tar)
+        } else if start.starts_with(GZIP_MAGIC) {
+            //whether it is a tar depends on what was compressed
+            if gzip_start().is_ok_and(|inner| inner.get(TAR_MAGIC_OFFSET..TAR_MAGIC_END) == Some(TAR_MAGIC)) {
+                Some(ArchiveKind::TarGz)
+            } else {
+                Some(ArchiveKind::Gzip)
+            }
+        } else if start.len() < TAR_MAGIC_END {
+            //too short to have a tar header, but a tar of nothing but end blocks has no magic either
+            Self::from_extension(path).filter(|kind| *kind == ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of archive the path names, from its extension
+    pub fn from_extension(path: &Utf8Path) -> Option<Self> {
+        let extension = path.extension()?;
+        let is_tar = |stem: Option<&str>| {
+            stem.is_some_and(|stem| Utf8Path::new(stem).extension().is_some_and(|e| e.eq_ignore_ascii_case("tar")))
+        };
+        if extension.eq_ignore_ascii_case("tgz") || (extension.eq_ignore_ascii_case("gz") && is_tar(path.file_stem())) {
+            Some(ArchiveKind::TarGz)
+        } else if extension.eq_ignore_ascii_case("gz") {
+            Some(ArchiveKind::Gzip)
+        } else if extension.eq_ignore_ascii_case("zip") {
+            Some(ArchiveKind::Zip)
+        } else if extension.eq_ignore_ascii_case("7z") {
+            Some(ArchiveKind::SevenZip)
+        } else if extension.eq_ignore_ascii_case("tar") {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Open the archive at the path for reading its members, and those of the archives nested inside it
+    pub fn open(self, path: &Utf8Path, nesting: Nesting) -> Result<Box<dyn ArchiveReader>> {
+        let archive: Box<dyn ArchiveReader> = match self {
+            ArchiveKind::Zip => Box::new(ZipArchive::new(File::open(path)?)?),
+            ArchiveKind::SevenZip => Box::new(SevenZReader::open(path, Password::empty())?),
+            ArchiveKind::Tar => Box::new(Archive::new(File::open(path)?)),
+            ArchiveKind::TarGz => Box::new(Archive::new(GzDecoder::new(File::open(path)?))),
+            ArchiveKind::Gzip => Box::new(GzipFile::open(path)?),
+        };
+        Ok(nested(archive, nesting, 1))
+    }
+
+    // open an archive that was read into memory from inside another
+    fn open_bytes(self, bytes: Vec<u8>, path: &Utf8Path) -> Result<Box<dyn ArchiveReader>> {
+        let length = bytes.len() as u64;
+        Ok(match self {
+            ArchiveKind::Zip => Box::new(ZipArchive::new(Cursor::new(bytes))?),
+            ArchiveKind::SevenZip => Box::new(SevenZReader::new(Cursor::new(bytes), length, Password::empty())?),
+            ArchiveKind::Tar => Box::new(Archive::new(Cursor::new(bytes))),
+            ArchiveKind::TarGz => Box::new(Archive::new(GzDecoder::new(Cursor::new(bytes)))),
+            ArchiveKind::Gzip => Box::new(GzipFile::new(path, Cursor::new(bytes))?),
+        })
+    }
+}
+
+// enough of the start of a file to find the magic bytes of any kind of archive
+fn read_start(reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut start = Vec::with_capacity(TAR_MAGIC_END);
+    reader.take(TAR_MAGIC_END as u64).read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// Called by `ArchiveReader::for_each_file_with_crc` with the path, uncompressed size, recorded CRC32 and contents
+/// of each member
+pub type EachWithCrc<'a> = dyn FnMut(&Utf8Path, u64, Option<u32>, &mut dyn Read) -> Result<()> + 'a;
+
+/// The files inside an archive, each read through its own stream
+pub trait ArchiveReader {
+    /// Call `each` with the path, uncompressed size and contents of every file in the archive, in archive order.
+    ///
+    /// Directories and members whose path would escape the archive are skipped, and each member's
+    /// contents fail to read if they are not the size recorded for them. An error from `each`
+    /// stops the iteration and is returned.
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()>;
+
+    /// Like `for_each_file`, also giving the CRC32 the archive records for a member if it has one, so `each` can
+    /// use that rather than reading the contents. Nothing checks the recorded CRC unless the contents are read
+    fn for_each_file_with_crc(&mut self, each: &mut EachWithCrc<'_>) -> Result<()> {
+        self.for_each_file(&mut |inner_path, size, file| each(inner_path, size, None, file))
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader for ZipArchive<R> {
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()> {
+        self.for_each_file_with_crc(&mut |inner_path, size, _, file| each(inner_path, size, file))
+    }
+
+    fn for_each_file_with_crc(&mut self, each: &mut EachWithCrc<'_>) -> Result<()> {
+        for i in 0..self.len() {
+            let mut member = self.by_index(i)?;
+            if member.is_dir() {
+                continue;
+            }
+
+            if let Some(inner_path) = member.enclosed_name().and_then(|p| Utf8PathBuf::try_from(p).ok()) {
+                //sizes stay u64 all the way through, so ZIP64 members over 4GB are checked in full
+                let expected_size = member.size();
+                //the contents are checked against the crc as they are decompressed
+                let crc = member.crc32();
+                each(&inner_path, expected_size, Some(crc), &mut SizeCheckedReader::new(&mut member, expected_size))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader for SevenZReader<R> {
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()> {
+        let mut result = Ok(());
+        self.for_each_entries(|entry, reader| {
+            if !entry.is_directory() {
+                if let Some(inner_path) = enclosed_name(entry.name()) {
+                    if let Err(e) = each(&inner_path, entry.size(), &mut SizeCheckedReader::new(&mut *reader, entry.size())) {
+                        result = Err(e);
+                        return Ok(false);
+                    }
+                }
+            }
+            //members of a solid archive share one stream, so whatever was not read has to be skipped
+            std::io::copy(reader, &mut sink())?;
+            Ok(true)
+        })?;
+        result
+    }
+}
+
+impl<R: Read> ArchiveReader for Archive<R> {
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()> {
+        for entry in self.entries()? {
+            let mut entry = entry?;
+            //links and special files have no contents of their own
+            if !matches!(entry.header().entry_type(), EntryType::Regular | EntryType::Continuous) {
+                continue;
+            }
+            let inner_path = entry.path()?.to_str().and_then(enclosed_name);
+            if let Some(inner_path) = inner_path {
+                let expected_size = entry.size();
+                each(&inner_path, expected_size, &mut SizeCheckedReader::new(&mut entry, expected_size))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// a single gzip compressed file, seen as an archive of the file it decompresses to
+struct GzipFile<R> {
+    inner_path: Utf8PathBuf,
+    size: u64,
+    reader: R,
+}
+
+impl GzipFile<File> {
+    fn open(path: &Utf8Path) -> Result<Self> {
+        Self::new(path, File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> GzipFile<R> {
+    fn new(path: &Utf8Path, mut reader: R) -> Result<Self> {
+        let inner_path = path
+            .file_stem()
+            .and_then(enclosed_name)
+            .ok_or_else(|| anyhow!("No file name inside {}", path))?;
+        //the size is only recorded modulo 4GB, in the last four bytes
+        let mut trailer = [0; 4];
+        reader.seek(SeekFrom::End(-4))?;
+        reader.read_exact(&mut trailer)?;
+        reader.rewind()?;
+        Ok(Self {
+            inner_path,
+            size: u64::from(u32::from_le_bytes(trailer)),
+            reader,
+        })
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader for GzipFile<R> {
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()> {
+        //not size checked as the recorded size can wrap, the decoder checks it (and the crc) against what it read instead
+        each(&self.inner_path, self.size, &mut GzDecoder::new(&mut self.reader))
+    }
+}
+
+// the archive, opening the archives inside it as well if it is not already as deep as allowed
+fn nested(archive: Box<dyn ArchiveReader>, nesting: Nesting, depth: u32) -> Box<dyn ArchiveReader> {
+    if depth < nesting.max_depth {
+        Box::new(NestedArchive { archive, nesting, depth })
+    } else {
+        archive
+    }
+}
+
+// gives the members of any archive inside the archive as if the nested archive were a directory of them,
+// e.g. `inner.zip/rom.bin`
+struct NestedArchive {
+    archive: Box<dyn ArchiveReader>,
+    nesting: Nesting,
+    depth: u32,
+}
+
+impl ArchiveReader for NestedArchive {
+    fn for_each_file(&mut self, each: &mut dyn FnMut(&Utf8Path, u64, &mut dyn Read) -> Result<()>) -> Result<()> {
+        self.for_each_file_with_crc(&mut |inner_path, size, _, file| each(inner_path, size, file))
+    }
+
+    fn for_each_file_with_crc(&mut self, each: &mut EachWithCrc<'_>) -> Result<()> {
+        let (nesting, depth) = (self.nesting, self.depth);
+        self.archive.for_each_file_with_crc(&mut |inner_path, size, crc, file| {
+            //telling an archive apart by its contents means decompressing the start of every member
+            let start = if nesting.detect_content {
+                read_start(&mut *file)?
+            } else {
+                Vec::new()
+            };
+            let maybe_archive = ArchiveKind::from_extension(inner_path).is_some()
+                || (nesting.detect_content && ArchiveKind::from_contents(&start, inner_path, || Ok(Vec::new())).is_some());
+            if !maybe_archive {
+                return each(inner_path, size, crc, &mut start.as_slice().chain(file));
+            }
+
+            //only archives are read into memory, they need to seek to be opened
+            let mut bytes = start;
+            file.read_to_end(&mut bytes)?;
+            let kind = if nesting.detect_content {
+                ArchiveKind::from_contents(&bytes, inner_path, || read_start(GzDecoder::new(bytes.as_slice())))
+            } else {
+                ArchiveKind::from_extension(inner_path)
+            };
+            let Some(kind) = kind else {
+                return each(inner_path, size, crc, &mut bytes.as_slice());
+            };
+            match kind.open_bytes(bytes, inner_path) {
+                Ok(archive) => nested(archive, nesting, depth + 1).for_each_file_with_crc(
+                    &mut |member_path, member_size, member_crc, member| {
+                        each(&inner_path.join(member_path), member_size, member_crc, member)
+                    },
+                ),
+                Err(e) => {
+                    //the rest of the outer archive is still worth reading
+                    eprintln!("Failed to process {} file {}: {}", kind, inner_path, e);
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+// the path of an archive member without any `./`, if it stays inside the archive (like `ZipFile::enclosed_name`)
+fn enclosed_name(name: &str) -> Option<Utf8PathBuf> {
+    let mut path = Utf8PathBuf::new();
+    for component in Utf8Path::new(name).components() {
+        match component {
+            Utf8Component::Normal(part) => path.push(part),
+            Utf8Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!path.as_str().is_empty()).then_some(path)
+}
+
+// fails the read if the stream ends before, or runs past, the size recorded for it (e.g. in a zip
+// central directory), so a truncated or mis-sized member is reported rather than silently hashed
+struct SizeCheckedReader<R> {
+    inner: R,
+    expected: u64,
+    read: u64,
+}
+
+impl<R: Read> SizeCheckedReader<R> {
+    fn new(inner: R, expected: u64) -> Self {
+        Self {
+            inner,
+            expected,
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for SizeCheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("read more than the expected {} bytes", self.expected),
+            ));
+        }
+        if read == 0 && !buf.is_empty() && self.read < self.expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("read {} of the expected {} bytes", self.read, self.expected),
+            ));
+        }
+        Ok(read)
+    }
+}
+
+/// Write a zip of the members in canonical form, so the same contents always give the same archive byte for byte:
+/// members sorted by their lowercased name then by name, deflated at level 9, all stamped 1996-12-24 23:32:00, with
+/// no directory entries, extra fields or comments, and zip64 only for members of 4 GiB or more.
+/// A member without a source file is written empty. The zip is written beside the path and renamed into place
+/// once complete, so a failed write leaves nothing behind
+pub fn write_canonical_zip(path: &Utf8Path, members: &[(&str, Option<&Utf8Path>)]) -> Result<()> {
+    let mut sorted = members.to_vec();
+    sorted.sort_by(|(a, _), (b, _)| a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b)));
+    let partial = Utf8PathBuf::from(format!("{}.part", path));
+    match write_zip_members(&partial, &sorted).and_then(|()| Ok(fs::rename(&partial, path)?)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+fn write_zip_members(path: &Utf8Path, members: &[(&str, Option<&Utf8Path>)]) -> Result<()> {
+    let (year, month, day, hour, minute, second) = CANONICAL_ZIP_TIME;
+    let time = DateTime::from_date_and_time(year, month, day, hour, minute, second)?;
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    for (name, source) in members {
+        let mut file = source.map(File::open).transpose()?;
+        let size = match &file {
+            Some(file) => file.metadata()?.len(),
+            None => 0,
+        };
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(Some(9))
+            .last_modified_time(time)
+            .large_file(size >= u64::from(u32::MAX));
+        zip.start_file(*name, options)?;
+        if let Some(file) = file.as_mut() {
+            io::copy(file, &mut zip)?;
+        }
+    }
+    let file = zip.finish()?.into_inner().map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::io::Write;
+
+    const OVER_4_GIB: u64 = (1 << 32) + 3;
+
+    // a stream of the given length that never fills the buffer it is read into, so gigabytes read in moments
+    struct Unwritten {
+        left: u64,
+    }
+
+    impl Read for Unwritten {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = buf.len().min(usize::try_from(self.left).unwrap_or(usize::MAX));
+            self.left -= read as u64;
+            Ok(read)
+        }
+    }
+
+    // read the stream to its end, returning how many bytes it gave
+    fn read_all(reader: &mut impl Read) -> std::io::Result<u64> {
+        let mut buf = vec![0; 1 << 20];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(total),
+                read => total += read as u64,
+            }
+        }
+    }
+
+    #[test]
+    fn size_checked_reader_reads_members_over_4_gib() -> Result<()> {
+        let mut reader = SizeCheckedReader::new(Unwritten { left: OVER_4_GIB }, OVER_4_GIB);
+        assert_eq!(read_all(&mut reader)?, OVER_4_GIB);
+        Ok(())
+    }
+
+    #[test]
+    fn size_checked_reader_fails_past_4_gib_boundaries() {
+        //a size recorded modulo 4GB must not be mistaken for the real one
+        let mut truncated = SizeCheckedReader::new(Unwritten { left: OVER_4_GIB - 1 }, OVER_4_GIB);
+        assert_eq!(read_all(&mut truncated).map_err(|e| e.kind()), Err(std::io::ErrorKind::UnexpectedEof));
+        let mut wrapped = SizeCheckedReader::new(Unwritten { left: OVER_4_GIB }, OVER_4_GIB - (1 << 32));
+        assert_eq!(read_all(&mut wrapped).map_err(|e| e.kind()), Err(std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn zip64_members_are_read() -> Result<()> {
+        let temp = TempDir::new();
+        let path = temp.path().join("large.zip");
+        let mut zip = ZipWriter::new(File::create(&path)?);
+        zip.start_file("game.bin", SimpleFileOptions::default().large_file(true))?;
+        zip.write_all(b"zip64 contents")?;
+        zip.finish()?;
+        //the local header's extra field starts with the zip64 record, header id 1
+        let bytes = fs::read(&path)?;
+        assert_eq!(bytes[30 + "game.bin".len()..][..2], [1, 0]);
+
+        let nesting = Nesting {
+            max_depth: 1,
+            detect_content: false,
+        };
+        let mut members = Vec::new();
+        ArchiveKind::detect(&path)
+            .ok_or_else(|| anyhow!("Not detected as an archive"))?
+            .open(&path, nesting)?
+            .for_each_file_with_crc(&mut |inner_path, size, crc, file| {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                members.push((inner_path.to_owned(), size, crc, contents));
+                Ok(())
+            })?;
+        let crc = crc32fast::hash(b"zip64 contents");
+        assert_eq!(members, [(Utf8PathBuf::from("game.bin"), 14, Some(crc), b"zip64 contents".to_vec())]);
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::warn;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+
+// hidden, so a scan of the directory never picks it up as a file
+const CHECKPOINT_NAME: &str = ".rcr2-checkpoint";
+
+/// The files a scan of a directory has finished with, saved next to them so an interrupted scan can be resumed.
+/// Every file finished is recorded whatever it matched, misses included
+pub struct Checkpoint {
+    path: Utf8PathBuf,
+    done: HashSet<String>,
+    // files finished since the checkpoint was last saved
+    unsaved: usize,
+    interval: usize,
+    // saving failed once, so it is not tried again
+    failed: bool,
+}
+
+impl Checkpoint {
+    /// Start recording the files scanned in the directory, carrying on from the files a previous scan saved if resuming
+    pub fn open(directory: &Utf8Path, interval: usize, resume: bool) -> Result<Self> {
+        let path = directory.join(CHECKPOINT_NAME);
+        let done = match (resume, File::open(&path)) {
+            (true, Ok(file)) => {
+                serde_json::from_reader(BufReader::new(file)).with_context(|| format!("Failed to read checkpoint {}", path))?
+            }
+            (true, Err(e)) if e.kind() == ErrorKind::NotFound => {
+                warn!("no checkpoint to resume from in {}, scanning every file", directory);
+                HashSet::new()
+            }
+            (true, Err(e)) => return Err(e).with_context(|| format!("Failed to open checkpoint {}", path)),
+            (false, _) => {
+                if path.exists() {
+                    warn!("starting over, the interrupted scan of {} can be resumed with --resume", directory);
+                }
+                HashSet::new()
+            }
+        };
+        Ok(Self {
+            path,
+            done,
+            unsaved: 0,
+            interval: interval.max(1),
+            failed: false,
+        })
+    }
+
+    /// Number of files finished by previous scans
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn contains(&self, path: &Utf8Path) -> bool {
+        self.done.contains(path.as_str())
+    }
+
+    /// Record a file as finished, returning whether enough have been since the last save for it to be saved again
+    pub fn finish(&mut self, path: &Utf8Path) -> bool {
+        if self.done.insert(path.as_str().to_owned()) {
+            self.unsaved += 1;
+        }
+        self.unsaved >= self.interval
+    }
+
+    /// Write the files finished so far, replacing the previous checkpoint only once the new one is complete.
+    /// A checkpoint that cannot be written is warned about once, the scan carries on without it
+    pub fn save(&mut self) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.write() {
+            warn!("failed to save checkpoint {}, an interrupted scan will start over: {:#}", self.path, e);
+            self.failed = true;
+        }
+        self.unsaved = 0;
+    }
+
+    fn write(&self) -> Result<()> {
+        let partial = self.path.with_extension("part");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, &self.done)?;
+        writer.flush()?;
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+
+    /// Delete the checkpoint once the scan is complete
+    pub fn remove(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove checkpoint {}", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,24 +1,33 @@
-use crate::models::{DataFile, Game, HashType, MatchType, Rom, ScannedFile};
+use crate::models::{DatabaseStats, Disk, Game, HashType, Header, MatchType, Rom, RomMatch, ScannedFile, SetStatus};
 use anyhow::{anyhow, Context, Result};
 use camino::Utf8Path;
-use rusqlite::{params, Connection};
+use log::debug;
+use rusqlite::{params, Connection, OptionalExtension, Params, Transaction};
 use std::{collections::HashMap, str::FromStr};
 
-macro_rules! debug_log {
-    ($debug:expr, $($arg:tt)*) => {
-        if $debug {
-            eprintln!("{}", format!("Debug: {}", format!($($arg)*)));
+/// Which side of a range a value limits a column from, for the range criteria of a rom search
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bound {
+    AtLeast,
+    AtMost,
+}
+
+impl Bound {
+    pub fn operator(self) -> &'static str {
+        match self {
+            Bound::AtLeast => ">=",
+            Bound::AtMost => "<=",
         }
-    };
+    }
 }
 
 pub struct Database {
     conn: Connection,
 }
 
-pub fn check_for_database(path: &Utf8Path, debug: bool) -> Result<Database> {
+pub fn check_for_database(path: &Utf8Path) -> Result<Database> {
     if path.is_file() {
-        debug_log!(debug, "database file {} exists, will attempt to connect", path);
+        debug!("database file {} exists, will attempt to connect", path);
         let db = Database::new(path).context("Failed to connect to database")?;
         Ok(db)
     } else {
@@ -26,19 +35,250 @@ pub fn check_for_database(path: &Utf8Path, debug: bool) -> Result<Database> {
     }
 }
 
+// rom rows as seen by queries, independent of whether rom content is normalized or not
+const FLAT_ROM_ENTRIES_VIEW: &str = "CREATE VIEW IF NOT EXISTS rom_entries AS
+    SELECT game_name, name, size, crc, md5, sha1, sha256 FROM roms";
+
+const NORMALIZED_ROM_ENTRIES_VIEW: &str = "CREATE VIEW IF NOT EXISTS rom_entries AS
+    SELECT r.game_name, r.name, c.size, c.crc, c.md5, c.sha1, c.sha256
+    FROM roms r
+    JOIN rom_content c ON c.id = r.content_id";
+
+// hash lookups on flat roms, normalized databases index `rom_content` instead
+const ROMS_HASH_INDICES: &str = "CREATE INDEX IF NOT EXISTS roms_crc ON roms(crc);
+    CREATE INDEX IF NOT EXISTS roms_md5 ON roms(md5);
+    CREATE INDEX IF NOT EXISTS roms_sha1 ON roms(sha1);
+    CREATE INDEX IF NOT EXISTS roms_sha256 ON roms(sha256);";
+
+const DISKS_TABLE: &str = "CREATE TABLE IF NOT EXISTS disks (
+    game_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    sha1 TEXT,
+    md5 TEXT,
+    PRIMARY KEY (game_name, name),
+    FOREIGN KEY(game_name) REFERENCES games(name) ON DELETE CASCADE
+)";
+
+const DISKS_SHA1_INDEX: &str = "CREATE INDEX IF NOT EXISTS disks_sha1 ON disks(sha1)";
+
+// files are matched to disks too, which have no rom row, and to roms that a later import may drop, so the game and
+// rom names are not a foreign key into `roms`. `prune --scanned-files` removes those left matched to nothing
+const SCANNED_FILES_TABLE: &str = "CREATE TABLE IF NOT EXISTS scanned_files (
+    base_path TEXT NOT NULL,
+    path TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    hash_type TEXT NOT NULL,
+    match_type TEXT NOT NULL,
+    game_name TEXT,
+    rom_name TEXT,
+    size INTEGER,
+    header_offset INTEGER,
+    first_seen TIMESTAMP,
+    last_scanned TIMESTAMP
+)";
+
+// scanned files matched to a rom or disk that is no longer in the database, disks are stored under their CHD file name
+const ORPHANED_SCANNED_FILES: &str = "game_name IS NOT NULL
+    AND NOT EXISTS (
+        SELECT 1 FROM rom_entries r WHERE r.game_name = scanned_files.game_name AND r.name = scanned_files.rom_name
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM disks d WHERE d.game_name = scanned_files.game_name AND d.name || '.chd' = scanned_files.rom_name
+    )";
+
+// the header of the most recently merged DAT, a single row
+const METADATA_TABLE: &str = "CREATE TABLE IF NOT EXISTS metadata (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    version TEXT NOT NULL
+)";
+
+// schema changes, in order, each run once on databases created before it; the database's user_version is the
+// number applied so far, version 0 databases may already have some of them so every step checks first
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    add_sha256_column,
+    add_rom_entries_view,
+    add_scanned_file_size_column,
+    add_scanned_file_header_offset_column,
+    add_disks_table,
+    add_metadata_table,
+    add_rom_hash_indices,
+    add_game_source_column,
+    drop_scanned_file_rom_key,
+    add_scanned_file_date_columns,
+];
+
+// databases created before sha256 was supported lack the column, and the view selecting it
+fn add_sha256_column(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "roms")? {
+        let content_table = if table_exists(conn, "rom_content")? {
+            "rom_content"
+        } else {
+            "roms"
+        };
+        if !column_exists(conn, content_table, "sha256")? {
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN sha256 TEXT", content_table), [])?;
+            conn.execute("DROP VIEW IF EXISTS rom_entries", [])?;
+        }
+    }
+    Ok(())
+}
+
+// databases created before rom content could be normalized have no view
+fn add_rom_entries_view(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "rom_content")? {
+        conn.execute(NORMALIZED_ROM_ENTRIES_VIEW, [])?;
+        conn.execute("CREATE INDEX IF NOT EXISTS rom_content_sha256 ON rom_content(sha256)", [])?;
+    } else if table_exists(conn, "roms")? {
+        conn.execute(FLAT_ROM_ENTRIES_VIEW, [])?;
+    }
+    Ok(())
+}
+
+// files scanned before sizes were recorded have no size
+fn add_scanned_file_size_column(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "scanned_files")? && !column_exists(conn, "scanned_files", "size")? {
+        conn.execute("ALTER TABLE scanned_files ADD COLUMN size INTEGER", [])?;
+    }
+    Ok(())
+}
+
+// files scanned before header offsets were recorded fall back to the header rules given on the command line
+fn add_scanned_file_header_offset_column(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "scanned_files")? && !column_exists(conn, "scanned_files", "header_offset")? {
+        conn.execute("ALTER TABLE scanned_files ADD COLUMN header_offset INTEGER", [])?;
+    }
+    Ok(())
+}
+
+fn add_disks_table(conn: &Connection) -> Result<()> {
+    conn.execute(DISKS_TABLE, [])?;
+    conn.execute(DISKS_SHA1_INDEX, [])?;
+    Ok(())
+}
+
+// the header is filled in by the next merge
+fn add_metadata_table(conn: &Connection) -> Result<()> {
+    conn.execute(METADATA_TABLE, [])?;
+    Ok(())
+}
+
+// without these every hash lookup while scanning is a full scan of the roms table
+fn add_rom_hash_indices(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "roms")? && !table_exists(conn, "rom_content")? {
+        conn.execute_batch(ROMS_HASH_INDICES)?;
+    }
+    Ok(())
+}
+
+// games imported before sources were recorded have none
+fn add_game_source_column(conn: &Connection) -> Result<()> {
+    if !column_exists(conn, "games", "source")? {
+        conn.execute("ALTER TABLE games ADD COLUMN source TEXT", [])?;
+    }
+    Ok(())
+}
+
+// the key was never enforced, and with enforcement on it would reject every file matched to a disk
+fn drop_scanned_file_rom_key(conn: &Connection) -> Result<()> {
+    if !table_exists(conn, "scanned_files")? {
+        return Ok(());
+    }
+    let keys: i64 = conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_list('scanned_files')", [], |row| row.get(0))?;
+    if keys > 0 {
+        conn.execute("ALTER TABLE scanned_files RENAME TO scanned_files_old", [])?;
+        conn.execute(SCANNED_FILES_TABLE, [])?;
+        conn.execute_batch(
+            "INSERT INTO scanned_files (base_path, path, hash, hash_type, match_type, game_name, rom_name, size, header_offset)
+                SELECT base_path, path, hash, hash_type, match_type, game_name, rom_name, size, header_offset
+                FROM scanned_files_old;
+            DROP TABLE scanned_files_old;",
+        )?;
+    }
+    Ok(())
+}
+
+// files scanned before dates were recorded have neither, until they are scanned or found unchanged again
+fn add_scanned_file_date_columns(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "scanned_files")? {
+        for column in ["first_seen", "last_scanned"] {
+            if !column_exists(conn, "scanned_files", column)? {
+                conn.execute(&format!("ALTER TABLE scanned_files ADD COLUMN {} TIMESTAMP", column), [])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, name: &str) -> Result<bool> {
+    let count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1", [name], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2", [table, column], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+// a LIKE pattern for the paths under the directory, or the members of the archive, with the wildcards a name may
+// contain escaped by '\'
+fn under_pattern(directory: &str) -> String {
+    let escaped = directory.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("{}/%", escaped.trim_end_matches('/'))
+}
+
 impl Database {
     pub fn new(path: &Utf8Path) -> Result<Self> {
         let conn = Connection::open(path)?;
-        Ok(Self { conn })
+        //sqlite leaves foreign keys unenforced unless asked, per connection
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let mut db = Self { conn };
+        db.migrate().context("Failed to upgrade database schema")?;
+        Ok(db)
     }
 
-    pub fn initialize(&mut self) -> Result<()> {
+    // bring an initialized database up to the current schema, a new one is created at it by `initialize`
+    fn migrate(&mut self) -> Result<()> {
+        if !table_exists(&self.conn, "games")? {
+            return Ok(());
+        }
+        let version: usize = self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(anyhow!(
+                "Database schema version {} is newer than this version of rcr2 supports ({})",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        if version == MIGRATIONS.len() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        for migration in &MIGRATIONS[version..] {
+            migration(&tx)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Whether rom content (size and hashes) is stored in the shared `rom_content` table
+    pub fn is_normalized(&self) -> Result<bool> {
+        table_exists(&self.conn, "rom_content")
+    }
+
+    pub fn initialize(&mut self, normalized: bool) -> Result<()> {
+        let already_normalized = self.is_normalized()?;
         let tx = self.conn.transaction()?;
 
         tx.execute(
             "CREATE TABLE IF NOT EXISTS games (
                 name TEXT PRIMARY KEY,
-                description TEXT NOT NULL
+                description TEXT NOT NULL,
+                source TEXT
             )",
             [],
         )?;
@@ -51,34 +291,109 @@ impl Database {
                 crc TEXT,
                 md5 TEXT,
                 sha1 TEXT,
+                sha256 TEXT,
                 PRIMARY KEY (game_name, name),
                 FOREIGN KEY(game_name) REFERENCES games(name) ON DELETE CASCADE
             )",
             [],
         )?;
 
-        tx.execute(
-            "CREATE TABLE IF NOT EXISTS scanned_files (
-                base_path TEXT NOT NULL,
-                path TEXT PRIMARY KEY,
-                hash TEXT NOT NULL,
-                hash_type TEXT NOT NULL,
-                match_type TEXT NOT NULL,
-                game_name TEXT,
-                rom_name TEXT,
-                FOREIGN KEY(game_name, rom_name) REFERENCES roms(game_name, name)
-            )",
-            [],
-        )?;
+        tx.execute(SCANNED_FILES_TABLE, [])?;
+
+        tx.execute(DISKS_TABLE, [])?;
+        tx.execute(DISKS_SHA1_INDEX, [])?;
+        tx.execute(METADATA_TABLE, [])?;
+
+        if !already_normalized {
+            tx.execute(FLAT_ROM_ENTRIES_VIEW, [])?;
+            tx.execute_batch(ROMS_HASH_INDICES)?;
+        }
+
+        //everything above is the current schema, so there is nothing left to migrate
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
 
         tx.commit()?;
+
+        if normalized {
+            self.shrink_roms()?;
+        }
         Ok(())
     }
 
+    /// Move rom sizes and hashes into a shared `rom_content` table, so that identical roms
+    /// across games are stored once and hash lookups hit a single indexed table.
+    ///
+    /// Returns the number of roms and the number of unique content entries they now share,
+    /// or `None` if the database was already normalized.
+    pub fn shrink_roms(&mut self) -> Result<Option<(i64, i64)>> {
+        if self.is_normalized()? {
+            return Ok(None);
+        }
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute_batch(
+            "CREATE TABLE rom_content (
+                id INTEGER PRIMARY KEY,
+                size INTEGER NOT NULL,
+                crc TEXT,
+                md5 TEXT,
+                sha1 TEXT,
+                sha256 TEXT
+            );
+
+            INSERT INTO rom_content (size, crc, md5, sha1, sha256)
+                SELECT DISTINCT size, crc, md5, sha1, sha256 FROM roms;
+
+            CREATE TABLE roms_normalized (
+                game_name TEXT NOT NULL,
+                name TEXT NOT NULL,
+                content_id INTEGER NOT NULL,
+                PRIMARY KEY (game_name, name),
+                FOREIGN KEY(game_name) REFERENCES games(name) ON DELETE CASCADE,
+                FOREIGN KEY(content_id) REFERENCES rom_content(id)
+            );
+
+            INSERT INTO roms_normalized (game_name, name, content_id)
+                SELECT r.game_name, r.name, c.id
+                FROM roms r
+                JOIN rom_content c
+                  ON c.size = r.size AND c.crc IS r.crc AND c.md5 IS r.md5 AND c.sha1 IS r.sha1 AND c.sha256 IS r.sha256;
+
+            DROP VIEW IF EXISTS rom_entries;
+            DROP TABLE roms;
+            ALTER TABLE roms_normalized RENAME TO roms;
+
+            CREATE INDEX rom_content_crc ON rom_content(crc);
+            CREATE INDEX rom_content_md5 ON rom_content(md5);
+            CREATE INDEX rom_content_sha1 ON rom_content(sha1);
+            CREATE INDEX rom_content_sha256 ON rom_content(sha256);
+            CREATE INDEX roms_content_id ON roms(content_id);",
+        )?;
+        tx.execute(NORMALIZED_ROM_ENTRIES_VIEW, [])?;
+
+        let rom_count: i64 = tx.query_row("SELECT COUNT(*) FROM roms", [], |row| row.get(0))?;
+        let content_count: i64 = tx.query_row("SELECT COUNT(*) FROM rom_content", [], |row| row.get(0))?;
+
+        tx.commit()?;
+
+        //reclaim the space used by the old roms table
+        self.conn.execute("VACUUM", [])?;
+
+        Ok(Some((rom_count, content_count)))
+    }
+
+    /// Record a scanned file, replacing any earlier record of the same path but keeping when it was first seen.
+    /// There is one record per file, so a file matching several roms is stored with whichever of them the caller chose
     pub fn store_file(&self, file: &ScannedFile) -> Result<()> {
         self.conn.execute(
-            "INSERT OR REPLACE INTO scanned_files (base_path, path, hash, hash_type, match_type, game_name, rom_name)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO scanned_files
+                (base_path, path, hash, hash_type, match_type, game_name, rom_name, size, header_offset, first_seen, last_scanned)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
+             ON CONFLICT(path) DO UPDATE SET
+                base_path = excluded.base_path, hash = excluded.hash, hash_type = excluded.hash_type,
+                match_type = excluded.match_type, game_name = excluded.game_name, rom_name = excluded.rom_name,
+                size = excluded.size, header_offset = excluded.header_offset, last_scanned = excluded.last_scanned",
             params![
                 file.base_path,
                 file.path,
@@ -86,43 +401,39 @@ impl Database {
                 file.hash_type.to_string(),
                 file.match_type.to_string(),
                 file.game_name,
-                file.rom_name
+                file.rom_name,
+                file.size,
+                file.header_offset
             ],
         )?;
         Ok(())
     }
 
-    pub fn merge_data(&mut self, data: DataFile) -> Result<()> {
-        let tx = self.conn.transaction()?;
-
-        for game in data.games {
-            tx.execute(
-                "INSERT OR REPLACE INTO games (name, description) 
-                 VALUES (?1, ?2)",
-                params![game.name, game.description],
-            )?;
-
-            // Delete existing ROMs for this game
-            tx.execute("DELETE FROM roms WHERE game_name = ?1", params![game.name])?;
-
-            // Insert new ROMs
-            for rom in game.roms {
-                tx.execute(
-                    "INSERT INTO roms (game_name, name, size, crc, md5, sha1) 
-                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-                    params![game.name, rom.name, rom.size, rom.crc, rom.md5, rom.sha1,],
-                )?;
-            }
-        }
+    /// Start a transaction around a batch of changes to the scanned files, that `Batch::commit` commits.
+    /// A batch dropped without being committed rolls its changes back
+    pub fn begin_batch(&self) -> Result<Batch<'_>> {
+        //unchecked, as the changes are made through the database rather than the transaction
+        let tx = self.conn.unchecked_transaction()?;
+        Ok(Batch { tx })
+    }
 
-        tx.commit()?;
-        Ok(())
+    /// Start merging a DAT into the database, in a transaction that `Merge::finish` commits.
+    /// Games are recorded as coming from the source, until `Merge::source` changes it
+    pub fn begin_merge(&mut self, source: &str) -> Result<Merge<'_>> {
+        let normalized = self.is_normalized()?;
+        let tx = self.conn.transaction()?;
+        Ok(Merge {
+            tx,
+            normalized,
+            source: source.to_owned(),
+        })
     }
 
     pub fn search_by_game_name(&self, name: &str, fuzzy: bool) -> Result<Vec<Game>> {
-        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1
+        //games made up only of disks have no roms, so they still need to be found
+        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
              FROM games g
-             JOIN roms r ON g.name = r.game_name";
+             LEFT JOIN rom_entries r ON g.name = r.game_name";
 
         let condition = if fuzzy {
             format!("{} WHERE g.name LIKE ? ORDER BY g.name, r.name", query)
@@ -132,90 +443,434 @@ impl Database {
 
         let param = if fuzzy { format!("%{}%", name) } else { name.to_owned() };
 
-        self.fetch_games_and_roms(&condition, &[param]).map(|results| {
-            let mut games: Vec<Game> = results
-                .into_iter()
-                .map(|(mut game, roms)| {
-                    game.roms = roms;
-                    game
+        let mut games = Vec::new();
+        for (mut game, roms) in self.fetch_games_and_roms(&condition, &[param])? {
+            game.roms = roms;
+            game.disks = self.get_disks(&game.name)?;
+            games.push(game);
+        }
+        games.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(games)
+    }
+
+    /// Every game in the database along with its roms and disks, sorted by name
+    pub fn get_all_games(&self) -> Result<Vec<Game>> {
+        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
+             FROM games g
+             LEFT JOIN rom_entries r ON g.name = r.game_name
+             ORDER BY g.name, r.name";
+
+        let mut disks_by_game: HashMap<String, Vec<Disk>> = HashMap::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT game_name, name, sha1, md5 FROM disks ORDER BY game_name, name")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                Disk {
+                    name: row.get(1)?,
+                    sha1: row.get(2)?,
+                    md5: row.get(3)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (game_name, disk) = row?;
+            disks_by_game.entry(game_name).or_default().push(disk);
+        }
+
+        let mut games = Vec::new();
+        for (mut game, roms) in self.fetch_games_and_roms(query, &[])? {
+            game.roms = roms;
+            game.disks = disks_by_game.remove(&game.name).unwrap_or_default();
+            games.push(game);
+        }
+        games.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(games)
+    }
+
+    /// The header of the DAT most recently merged into the database, if one has been recorded
+    pub fn get_header(&self) -> Result<Option<Header>> {
+        let header = self
+            .conn
+            .query_row("SELECT name, description, version FROM metadata WHERE id = 0", [], |row| {
+                Ok(Header {
+                    name: row.get(0)?,
+                    description: row.get(1)?,
+                    version: row.get(2)?,
                 })
-                .collect();
-            games.sort_by(|a, b| a.name.cmp(&b.name));
-            games
-        })
+            })
+            .optional()?;
+        Ok(header)
     }
 
-    pub fn search_roms(
-        &self,
-        criteria: &HashMap<&str, &str>,
-        fuzzy_criteria: &HashMap<&str, &str>,
-    ) -> Result<Vec<(Game, Vec<Rom>)>> {
-        let mut conditions = Vec::new();
-        let mut params: Vec<String> = Vec::new();
+    /// Counts of the games, roms, disks and scanned files in the database
+    pub fn get_stats(&self) -> Result<DatabaseStats> {
+        let mut stats = DatabaseStats {
+            games: self.conn.query_row("SELECT COUNT(*) FROM games", [], |row| row.get(0))?,
+            disks: self.conn.query_row("SELECT COUNT(*) FROM disks", [], |row| row.get(0))?,
+            ..DatabaseStats::default()
+        };
+        self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), COUNT(crc), COUNT(md5), COUNT(sha1), COUNT(sha256) FROM rom_entries",
+            [],
+            |row| {
+                stats.roms = row.get(0)?;
+                stats.rom_bytes = row.get(1)?;
+                stats.roms_with_crc = row.get(2)?;
+                stats.roms_with_md5 = row.get(3)?;
+                stats.roms_with_sha1 = row.get(4)?;
+                stats.roms_with_sha256 = row.get(5)?;
+                Ok(())
+            },
+        )?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT match_type, COUNT(*) FROM scanned_files GROUP BY match_type")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let match_type: String = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            match MatchType::from_str(&match_type) {
+                Ok(MatchType::Exact) => stats.exact_files += count,
+                Ok(MatchType::Partial) => stats.partial_files += count,
+                Ok(MatchType::None) | Err(_) => stats.unmatched_files += count,
+            }
+        }
+        Ok(stats)
+    }
 
-        for (key, value) in criteria {
-            conditions.push(format!("r.{} = ?", key));
-            params.push(String::from(*value));
+    /// For every game, how many of its roms and disks have an exact match among all scanned files, sorted by name
+    pub fn get_set_statuses(&self) -> Result<Vec<SetStatus>> {
+        //disks are stored in scanned files under their CHD file name
+        let mut stmt = self.conn.prepare(
+            "WITH entries AS (
+                SELECT game_name, name FROM rom_entries
+                UNION ALL
+                SELECT game_name, name || '.chd' FROM disks
+             )
+             SELECT g.name, COUNT(DISTINCT e.name), COUNT(DISTINCT s.rom_name)
+             FROM games g
+             LEFT JOIN entries e ON e.game_name = g.name
+             LEFT JOIN scanned_files s ON s.game_name = e.game_name AND s.rom_name = e.name AND s.match_type = ?1
+             GROUP BY g.name
+             ORDER BY g.name",
+        )?;
+        let rows = stmt.query_map(params![MatchType::Exact.to_string()], |row| {
+            Ok(SetStatus {
+                game_name: row.get(0)?,
+                roms: row.get(1)?,
+                have: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Every game with the roms and disks that no scanned file matches exactly, sorted by name
+    pub fn get_wanted(&self) -> Result<Vec<Game>> {
+        let exact = MatchType::Exact.to_string();
+        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
+             FROM games g
+             JOIN rom_entries r ON g.name = r.game_name
+             WHERE NOT EXISTS (
+                SELECT 1 FROM scanned_files s
+                WHERE s.game_name = r.game_name AND s.rom_name = r.name AND s.match_type = ?1
+             )
+             ORDER BY g.name, r.name";
+        let mut games: HashMap<String, Game> = HashMap::new();
+        for (mut game, roms) in self.fetch_games_and_roms(query, std::slice::from_ref(&exact))? {
+            game.roms = roms;
+            games.insert(game.name.clone(), game);
         }
 
-        for (key, value) in fuzzy_criteria {
-            conditions.push(format!("r.{} LIKE ?", key));
-            params.push(format!("%{}%", value));
+        //disks are stored in scanned files under their CHD file name
+        let mut stmt = self.conn.prepare(
+            "SELECT g.name, g.description, d.name, d.sha1, d.md5, g.source
+             FROM games g
+             JOIN disks d ON g.name = d.game_name
+             WHERE NOT EXISTS (
+                SELECT 1 FROM scanned_files s
+                WHERE s.game_name = d.game_name AND s.rom_name = d.name || '.chd' AND s.match_type = ?1
+             )
+             ORDER BY g.name, d.name",
+        )?;
+        let rows = stmt.query_map(params![exact], |row| {
+            Ok((
+                Game {
+                    name: row.get(0)?,
+                    description: row.get(1)?,
+                    roms: vec![],
+                    disks: vec![],
+                    source: row.get(5)?,
+                },
+                Disk {
+                    name: row.get(2)?,
+                    sha1: row.get(3)?,
+                    md5: row.get(4)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (game, disk) = row?;
+            games.entry(game.name.clone()).or_insert(game).disks.push(disk);
         }
 
+        let mut games: Vec<Game> = games.into_values().collect();
+        games.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(games)
+    }
+
+    /// The size the DAT gives for a rom, if the game has a rom of that name
+    pub fn get_rom_size(&self, game_name: &str, rom_name: &str) -> Result<Option<i64>> {
+        let size = self
+            .conn
+            .query_row(
+                "SELECT size FROM rom_entries WHERE game_name = ?1 AND name = ?2",
+                params![game_name, rom_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(size)
+    }
+
+    fn get_disks(&self, game_name: &str) -> Result<Vec<Disk>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, sha1, md5 FROM disks WHERE game_name = ?1 ORDER BY name")?;
+        let rows = stmt.query_map(params![game_name], |row| {
+            Ok(Disk {
+                name: row.get(0)?,
+                sha1: row.get(1)?,
+                md5: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Search for disks whose CHD header SHA1 matches the given hash
+    pub fn search_disks_by_sha1(&self, sha1: &str) -> Result<Vec<(Game, Vec<Disk>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT g.name, g.description, d.name, d.sha1, d.md5, g.source
+             FROM games g
+             JOIN disks d ON g.name = d.game_name
+             WHERE d.sha1 = ?1
+             ORDER BY g.name, d.name",
+        )?;
+        let rows = stmt.query_map(params![sha1], |row| {
+            Ok((
+                Game {
+                    name: row.get(0)?,
+                    description: row.get(1)?,
+                    roms: vec![],
+                    disks: vec![],
+                    source: row.get(5)?,
+                },
+                Disk {
+                    name: row.get(2)?,
+                    sha1: row.get(3)?,
+                    md5: row.get(4)?,
+                },
+            ))
+        })?;
+
+        let mut results: Vec<(Game, Vec<Disk>)> = Vec::new();
+        for row in rows {
+            let (game, disk) = row?;
+            match results.last_mut() {
+                Some((last, disks)) if last.name == game.name => disks.push(disk),
+                _ => results.push((game, vec![disk])),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Search for roms matching all the criteria: columns equal to, containing, starting with or within a bound of a value
+    pub fn search_roms(
+        &self,
+        criteria: &HashMap<&str, &str>,
+        fuzzy_criteria: &HashMap<&str, &str>,
+        prefix_criteria: &HashMap<&str, &str>,
+        range_criteria: &[(&str, Bound, u64)],
+    ) -> Result<Vec<(Game, Vec<Rom>)>> {
+        let (conditions, params) = rom_search_conditions(criteria, fuzzy_criteria, prefix_criteria, range_criteria);
         let query = format!(
-            "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1
+            "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
              FROM games g
-             JOIN roms r ON g.name = r.game_name
+             JOIN rom_entries r ON g.name = r.game_name
              WHERE {}
              ORDER BY g.name, r.name",
-            conditions.join(" AND ")
+            conditions
         );
 
         self.fetch_games_and_roms(&query, &params)
     }
 
+    /// How many roms there are, and how many of them have each kind of hash
+    pub fn count_rom_hashes(&self) -> Result<(u64, Vec<(HashType, u64)>)> {
+        let (roms, crc, md5, sha1, sha256) = self.conn.query_row(
+            "SELECT COUNT(*), COUNT(crc), COUNT(md5), COUNT(sha1), COUNT(sha256) FROM rom_entries",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+        )?;
+        Ok((
+            roms,
+            vec![
+                (HashType::Crc, crc),
+                (HashType::Md5, md5),
+                (HashType::Sha1, sha1),
+                (HashType::Sha256, sha256),
+            ],
+        ))
+    }
+
+    /// Count the roms `search_roms` would find, without fetching them
+    pub fn count_roms(
+        &self,
+        criteria: &HashMap<&str, &str>,
+        fuzzy_criteria: &HashMap<&str, &str>,
+        prefix_criteria: &HashMap<&str, &str>,
+        range_criteria: &[(&str, Bound, u64)],
+    ) -> Result<i64> {
+        let (conditions, params) = rom_search_conditions(criteria, fuzzy_criteria, prefix_criteria, range_criteria);
+        let query = format!(
+            "SELECT COUNT(*)
+             FROM games g
+             JOIN rom_entries r ON g.name = r.game_name
+             WHERE {}",
+            conditions
+        );
+
+        let count = self
+            .conn
+            .query_row(&query, rusqlite::params_from_iter(params), |row| row.get(0))?;
+        Ok(count)
+    }
+
+    /// Search for roms matching any of the given hashes, recording which hashes matched each rom.
+    ///
+    /// With a `crc_size`, a CRC only matches roms of that size, as CRCs are short enough to collide.
+    ///
+    /// Roms are keyed on their game and name, so each pair is matched at most once. Differently named roms of a game
+    /// that share a hash, such as filler, are each matched, as a file may stand in for any of them
+    pub fn search_roms_by_hash(&self, hashes: &[(HashType, &str)], crc_size: Option<u64>) -> Result<Vec<(Game, Vec<RomMatch>)>> {
+        let (query, params) = roms_by_hash_query(hashes, crc_size);
+        let results = self.fetch_games_and_roms(&query, &params)?;
+        Ok(results
+            .into_iter()
+            .map(|(game, roms)| {
+                let rom_matches = roms
+                    .into_iter()
+                    .map(|rom| {
+                        let size_matches =
+                            crc_size.is_none_or(|size| u64::try_from(rom.size).is_ok_and(|rom_size| rom_size == size));
+                        let matched_by = hashes
+                            .iter()
+                            .filter(|(hash_type, hash)| rom.hash(*hash_type) == Some(*hash))
+                            .filter(|(hash_type, _)| *hash_type != HashType::Crc || size_matches)
+                            .map(|(hash_type, _)| *hash_type)
+                            .collect();
+                        RomMatch { rom, matched_by }
+                    })
+                    .collect();
+                (game, rom_matches)
+            })
+            .collect())
+    }
+
+    /// Search for zero byte roms without hashes that have the given name, as no hash can find them
+    pub fn search_empty_roms(&self, name: &str) -> Result<Vec<(Game, Vec<RomMatch>)>> {
+        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
+             FROM games g
+             JOIN rom_entries r ON g.name = r.game_name
+             WHERE r.name = ? AND r.size = 0
+               AND r.crc IS NULL AND r.md5 IS NULL AND r.sha1 IS NULL AND r.sha256 IS NULL
+             ORDER BY g.name, r.name";
+        self.fetch_rom_matches_by_name(query, name)
+    }
+
+    /// Search for roms with the given name, for DATs that list no hashes to match by
+    pub fn search_roms_by_name(&self, name: &str) -> Result<Vec<(Game, Vec<RomMatch>)>> {
+        let query = "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
+             FROM games g
+             JOIN rom_entries r ON g.name = r.game_name
+             WHERE r.name = ?
+             ORDER BY g.name, r.name";
+        self.fetch_rom_matches_by_name(query, name)
+    }
+
+    // roms found by name rather than by a hash, so nothing is recorded as having matched
+    fn fetch_rom_matches_by_name(&self, query: &str, name: &str) -> Result<Vec<(Game, Vec<RomMatch>)>> {
+        let results = self.fetch_games_and_roms(query, &[name.to_owned()])?;
+        Ok(results
+            .into_iter()
+            .map(|(game, roms)| {
+                let rom_matches = roms.into_iter().map(|rom| RomMatch { rom, matched_by: vec![] }).collect();
+                (game, rom_matches)
+            })
+            .collect())
+    }
+
     fn fetch_games_and_roms(&self, query: &str, params: &[String]) -> Result<Vec<(Game, Vec<Rom>)>> {
         let mut stmt = self.conn.prepare(query)?;
         let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            let rom_name: Option<String> = row.get(2)?;
+            //a left join gives a single row without a rom for games that have none
+            let rom = match rom_name {
+                Some(name) => Some(Rom {
+                    name,
+                    size: row.get(3)?,
+                    crc: row.get(4)?,
+                    md5: row.get(5)?,
+                    sha1: row.get(6)?,
+                    sha256: row.get(7)?,
+                }),
+                None => None,
+            };
             Ok((
                 Game {
                     name: row.get(0)?,
                     description: row.get(1)?,
                     roms: vec![],
+                    disks: vec![],
+                    source: row.get(8)?,
                 },
-                Rom {
-                    name: row.get(2)?,
-                    size: row.get(3)?,
-                    crc: row.get(4)?,
-                    md5: row.get(5)?,
-                    sha1: row.get(6)?,
-                },
+                rom,
             ))
         })?;
 
-        let mut games_map = HashMap::new();
-
+        //the queries sort by game, so each game's rows come together and the games stay in the order asked for
+        let mut results: Vec<(Game, Vec<Rom>)> = Vec::new();
         for row in rows {
             let (game, rom) = row?;
-            games_map
-                .entry(game.name.clone())
-                .or_insert_with(|| (game, Vec::new()))
-                .1
-                .push(rom);
+            match results.last_mut() {
+                Some((last, roms)) if last.name == game.name => roms.extend(rom),
+                _ => results.push((game, rom.into_iter().collect())),
+            }
         }
-
-        let results: Vec<_> = games_map.into_values().collect();
         Ok(results)
     }
 
     pub fn get_files_by_base_path(&self, base_path: &str) -> Result<Vec<ScannedFile>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT base_path, path, hash, hash_type, match_type, game_name, rom_name
+        self.get_scanned_files("base_path = ?1", [base_path])
+    }
+
+    /// The files in the directory and in every directory under it, not those of a sibling whose name starts the same
+    pub fn get_files_under_base_path(&self, base_path: &str) -> Result<Vec<ScannedFile>> {
+        self.get_scanned_files("base_path = ?1 OR base_path LIKE ?2 ESCAPE '\\'", params![base_path, under_pattern(base_path)])
+    }
+
+    pub fn get_all_files(&self) -> Result<Vec<ScannedFile>> {
+        self.get_scanned_files("1", params![])
+    }
+
+    fn get_scanned_files(&self, condition: &str, params: impl Params) -> Result<Vec<ScannedFile>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT base_path, path, hash, hash_type, match_type, game_name, rom_name, size, header_offset, first_seen, last_scanned
              FROM scanned_files
-             WHERE base_path = ?1",
-        )?;
-        let rows = stmt.query_map(params![base_path], |row| {
+             WHERE {}",
+            condition
+        ))?;
+        let rows = stmt.query_map(params, |row| {
             let raw_type: String = row.get(3)?;
             let raw_match: String = row.get(4)?;
             Ok(ScannedFile {
@@ -226,6 +881,10 @@ impl Database {
                 match_type: MatchType::from_str(&raw_match).expect("should be a valid MatchType"),
                 game_name: row.get(5)?,
                 rom_name: row.get(6)?,
+                size: row.get(7)?,
+                header_offset: row.get(8)?,
+                first_seen: row.get(9)?,
+                last_scanned: row.get(10)?,
             })
         })?;
         let mut scanned_files = Vec::new();
@@ -235,40 +894,509 @@ impl Database {
         Ok(scanned_files)
     }
 
-    pub fn get_files_under_base_path(&self, base_path: &str) -> Result<Vec<ScannedFile>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT base_path, path, hash, hash_type, match_type, game_name, rom_name
-             FROM scanned_files
-             WHERE base_path LIKE ?1",
+    /// Mark the files with the base path as not yet scanned again, `store_file` unmarks each one it stores.
+    /// Rather than clearing them up front, so files scanned again keep when they were first seen
+    pub fn mark_files_stale(&self, base_path: &str) -> Result<()> {
+        self.conn
+            .execute("UPDATE scanned_files SET last_scanned = NULL WHERE base_path = ?1", [base_path])?;
+        Ok(())
+    }
+
+    /// Remove the files with the base path that were marked stale and not stored since
+    pub fn delete_stale_files(&self, base_path: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM scanned_files WHERE base_path = ?1 AND last_scanned IS NULL", [base_path])?;
+        Ok(())
+    }
+
+    /// Remove games with no roms or disks, and with `scanned_files` the scanned files matched to a rom that no
+    /// longer exists, all in one transaction. A dry run rolls it back, so the counts are what would be removed.
+    ///
+    /// Returns the number of games and scanned files removed
+    pub fn prune(&mut self, scanned_files: bool, dry_run: bool) -> Result<(usize, usize)> {
+        let tx = self.conn.transaction()?;
+        let games = tx.execute(
+            "DELETE FROM games
+             WHERE NOT EXISTS (SELECT 1 FROM roms r WHERE r.game_name = games.name)
+               AND NOT EXISTS (SELECT 1 FROM disks d WHERE d.game_name = games.name)",
+            [],
         )?;
-        let rows = stmt.query_map(params![format!("{}%", base_path)], |row| {
-            let raw_type: String = row.get(3)?;
-            let raw_match: String = row.get(4)?;
-            Ok(ScannedFile {
-                base_path: row.get(0)?,
-                path: row.get(1)?,
-                hash: row.get(2)?,
-                hash_type: HashType::from_str(&raw_type).expect("should be a valid HashType"),
-                match_type: MatchType::from_str(&raw_match).expect("should be a valid MatchType"),
-                game_name: row.get(5)?,
-                rom_name: row.get(6)?,
-            })
-        })?;
-        let mut scanned_files = Vec::new();
+        //after the games, so files matched to one of them are removed too
+        let files = if scanned_files {
+            tx.execute(&format!("DELETE FROM scanned_files WHERE {}", ORPHANED_SCANNED_FILES), [])?
+        } else {
+            0
+        };
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok((games, files))
+    }
+
+    /// The problems `PRAGMA integrity_check` finds in the database file, empty when it is sound
+    pub fn integrity_problems(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut problems = Vec::new();
         for row in rows {
-            scanned_files.push(row?);
+            let row = row?;
+            if row != "ok" {
+                problems.push(row);
+            }
         }
-        Ok(scanned_files)
+        Ok(problems)
+    }
+
+    /// Rows whose foreign key has no parent row, as the table, rowid and parent table
+    pub fn foreign_key_violations(&self) -> Result<Vec<(String, Option<i64>, String)>> {
+        let mut stmt = self.conn.prepare("PRAGMA foreign_key_check")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        let mut violations = Vec::new();
+        for row in rows {
+            violations.push(row?);
+        }
+        Ok(violations)
     }
 
-    pub fn clear_files_by_base_path(&self, base_path: &str) -> Result<()> {
+    /// Paths of scanned files matched to a rom or disk that is no longer in the database, sorted
+    pub fn orphaned_scanned_files(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT path FROM scanned_files WHERE {} ORDER BY path", ORPHANED_SCANNED_FILES))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        let mut paths = Vec::new();
+        for row in rows {
+            paths.push(row?);
+        }
+        Ok(paths)
+    }
+
+    /// Record that a scanned file was found unchanged just now
+    pub fn touch_file(&self, path: &str) -> Result<()> {
         self.conn
-            .execute("DELETE FROM scanned_files WHERE base_path = ?1", [base_path])?;
+            .execute("UPDATE scanned_files SET last_scanned = CURRENT_TIMESTAMP WHERE path = ?1", [path])?;
         Ok(())
     }
 
+    /// Move the record of a scanned file to a new path and base path, keeping everything else about it.
+    ///
+    /// Returns false, leaving the record where it is, if the new path already has a record
+    pub fn relocate_file(&self, path: &str, new_path: &str, new_base_path: &str) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE OR IGNORE scanned_files SET path = ?2, base_path = ?3 WHERE path = ?1",
+            [path, new_path, new_base_path],
+        )?;
+        Ok(updated > 0)
+    }
+
     pub fn delete_file(&self, path: &str) -> Result<()> {
         self.conn.execute("DELETE FROM scanned_files WHERE path = ?1", [path])?;
         Ok(())
     }
+
+    /// Remove the file and, for an archive, the members stored under its path
+    pub fn delete_files_under(&self, path: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM scanned_files WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'",
+            params![path, under_pattern(path)],
+        )?;
+        Ok(())
+    }
+}
+
+/// Changes to scanned files committed together, or rolled back if dropped
+pub struct Batch<'a> {
+    tx: Transaction<'a>,
+}
+
+impl Batch<'_> {
+    pub fn commit(self) -> Result<()> {
+        self.tx.commit()?;
+        Ok(())
+    }
+}
+
+/// A DAT being merged into the database, games replace any of the same name as they are added,
+/// taking their source with them so a game always belongs to the DAT it was last imported from
+pub struct Merge<'a> {
+    tx: Transaction<'a>,
+    normalized: bool,
+    source: String,
+}
+
+impl Merge<'_> {
+    /// Record the games added from now on as coming from this source
+    pub fn source(&mut self, source: &str) {
+        source.clone_into(&mut self.source);
+    }
+
+    /// Record the header of the DAT being merged
+    pub fn header(&self, header: &Header) -> Result<()> {
+        self.tx.execute(
+            "INSERT OR REPLACE INTO metadata (id, name, description, version)
+             VALUES (0, ?1, ?2, ?3)",
+            params![header.name, header.description, header.version],
+        )?;
+        Ok(())
+    }
+
+    pub fn game(&self, game: Game) -> Result<()> {
+        let tx = &self.tx;
+        //an upsert rather than a replace, which would delete the game row and cascade to its roms and disks
+        tx.execute(
+            "INSERT INTO games (name, description, source)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET description = excluded.description, source = excluded.source",
+            params![game.name, game.description, self.source],
+        )?;
+
+        // Delete existing ROMs for this game
+        tx.execute("DELETE FROM roms WHERE game_name = ?1", params![game.name])?;
+
+        // Insert new ROMs
+        for rom in game.roms {
+            if self.normalized {
+                let content_id = find_or_insert_content(tx, &rom)?;
+                tx.execute(
+                    "INSERT INTO roms (game_name, name, content_id)
+                     VALUES (?1, ?2, ?3)",
+                    params![game.name, rom.name, content_id],
+                )?;
+            } else {
+                tx.execute(
+                    "INSERT INTO roms (game_name, name, size, crc, md5, sha1, sha256)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![game.name, rom.name, rom.size, rom.crc, rom.md5, rom.sha1, rom.sha256],
+                )?;
+            }
+        }
+
+        tx.execute("DELETE FROM disks WHERE game_name = ?1", params![game.name])?;
+        for disk in game.disks {
+            tx.execute(
+                "INSERT INTO disks (game_name, name, sha1, md5)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![game.name, disk.name, disk.sha1, disk.md5],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.normalized {
+            // Remove content no longer referenced by any replaced ROMs
+            self.tx
+                .execute("DELETE FROM rom_content WHERE id NOT IN (SELECT content_id FROM roms)", [])?;
+        }
+
+        self.tx.commit()?;
+        Ok(())
+    }
+}
+
+fn find_or_insert_content(tx: &Transaction, rom: &Rom) -> Result<i64> {
+    let existing = tx
+        .query_row(
+            "SELECT id FROM rom_content WHERE size = ?1 AND crc IS ?2 AND md5 IS ?3 AND sha1 IS ?4 AND sha256 IS ?5",
+            params![rom.size, rom.crc, rom.md5, rom.sha1, rom.sha256],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = existing {
+        Ok(id)
+    } else {
+        tx.execute(
+            "INSERT INTO rom_content (size, crc, md5, sha1, sha256) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![rom.size, rom.crc, rom.md5, rom.sha1, rom.sha256],
+        )?;
+        Ok(tx.last_insert_rowid())
+    }
+}
+
+// the query `search_roms_by_hash` runs, with its parameters
+fn roms_by_hash_query(hashes: &[(HashType, &str)], crc_size: Option<u64>) -> (String, Vec<String>) {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    for (hash_type, hash) in hashes {
+        match (hash_type, crc_size) {
+            (HashType::Crc, Some(size)) => {
+                conditions.push(String::from("(r.crc = ? AND r.size = ?)"));
+                params.push(String::from(*hash));
+                params.push(size.to_string());
+            }
+            _ => {
+                conditions.push(format!("r.{} = ?", <&str>::from(hash_type)));
+                params.push(String::from(*hash));
+            }
+        }
+    }
+
+    let query = format!(
+        "SELECT g.name, g.description, r.name, r.size, r.crc, r.md5, r.sha1, r.sha256, g.source
+         FROM games g
+         JOIN rom_entries r ON g.name = r.game_name
+         WHERE {}
+         ORDER BY g.name, r.name",
+        conditions.join(" OR ")
+    );
+    (query, params)
+}
+
+// the WHERE clause and its parameters shared by the rom search and count queries
+fn rom_search_conditions(
+    criteria: &HashMap<&str, &str>,
+    fuzzy_criteria: &HashMap<&str, &str>,
+    prefix_criteria: &HashMap<&str, &str>,
+    range_criteria: &[(&str, Bound, u64)],
+) -> (String, Vec<String>) {
+    let mut conditions = Vec::new();
+    let mut params: Vec<String> = Vec::new();
+
+    for (key, value) in criteria {
+        conditions.push(format!("r.{} = ?", key));
+        params.push(String::from(*value));
+    }
+
+    for (key, value) in fuzzy_criteria {
+        conditions.push(format!("r.{} LIKE ?", key));
+        params.push(format!("%{}%", value));
+    }
+
+    for (key, value) in prefix_criteria {
+        conditions.push(format!("r.{} LIKE ?", key));
+        params.push(format!("{}%", value));
+    }
+
+    for (key, bound, value) in range_criteria {
+        conditions.push(format!("r.{} {} ?", key, bound.operator()));
+        params.push(value.to_string());
+    }
+
+    (conditions.join(" AND "), params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{roms_by_hash_query, Database, MIGRATIONS};
+    use crate::models::{Disk, HashType, MatchType};
+    use crate::testing::{game, memory_database, rom, unmatched_file, TempDir};
+    use anyhow::Result;
+    use rusqlite::Connection;
+
+    // the paths of the files found under the directory, sorted
+    fn paths_under(db: &Database, directory: &str) -> Result<Vec<String>> {
+        let mut paths: Vec<String> = db
+            .get_files_under_base_path(directory)?
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn files_under_base_path_include_subdirectories_only() -> Result<()> {
+        let db = memory_database();
+        for path in [
+            "/roms/nes/a.bin",
+            "/roms/nes/sub/b.bin",
+            "/roms/nes-hacks/c.bin",
+            "/roms/nes2/d.bin",
+            "/roms/e.bin",
+        ] {
+            db.store_file(&unmatched_file(path))?;
+        }
+        assert_eq!(paths_under(&db, "/roms/nes")?, ["/roms/nes/a.bin", "/roms/nes/sub/b.bin"]);
+        assert_eq!(paths_under(&db, "/roms")?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn files_under_base_path_escape_wildcards() -> Result<()> {
+        let db = memory_database();
+        for path in ["/roms/n_s/a.bin", "/roms/nes/b.bin", "/roms/100%/c.bin", "/roms/1000/d.bin"] {
+            db.store_file(&unmatched_file(path))?;
+        }
+        assert_eq!(paths_under(&db, "/roms/n_s")?, ["/roms/n_s/a.bin"]);
+        assert_eq!(paths_under(&db, "/roms/100%")?, ["/roms/100%/c.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_files_under_removes_archive_members() -> Result<()> {
+        let db = memory_database();
+        for path in [
+            "/roms/game.zip",
+            "/roms/game.zip/a.bin",
+            "/roms/game.zip/sub/b.bin",
+            "/roms/game.zip2/c.bin",
+        ] {
+            db.store_file(&unmatched_file(path))?;
+        }
+        db.delete_files_under("/roms/game.zip")?;
+        assert_eq!(paths_under(&db, "/roms")?, ["/roms/game.zip2/c.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn baseline_database_migrates_to_the_current_schema() -> Result<()> {
+        let temp = TempDir::new();
+        let path = temp.path().join("baseline.db");
+        //the schema of the first release, before user_version was set, with the rom key on scanned files
+        let conn = Connection::open(&path)?;
+        conn.execute_batch(
+            "CREATE TABLE games (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL
+            );
+            CREATE TABLE roms (
+                game_name TEXT NOT NULL,
+                name TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                crc TEXT,
+                md5 TEXT,
+                sha1 TEXT,
+                PRIMARY KEY (game_name, name),
+                FOREIGN KEY(game_name) REFERENCES games(name) ON DELETE CASCADE
+            );
+            CREATE TABLE scanned_files (
+                base_path TEXT NOT NULL,
+                path TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                hash_type TEXT NOT NULL,
+                match_type TEXT NOT NULL,
+                game_name TEXT,
+                rom_name TEXT,
+                FOREIGN KEY(game_name, rom_name) REFERENCES roms(game_name, name)
+            );
+            INSERT INTO games VALUES ('Game', 'Game (Europe)');
+            INSERT INTO roms VALUES ('Game', 'a.bin', 16, '11111111', NULL, NULL);
+            INSERT INTO scanned_files VALUES ('/roms', '/roms/a.bin', '11111111', 'Crc', 'Exact', 'Game', 'a.bin');
+            INSERT INTO scanned_files VALUES ('/roms', '/roms/x.bin', '22222222', 'Crc', 'None', NULL, NULL);",
+        )?;
+        drop(conn);
+
+        let db = Database::new(&path)?;
+        let version: usize = db.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(version, MIGRATIONS.len());
+        assert!(db.foreign_key_violations()?.is_empty());
+
+        let games = db.get_all_games()?;
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].roms.len(), 1);
+        assert_eq!(games[0].roms[0].crc.as_deref(), Some("11111111"));
+        let mut files = db.get_all_files()?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].match_type, MatchType::Exact);
+        assert_eq!(files[0].rom_name.as_deref(), Some("a.bin"));
+        assert_eq!(files[1].match_type, MatchType::None);
+
+        //files matched to a disk have no rom row, which the dropped key would have refused
+        let mut disk_file = unmatched_file("/roms/game.chd");
+        disk_file.match_type = MatchType::Exact;
+        disk_file.game_name = Some(String::from("Game"));
+        disk_file.rom_name = Some(String::from("game.chd"));
+        db.store_file(&disk_file)?;
+        drop(db);
+
+        //opening again has nothing left to do
+        let db = Database::new(&path)?;
+        assert_eq!(db.get_all_files()?.len(), 3);
+        Ok(())
+    }
+
+    // a database of a thousand games of five roms each, all with different CRCs and SHA1s
+    fn thousands_of_roms(normalized: bool) -> Result<Database> {
+        let mut db = Database::new(":memory:".into())?;
+        db.initialize(normalized)?;
+        let merge = db.begin_merge("test")?;
+        for g in 0..1000 {
+            let roms = (0..5)
+                .map(|r| {
+                    let mut rom = rom(&format!("{}.bin", r), &format!("{:08x}", g * 5 + r));
+                    rom.sha1 = Some(format!("{:040x}", g * 5 + r));
+                    rom
+                })
+                .collect();
+            merge.game(game(&format!("Game {}", g), roms))?;
+        }
+        merge.finish()?;
+        Ok(db)
+    }
+
+    // the details of how sqlite would run the hash search, one line for each step
+    fn hash_search_plan(db: &Database, hashes: &[(HashType, &str)], crc_size: Option<u64>) -> Result<Vec<String>> {
+        let (query, params) = roms_by_hash_query(hashes, crc_size);
+        let mut statement = db.conn.prepare(&format!("EXPLAIN QUERY PLAN {}", query))?;
+        let plan = statement
+            .query_map(rusqlite::params_from_iter(params), |row| row.get(3))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(plan)
+    }
+
+    #[test]
+    fn hash_searches_use_the_hash_indices() -> Result<()> {
+        for (normalized, crc_index, sha1_index) in [
+            (false, "roms_crc", "roms_sha1"),
+            (true, "rom_content_crc", "rom_content_sha1"),
+        ] {
+            let db = thousands_of_roms(normalized)?;
+            let hashes = [
+                (HashType::Crc, "00000d05"),
+                (HashType::Sha1, "0000000000000000000000000000000000000d05"),
+            ];
+            let found = db.search_roms_by_hash(&hashes, Some(16))?;
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].0.name, "Game 666");
+            assert_eq!(found[0].1[0].matched_by, [HashType::Crc, HashType::Sha1]);
+
+            let plan = hash_search_plan(&db, &hashes, Some(16))?;
+            //a search of each index rather than a scan of every rom
+            assert!(!plan.iter().any(|step| step.starts_with("SCAN")), "{:?}", plan);
+            assert!(plan.iter().any(|step| step.contains(crc_index)), "{:?}", plan);
+            assert!(plan.iter().any(|step| step.contains(sha1_index)), "{:?}", plan);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_a_game_cascades_to_its_roms_and_disks() -> Result<()> {
+        for normalized in [false, true] {
+            let mut db = Database::new(":memory:".into())?;
+            db.initialize(normalized)?;
+            let merge = db.begin_merge("test")?;
+            let mut with_disk = game("Game A", vec![rom("a.bin", "11111111")]);
+            with_disk.disks.push(Disk {
+                name: String::from("disc"),
+                sha1: Some(String::from("0000000000000000000000000000000000000001")),
+                md5: None,
+            });
+            merge.game(with_disk)?;
+            merge.game(game("Game B", vec![rom("b.bin", "22222222")]))?;
+            merge.finish()?;
+            for (path, game_name, rom_name) in [
+                ("/roms/a.bin", "Game A", "a.bin"),
+                ("/roms/disc.chd", "Game A", "disc.chd"),
+                ("/roms/b.bin", "Game B", "b.bin"),
+            ] {
+                let mut file = unmatched_file(path);
+                file.match_type = MatchType::Exact;
+                file.game_name = Some(game_name.to_owned());
+                file.rom_name = Some(rom_name.to_owned());
+                db.store_file(&file)?;
+            }
+
+            db.conn.execute("DELETE FROM games WHERE name = 'Game A'", [])?;
+            let stats = db.get_stats()?;
+            assert_eq!((stats.games, stats.roms, stats.disks), (1, 1, 0));
+            assert!(db.foreign_key_violations()?.is_empty());
+            //scanned files have no key into the roms, they are left for prune to remove
+            assert_eq!(db.get_all_files()?.len(), 3);
+            assert_eq!(db.orphaned_scanned_files()?, ["/roms/a.bin", "/roms/disc.chd"]);
+            assert_eq!(db.prune(true, false)?, (0, 2));
+            assert_eq!(paths_under(&db, "/roms")?, ["/roms/b.bin"]);
+        }
+        Ok(())
+    }
 }
@@ -1,64 +1,193 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{anyhow, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use clap::Subcommand;
+use clap::{Args, Subcommand, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::warn;
+use serde_json::Value;
+use std::io::Write;
 
+use crate::database::Bound;
+use crate::models::HashType;
+use crate::output::{self, OutputFormat, Table};
 use crate::{database, models, xml_parser};
 
 #[derive(Subcommand)]
 pub enum DbCommands {
     /// Initialize the database
     Initialize {
-        /// Path to the XML file to import
+        /// Path to the XML file to import, or - to read it from stdin. It may be gzip compressed
         input: Utf8PathBuf,
 
         /// List of remappings for file extensions, comma separated
         /// e.g. "3ds=cci,bin=nes"
         #[arg(short, long, value_delimiter = ',', value_parser = parse_key_val::<String, String>)]
         remap_extensions: Vec<(String, String)>,
+
+        #[command(flatten)]
+        filter: GameFilterArgs,
+
+        /// What to do when a game lists the same rom name more than once
+        #[arg(long, value_enum, default_value = "keep-first")]
+        on_rom_conflict: RomConflict,
+
+        /// Store rom sizes and hashes in a shared table, deduplicating identical roms across games
+        #[arg(long)]
+        normalized: bool,
     },
     /// Import data into the database
     Import {
-        /// Path to the XML file to import
+        /// Path to the XML file to import, or - to read it from stdin. It may be gzip compressed
         input: Utf8PathBuf,
 
         /// List of remappings for file extensions, comma separated
         /// e.g. "3ds=cci,bin=nes"
         #[arg(short, long, value_delimiter = ',', value_parser = parse_key_val::<String, String>)]
         remap_extensions: Vec<(String, String)>,
+
+        #[command(flatten)]
+        filter: GameFilterArgs,
+
+        /// What to do when a game lists the same rom name more than once
+        #[arg(long, value_enum, default_value = "keep-first")]
+        on_rom_conflict: RomConflict,
+
+        /// Label to record the imported games as coming from, instead of the DAT header name.
+        /// A game imported again takes the source of the latest import
+        #[arg(long)]
+        source: Option<String>,
     },
     /// Search the database
     Search {
         #[command(subcommand)]
         search_type: SearchType,
     },
+    /// Print the header of the DAT the database was built from
+    Info,
+    /// Summarize the database: games, roms and disks, how many roms have each hash, and scanned files by match
+    Stats,
+    /// Report which games have all, some or none of their roms matched exactly by scanned files
+    Sets {
+        /// Only list games that are missing roms
+        #[arg(long)]
+        missing_only: bool,
+    },
+    /// List every rom and disk that no scanned file matches exactly, as text, a Logiqx XML DAT with --dat,
+    /// or in the chosen structured --format
+    Wantlist {
+        /// File to write to (defaults to stdout), a name ending in .gz is written compressed
+        output: Option<Utf8PathBuf>,
+
+        /// Write a Logiqx XML DAT of the wanted roms, e.g. to diff against another collection
+        #[arg(long)]
+        dat: bool,
+
+        /// Compress the output with gzip, even without a .gz file name
+        #[arg(long)]
+        compress_output: bool,
+    },
+    /// Move rom sizes and hashes into a shared table, deduplicating identical roms across games
+    ShrinkRoms,
+    /// Remove games that have no roms or disks left
+    Prune {
+        /// Also remove scanned files matched to a rom that is no longer in the database
+        #[arg(long)]
+        scanned_files: bool,
+
+        /// Only count what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the database file for corruption, rows missing the row their foreign key refers to,
+    /// and scanned files matched to roms that are no longer in the database
+    Check,
+    /// Export every game and rom in the database as a Logiqx XML DAT, or in the chosen structured --format
+    Export {
+        /// File to write to (defaults to stdout), a name ending in .gz is written compressed
+        output: Option<Utf8PathBuf>,
+
+        /// Compress the output with gzip, even without a .gz file name
+        #[arg(long)]
+        compress_output: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct GameFilterArgs {
+    /// Only import games whose name matches one of these glob patterns, comma separated
+    /// e.g. "Game Boy*,Mega Drive*"
+    #[arg(long, value_delimiter = ',')]
+    only: Vec<String>,
+
+    /// Skip games whose name matches one of these glob patterns, comma separated
+    #[arg(long, value_delimiter = ',')]
+    exclude_games: Vec<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum RomConflict {
+    /// Warn and keep the first rom with the name
+    KeepFirst,
+    /// Stop the import
+    Error,
 }
 
 #[derive(Subcommand)]
 pub enum SearchType {
     /// Search by game name
     Game {
-        /// Game name to search for (fuzzy search)
+        /// Game name to search for (fuzzy search unless --exact is given)
         name: String,
-    },
-    /// Search for ROMs by various criteria
-    Rom {
-        /// ROM name to search for (fuzzy search)
-        name: Option<String>,
-
-        /// CRC to search for (exact match)
-        #[arg(short, long)]
-        crc: Option<String>,
 
-        /// MD5 to search for (exact match)
+        /// Only find games with exactly this name
         #[arg(short, long)]
-        md5: Option<String>,
+        exact: bool,
 
-        /// SHA1 to search for (exact match)
-        #[arg(short, long)]
-        sha1: Option<String>,
+        /// Show at most this many of the matching games, the first by name
+        #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+        limit: Option<u64>,
     },
+    /// Search for ROMs by various criteria, roms must match all of those given
+    Rom(RomSearchArgs),
+}
+
+#[derive(Args)]
+pub struct RomSearchArgs {
+    /// ROM name to search for (fuzzy search)
+    name: Option<String>,
+
+    /// CRC to search for (exact match, or a prefix ending in *)
+    #[arg(short, long)]
+    crc: Option<String>,
+
+    /// MD5 to search for (exact match, or a prefix ending in *)
+    #[arg(short, long)]
+    md5: Option<String>,
+
+    /// SHA1 to search for (exact match, or a prefix ending in *)
+    #[arg(short, long)]
+    sha1: Option<String>,
+
+    /// SHA256 to search for (exact match, or a prefix ending in *)
+    #[arg(long)]
+    sha256: Option<String>,
+
+    /// Size in bytes to search for (exact match)
+    #[arg(long, conflicts_with_all = ["min_size", "max_size"])]
+    size: Option<u64>,
+
+    /// Only find roms of at least this many bytes
+    #[arg(long)]
+    min_size: Option<u64>,
+
+    /// Only find roms of at most this many bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Only print the number of matching roms
+    #[arg(long)]
+    count_only: bool,
 }
 
 fn parse_key_val<T, U>(s: &str) -> Result<(T, U), Box<dyn std::error::Error + Send + Sync + 'static>>
@@ -74,9 +203,41 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+fn push_game_with_roms(table: &mut Table, game: &models::Game, roms: &[models::Rom]) {
+    for rom in roms {
+        table.push(vec![
+            game.name.clone().into(),
+            rom.name.clone().into(),
+            rom.size.into(),
+            rom.crc.clone().into(),
+            rom.md5.clone().into(),
+            rom.sha1.clone().into(),
+            rom.sha256.clone().into(),
+            game.source.clone().into(),
+        ]);
+    }
+    for disk in &game.disks {
+        table.push(vec![
+            game.name.clone().into(),
+            disk.file_name().into(),
+            Value::Null,
+            Value::Null,
+            disk.md5.clone().into(),
+            disk.sha1.clone().into(),
+            Value::Null,
+            game.source.clone().into(),
+        ]);
+    }
+}
+
+const ROM_COLUMNS: [&str; 8] = ["game", "rom", "size", "crc", "md5", "sha1", "sha256", "source"];
+
 fn print_game_with_roms(game: &models::Game, roms: &[models::Rom]) {
     println!("\nGame:");
     println!("Name: {}", game.name);
+    if let Some(source) = &game.source {
+        println!("Source: {}", source);
+    }
     //    println!("Description: {}", game.description);
     println!("ROMs:");
     for rom in roms {
@@ -91,108 +252,502 @@ fn print_game_with_roms(game: &models::Game, roms: &[models::Rom]) {
         if let Some(sha1) = &rom.sha1 {
             println!("\tSHA1: {}", sha1);
         }
+        if let Some(sha256) = &rom.sha256 {
+            println!("\tSHA256: {}", sha256);
+        }
     }
+    if !game.disks.is_empty() {
+        println!("Disks:");
+        for disk in &game.disks {
+            println!("\n\tName: {}", disk.name);
+            if let Some(md5) = &disk.md5 {
+                println!("\tMD5: {}", md5);
+            }
+            if let Some(sha1) = &disk.sha1 {
+                println!("\tSHA1: {}", sha1);
+            }
+        }
+    }
+}
+
+fn print_stats(stats: &models::DatabaseStats) {
+    println!("Games: {}", stats.games);
+    println!("Roms: {} ({} bytes)", stats.roms, stats.rom_bytes);
+    println!("Disks: {}", stats.disks);
+    //a hash every rom has can be used to scan with on its own
+    for (hash_type, count) in [
+        (HashType::Crc, stats.roms_with_crc),
+        (HashType::Md5, stats.roms_with_md5),
+        (HashType::Sha1, stats.roms_with_sha1),
+        (HashType::Sha256, stats.roms_with_sha256),
+    ] {
+        let coverage = if count == stats.roms && count > 0 { " (all)" } else { "" };
+        println!("Roms with {}: {}{}", hash_type.to_string().to_lowercase(), count, coverage);
+    }
+    println!(
+        "Scanned files: {} exact, {} partial, {} unmatched",
+        stats.exact_files, stats.partial_files, stats.unmatched_files
+    );
 }
 
-pub fn handle_command(db_path: &Utf8Path, debug: bool, command: &DbCommands) -> Result<()> {
+pub fn handle_command(db_path: &Utf8Path, format: OutputFormat, command: &DbCommands) -> Result<()> {
     match command {
-        DbCommands::Initialize { input, remap_extensions } => {
+        DbCommands::Initialize {
+            input,
+            remap_extensions,
+            filter,
+            on_rom_conflict,
+            normalized,
+        } => {
+            output::require_human(format, "database initialize")?;
             let mut db = database::Database::new(db_path).context("Failed to connect to database")?;
-            db.initialize().context("Failed to initialize database")?;
-            let mut data = xml_parser::parse_file(input).context("Failed to parse XML file")?;
-            if !remap_extensions.is_empty() {
-                let remap: HashMap<String, String> = remap_extensions.iter().cloned().collect();
-                remap_datafile(&mut data, &remap).context("Failed to remap datafile")?;
-            }
-            db.merge_data(data).context("Failed to merge data into database")?;
+            db.initialize(*normalized).context("Failed to initialize database")?;
+            import_datafile(&mut db, input, remap_extensions, filter, *on_rom_conflict, None)?;
             println!("Initialize completed successfully");
         }
-        DbCommands::Import { input, remap_extensions } => {
-            let mut db = database::check_for_database(db_path, debug)?;
-            let mut data = xml_parser::parse_file(input).context("Failed to parse XML file")?;
-            if !remap_extensions.is_empty() {
-                let remap: HashMap<String, String> = remap_extensions.iter().cloned().collect();
-                remap_datafile(&mut data, &remap).context("Failed to remap datafile")?;
-            }
-            db.merge_data(data).context("Failed to merge data into database")?;
+        DbCommands::Import {
+            input,
+            remap_extensions,
+            filter,
+            on_rom_conflict,
+            source,
+        } => {
+            output::require_human(format, "database import")?;
+            let mut db = database::check_for_database(db_path)?;
+            import_datafile(&mut db, input, remap_extensions, filter, *on_rom_conflict, source.as_deref())?;
             println!("Import completed successfully");
         }
         DbCommands::Search { search_type } => {
-            let db = database::check_for_database(db_path, debug)?;
+            let db = database::check_for_database(db_path)?;
             match search_type {
-                SearchType::Game { name } => {
-                    let results = db.search_by_game_name(name, true).context("Failed to search database")?;
-                    if results.is_empty() {
+                SearchType::Game { name, exact, limit } => {
+                    let mut results = db.search_by_game_name(name, !exact).context("Failed to search database")?;
+                    let found = results.len();
+                    if let Some(limit) = limit {
+                        results.truncate(usize::try_from(*limit).unwrap_or(usize::MAX));
+                    }
+                    if format != OutputFormat::Human {
+                        let mut table = Table::new(&ROM_COLUMNS);
+                        for game in &results {
+                            push_game_with_roms(&mut table, game, &game.roms);
+                        }
+                        table.print(format)?;
+                    } else if results.is_empty() {
                         println!("No games found matching name: {}", name);
                     } else {
-                        println!("Found {} matching game(s)", results.len());
+                        if results.len() < found {
+                            println!("Found {} matching game(s), showing the first {}", found, results.len());
+                        } else {
+                            println!("Found {} matching game(s)", found);
+                        }
                         for game in results {
                             print_game_with_roms(&game, &game.roms);
                         }
                     }
                 }
-                SearchType::Rom { name, crc, md5, sha1 } => {
-                    search_roms(&db, name, crc, md5, sha1)?;
+                SearchType::Rom(args) => {
+                    search_roms(&db, format, args)?;
                 }
             }
         }
+        DbCommands::Info => {
+            let db = database::check_for_database(db_path)?;
+            let header = db.get_header().context("Failed to read DAT header from database")?;
+            if format != OutputFormat::Human {
+                let mut table = Table::new(&["name", "description", "version"]);
+                if let Some(header) = header {
+                    table.push(vec![header.name.into(), header.description.into(), header.version.into()]);
+                }
+                table.print(format)?;
+            } else if let Some(header) = header {
+                println!("Name: {}", header.name);
+                println!("Description: {}", header.description);
+                println!("Version: {}", header.version);
+            } else {
+                println!("No DAT header recorded, import a DAT to record one");
+            }
+        }
+        DbCommands::Stats => {
+            let db = database::check_for_database(db_path)?;
+            let stats = db.get_stats().context("Failed to read statistics from database")?;
+            if format == OutputFormat::Human {
+                print_stats(&stats);
+            } else {
+                let mut table = Table::new(&[
+                    "games",
+                    "roms",
+                    "disks",
+                    "rom_bytes",
+                    "roms_with_crc",
+                    "roms_with_md5",
+                    "roms_with_sha1",
+                    "roms_with_sha256",
+                    "exact_files",
+                    "partial_files",
+                    "unmatched_files",
+                ]);
+                table.push(vec![
+                    stats.games.into(),
+                    stats.roms.into(),
+                    stats.disks.into(),
+                    stats.rom_bytes.into(),
+                    stats.roms_with_crc.into(),
+                    stats.roms_with_md5.into(),
+                    stats.roms_with_sha1.into(),
+                    stats.roms_with_sha256.into(),
+                    stats.exact_files.into(),
+                    stats.partial_files.into(),
+                    stats.unmatched_files.into(),
+                ]);
+                table.print(format)?;
+            }
+        }
+        DbCommands::Sets { missing_only } => {
+            let db = database::check_for_database(db_path)?;
+            let statuses = db.get_set_statuses().context("Failed to read set status from database")?;
+            let mut table = Table::new(&["game", "status", "have", "roms"]);
+            for status in statuses {
+                let tag = if status.have == status.roms {
+                    if *missing_only {
+                        continue;
+                    }
+                    "HAVE"
+                } else if status.have == 0 {
+                    "MISS"
+                } else {
+                    "PART"
+                };
+                if format == OutputFormat::Human {
+                    println!("[{}] {} ({} of {} roms)", tag, status.game_name, status.have, status.roms);
+                } else {
+                    table.push(vec![
+                        status.game_name.into(),
+                        tag.to_ascii_lowercase().into(),
+                        status.have.into(),
+                        status.roms.into(),
+                    ]);
+                }
+            }
+            if format != OutputFormat::Human {
+                table.print(format)?;
+            }
+        }
+        DbCommands::Wantlist {
+            output,
+            dat,
+            compress_output,
+        } => {
+            if *dat {
+                output::require_human(format, "database wantlist --dat")?;
+            }
+            let db = database::check_for_database(db_path)?;
+            let games = db.get_wanted().context("Failed to read wanted roms from database")?;
+            let mut sink = output::OutputSink::create(output.as_deref(), *compress_output)?;
+            if *dat {
+                let mut header = dat_header(&db, db_path)?;
+                header.description = format!("{} (wanted)", header.description);
+                xml_parser::write_datafile(&models::DataFile { header, games }, &mut sink)?;
+            } else if format == OutputFormat::Human {
+                write_wantlist(&games, &mut sink)?;
+            } else {
+                let mut table = Table::new(&ROM_COLUMNS);
+                for game in &games {
+                    push_game_with_roms(&mut table, game, &game.roms);
+                }
+                table.write(format, &mut sink)?;
+            }
+            sink.finish().context("Failed to write wantlist")?;
+        }
+        DbCommands::ShrinkRoms => {
+            output::require_human(format, "database shrink-roms")?;
+            let mut db = database::check_for_database(db_path)?;
+            match db.shrink_roms().context("Failed to shrink roms")? {
+                Some((rom_count, content_count)) => {
+                    println!("Shrink completed successfully, {} roms now share {} content entries", rom_count, content_count)
+                }
+                None => println!("Database roms are already normalized"),
+            }
+        }
+        DbCommands::Prune { scanned_files, dry_run } => {
+            output::require_human(format, "database prune")?;
+            let mut db = database::check_for_database(db_path)?;
+            let (games, files) = db.prune(*scanned_files, *dry_run).context("Failed to prune database")?;
+            let verb = if *dry_run { "Would remove" } else { "Removed" };
+            if *scanned_files {
+                println!("{} {} game(s) and {} scanned file(s)", verb, games, files);
+            } else {
+                println!("{} {} game(s)", verb, games);
+            }
+        }
+        DbCommands::Check => {
+            output::require_human(format, "database check")?;
+            let db = database::check_for_database(db_path)?;
+            let integrity = db.integrity_problems().context("Failed to check database integrity")?;
+            let violations = db.foreign_key_violations().context("Failed to check database foreign keys")?;
+            let orphans = db.orphaned_scanned_files().context("Failed to check scanned files")?;
+            for problem in &integrity {
+                println!("[BAD ] {}", problem);
+            }
+            for (table, rowid, parent) in &violations {
+                match rowid {
+                    Some(rowid) => println!("[KEY ] {} row {} refers to a missing row in {}", table, rowid, parent),
+                    None => println!("[KEY ] {} has a row that refers to a missing row in {}", table, parent),
+                }
+            }
+            for path in &orphans {
+                println!("[ORPH] {}", path);
+            }
+            println!(
+                "Integrity: {} problem(s); Foreign keys: {} violation(s); Scanned files: {} orphaned",
+                integrity.len(),
+                violations.len(),
+                orphans.len()
+            );
+            if !orphans.is_empty() {
+                println!("Orphaned scanned files can be removed with `database prune --scanned-files`");
+            }
+            if !integrity.is_empty() || !violations.is_empty() || !orphans.is_empty() {
+                return Err(anyhow!("Database check found problems"));
+            }
+        }
+        DbCommands::Export { output, compress_output } => {
+            let db = database::check_for_database(db_path)?;
+            let games = db.get_all_games().context("Failed to read games from database")?;
+            let mut sink = output::OutputSink::create(output.as_deref(), *compress_output)?;
+            if format == OutputFormat::Human {
+                //without a structured format the export is a DAT, which can be imported again
+                let header = dat_header(&db, db_path)?;
+                xml_parser::write_datafile(&models::DataFile { header, games }, &mut sink)?;
+            } else {
+                let mut table = Table::new(&ROM_COLUMNS);
+                for game in &games {
+                    push_game_with_roms(&mut table, game, &game.roms);
+                }
+                table.write(format, &mut sink)?;
+            }
+            sink.finish().context("Failed to write export")?;
+        }
     }
     Ok(())
 }
 
-fn remap_datafile(data: &mut models::DataFile, remap_extensions: &HashMap<String, String>) -> Result<()> {
-    for game in &mut data.games {
-        for rom in &mut game.roms {
-            let mut iter = rom.name.rsplitn(2, '.');
-            let after = iter.next();
-            let before = iter.next();
-            if before == Some("") {
-                continue;
-            } else {
-                if let Some(extension) = before.and(after) {
-                    if let Some(new_extension) = remap_extensions.get(extension) {
-                        rom.name = format!("{}.{}", before.unwrap(), new_extension);
-                    }
+// the header of the DAT the database was built from, or one named after the database file for those built before
+// the header was kept
+fn dat_header(db: &database::Database, db_path: &Utf8Path) -> Result<models::Header> {
+    match db.get_header().context("Failed to read DAT header from database")? {
+        Some(header) => Ok(header),
+        None => {
+            warn!("no DAT header recorded in the database, naming the DAT after it");
+            let name = db_path.file_stem().unwrap_or("rcr2").to_owned();
+            Ok(models::Header {
+                description: name.clone(),
+                name,
+                version: String::new(),
+            })
+        }
+    }
+}
+
+// one line per game, followed by an indented line for each rom it wants with its size and hashes
+fn write_wantlist(games: &[models::Game], out: &mut impl Write) -> Result<()> {
+    for game in games {
+        writeln!(out, "{}", game.name)?;
+        for rom in &game.roms {
+            write!(out, "  {} size={}", rom.name, rom.size)?;
+            for hash_type in [HashType::Crc, HashType::Md5, HashType::Sha1, HashType::Sha256] {
+                if let Some(hash) = rom.hash(hash_type) {
+                    write!(out, " {}={}", <&str>::from(hash_type).to_ascii_lowercase(), hash)?;
                 }
             }
+            writeln!(out)?;
+        }
+        for disk in &game.disks {
+            write!(out, "  {}", disk.file_name())?;
+            if let Some(md5) = &disk.md5 {
+                write!(out, " md5={}", md5)?;
+            }
+            if let Some(sha1) = &disk.sha1 {
+                write!(out, " sha1={}", sha1)?;
+            }
+            writeln!(out)?;
         }
     }
     Ok(())
 }
 
-fn search_roms(
-    db: &database::Database,
-    name: &Option<String>,
-    crc: &Option<String>,
-    md5: &Option<String>,
-    sha1: &Option<String>,
+// games are merged as they are read, so the whole DAT is never held in memory. Without a source they are recorded
+// as coming from the DAT header name, or the file name for a DAT with no header
+fn import_datafile(
+    db: &mut database::Database,
+    input: &Utf8Path,
+    remap_extensions: &[(String, String)],
+    filter: &GameFilterArgs,
+    on_rom_conflict: RomConflict,
+    source: Option<&str>,
 ) -> Result<()> {
+    let filtering = !filter.only.is_empty() || !filter.exclude_games.is_empty();
+    let only = build_globset(&filter.only).context("Invalid --only pattern")?;
+    let exclude = build_globset(&filter.exclude_games).context("Invalid --exclude-games pattern")?;
+    let remap: HashMap<String, String> = remap_extensions.iter().cloned().collect();
+
+    let file_name = if input == "-" {
+        "stdin"
+    } else {
+        input.file_name().unwrap_or(input.as_str())
+    };
+    let mut merge = db
+        .begin_merge(source.unwrap_or(file_name))
+        .context("Failed to merge data into database")?;
+    let mut selected = 0;
+    let mut total = 0;
+    xml_parser::for_each_element(input, |element| match element {
+        xml_parser::DatElement::Header(header) => {
+            if source.is_none() {
+                merge.source(&header.name);
+            }
+            merge.header(&header)
+        }
+        xml_parser::DatElement::Game(mut game) => {
+            total += 1;
+            if (!filter.only.is_empty() && !only.is_match(&game.name)) || exclude.is_match(&game.name) {
+                return Ok(());
+            }
+            selected += 1;
+            remap_game(&mut game, &remap);
+            //remapping can make two rom names collide, so this has to come after it
+            resolve_rom_conflicts(&mut game, on_rom_conflict)?;
+            merge.game(game)
+        }
+    })
+    .context("Failed to import XML file")?;
+    merge.finish().context("Failed to merge data into database")?;
+
+    if filtering {
+        println!("Selected {} of {} games", selected, total);
+    }
+    Ok(())
+}
+
+// roms are keyed by game and rom name, so only one rom of each name can be stored for a game
+fn resolve_rom_conflicts(game: &mut models::Game, on_rom_conflict: RomConflict) -> Result<()> {
+    let mut seen = HashSet::new();
+    let mut conflicts = Vec::new();
+    game.roms.retain(|rom| {
+        if seen.insert(rom.name.clone()) {
+            true
+        } else {
+            conflicts.push(rom.name.clone());
+            false
+        }
+    });
+    for rom_name in conflicts {
+        match on_rom_conflict {
+            RomConflict::KeepFirst => {
+                warn!("game {} lists rom {} more than once, keeping the first", game.name, rom_name)
+            }
+            RomConflict::Error => {
+                return Err(anyhow!("Game {} lists rom {} more than once", game.name, rom_name));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob pattern '{}'", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+// only the final extension of the rom's file name is remapped, so "game.v1.bin" keeps its "v1". Names without
+// an extension and dotfiles like ".nfo" are left alone, and each rom is remapped at most once
+fn remap_game(game: &mut models::Game, remap_extensions: &HashMap<String, String>) {
+    for rom in &mut game.roms {
+        let path = Utf8Path::new(&rom.name);
+        if let Some(new_extension) = path.extension().and_then(|extension| remap_extensions.get(extension)) {
+            rom.name = path.with_extension(new_extension).into_string();
+        }
+    }
+}
+
+// shorter hash prefixes are still searched for, with a warning
+const MIN_HASH_PREFIX: usize = 4;
+
+fn search_roms(db: &database::Database, format: OutputFormat, args: &RomSearchArgs) -> Result<()> {
+    let size = args.size.map(|size| size.to_string());
     let mut criteria = HashMap::new();
     let mut fuzzy_criteria = HashMap::new();
-    if let Some(name) = name {
+    let mut range_criteria = Vec::new();
+    if let Some(name) = &args.name {
         fuzzy_criteria.insert("name", name.as_str());
     }
-    if let Some(crc) = crc {
-        criteria.insert("crc", crc.as_str());
+    let mut prefix_criteria = HashMap::new();
+    for (column, hash) in [
+        ("crc", &args.crc),
+        ("md5", &args.md5),
+        ("sha1", &args.sha1),
+        ("sha256", &args.sha256),
+    ] {
+        let Some(hash) = hash else { continue };
+        match hash.strip_suffix('*') {
+            Some(prefix) => {
+                if prefix.len() < MIN_HASH_PREFIX {
+                    warn!("{} prefix {} is under {} characters, it may match most roms", column, prefix, MIN_HASH_PREFIX);
+                }
+                prefix_criteria.insert(column, prefix);
+            }
+            None => {
+                criteria.insert(column, hash.as_str());
+            }
+        }
+    }
+    if let Some(size) = &size {
+        criteria.insert("size", size.as_str());
     }
-    if let Some(md5) = md5 {
-        criteria.insert("md5", md5.as_str());
+    if let Some(min_size) = args.min_size {
+        range_criteria.push(("size", Bound::AtLeast, min_size));
     }
-    if let Some(sha1) = sha1 {
-        criteria.insert("sha1", sha1.as_str());
+    if let Some(max_size) = args.max_size {
+        range_criteria.push(("size", Bound::AtMost, max_size));
     }
 
-    if criteria.is_empty() {
+    if criteria.is_empty() && fuzzy_criteria.is_empty() && prefix_criteria.is_empty() && range_criteria.is_empty() {
         Err(anyhow!("No criteria given on command line, please supply at least one search term"))
+    } else if args.count_only {
+        let count = db
+            .count_roms(&criteria, &fuzzy_criteria, &prefix_criteria, &range_criteria)
+            .context("Failed to search database")?;
+        if format == OutputFormat::Human {
+            println!("{}", count);
+        } else {
+            let mut table = Table::new(&["count"]);
+            table.push(vec![count.into()]);
+            table.print(format)?;
+        }
+        Ok(())
     } else {
         let results = db
-            .search_roms(&criteria, &fuzzy_criteria)
+            .search_roms(&criteria, &fuzzy_criteria, &prefix_criteria, &range_criteria)
             .context("Failed to search database")?;
-        if results.is_empty() {
+        if format != OutputFormat::Human {
+            let mut table = Table::new(&ROM_COLUMNS);
+            for (game, roms) in &results {
+                push_game_with_roms(&mut table, game, roms);
+            }
+            table.print(format)?;
+        } else if results.is_empty() {
             let args = criteria
                 .iter()
                 .chain(&fuzzy_criteria)
                 .map(|(k, v)| format!("{k}: {v}"))
+                .chain(prefix_criteria.iter().map(|(k, v)| format!("{k}: {v}*")))
+                .chain(
+                    range_criteria
+                        .iter()
+                        .map(|(k, bound, v)| format!("{k} {} {v}", bound.operator())),
+                )
                 .collect::<Vec<_>>()
                 .join(", ");
             println!("No ROMs found matching criteria: {}", args);
@@ -205,3 +760,134 @@ fn search_roms(
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_commands::default_args;
+    use crate::testing::{game, memory_database, rom, TempDir, DAT};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs::{self, File};
+
+    // the names of the game's roms, in order
+    fn rom_names(game: &models::Game) -> Vec<&str> {
+        game.roms.iter().map(|rom| rom.name.as_str()).collect()
+    }
+
+    // a game listing a.bin twice, with different contents
+    fn game_with_conflict() -> models::Game {
+        game("Game", vec![rom("a.bin", "11111111"), rom("b.bin", "22222222"), rom("a.bin", "33333333")])
+    }
+
+    #[test]
+    fn rom_conflicts_keep_the_first_rom() -> Result<()> {
+        let mut game = game_with_conflict();
+        resolve_rom_conflicts(&mut game, RomConflict::KeepFirst)?;
+        assert_eq!(rom_names(&game), ["a.bin", "b.bin"]);
+        assert_eq!(game.roms[0].crc.as_deref(), Some("11111111"));
+        Ok(())
+    }
+
+    #[test]
+    fn rom_conflicts_stop_the_import_on_error() {
+        let mut game = game_with_conflict();
+        let error = resolve_rom_conflicts(&mut game, RomConflict::Error).expect_err("should refuse the conflict");
+        assert_eq!(error.to_string(), "Game Game lists rom a.bin more than once");
+    }
+
+    #[test]
+    fn games_without_conflicts_are_left_alone() -> Result<()> {
+        for on_rom_conflict in [RomConflict::KeepFirst, RomConflict::Error] {
+            let mut game = game("Game", vec![rom("a.bin", "11111111"), rom("A.bin", "22222222")]);
+            resolve_rom_conflicts(&mut game, on_rom_conflict)?;
+            assert_eq!(rom_names(&game), ["a.bin", "A.bin"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remap_only_changes_the_final_extension() {
+        let remap = HashMap::from([
+            (String::from("bin"), String::from("nes")),
+            (String::from("nfo"), String::from("txt")),
+        ]);
+        let mut game = game(
+            "Game",
+            ["game.bin", "game.v1.bin", ".nfo", "readme", "dir/track.bin", "game.bin.bak"]
+                .iter()
+                .map(|name| rom(name, "11111111"))
+                .collect(),
+        );
+        let remapped = ["game.nes", "game.v1.nes", ".nfo", "readme", "dir/track.nes", "game.bin.bak"];
+        remap_game(&mut game, &remap);
+        assert_eq!(rom_names(&game), remapped);
+        //the new extensions are not remapped again
+        remap_game(&mut game, &remap);
+        assert_eq!(rom_names(&game), remapped);
+    }
+
+    // the games and roms in a database the DAT was imported into
+    fn imported_counts(input: &Utf8Path) -> Result<(i64, i64)> {
+        let mut db = memory_database();
+        import_datafile(&mut db, input, &[], &default_args(), RomConflict::Error, None)?;
+        let stats = db.get_stats()?;
+        Ok((stats.games, stats.roms))
+    }
+
+    #[test]
+    fn gzipped_dat_imports_the_same_games() -> Result<()> {
+        let temp = TempDir::new();
+        let plain = temp.path().join("test.dat");
+        fs::write(&plain, DAT)?;
+        let gzipped = temp.path().join("test.dat.gz");
+        let mut encoder = GzEncoder::new(File::create(&gzipped)?, Compression::default());
+        encoder.write_all(DAT.as_bytes())?;
+        encoder.finish()?;
+
+        assert_eq!(imported_counts(&plain)?, (3, 3));
+        assert_eq!(imported_counts(&gzipped)?, imported_counts(&plain)?);
+        Ok(())
+    }
+
+    // the header and games of the DAT, read a game at a time as an import does
+    fn read_datafile(path: &Utf8Path) -> Result<models::DataFile> {
+        let mut header = None;
+        let mut games = Vec::new();
+        xml_parser::for_each_element(path, |element| {
+            match element {
+                xml_parser::DatElement::Header(read) => header = Some(read),
+                xml_parser::DatElement::Game(game) => games.push(game),
+            }
+            Ok(())
+        })?;
+        let header = header.ok_or_else(|| anyhow!("No header in {}", path))?;
+        Ok(models::DataFile { header, games })
+    }
+
+    #[test]
+    fn exported_dat_reads_back_as_the_imported_one() -> Result<()> {
+        let temp = TempDir::new();
+        let dat = temp.path().join("test.dat");
+        fs::write(&dat, DAT)?;
+        let db_path = temp.path().join("test.db");
+        let initialize = DbCommands::Initialize {
+            input: dat.clone(),
+            remap_extensions: Vec::new(),
+            filter: default_args(),
+            on_rom_conflict: RomConflict::Error,
+            normalized: false,
+        };
+        handle_command(&db_path, OutputFormat::Human, &initialize)?;
+        let exported = temp.path().join("exported.dat");
+        let export = DbCommands::Export { output: Some(exported.clone()), compress_output: false };
+        handle_command(&db_path, OutputFormat::Human, &export)?;
+
+        let imported = read_datafile(&dat)?;
+        let read_back = read_datafile(&exported)?;
+        assert_eq!(read_back.games.len(), 3);
+        //the models have no equality of their own, but serialize every field the DAT holds
+        assert_eq!(serde_json::to_value(&read_back)?, serde_json::to_value(&imported)?);
+        Ok(())
+    }
+}
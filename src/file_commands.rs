@@ -1,33 +1,43 @@
 use anyhow::{anyhow, Context, Result};
 use camino::{Utf8DirEntry, Utf8Path, Utf8PathBuf};
-use clap::builder::PossibleValue;
-use clap::{Args, Subcommand, ValueEnum};
+use clap::{Args, Command, FromArgMatches, Subcommand, ValueEnum};
 use crc32fast::Hasher;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, log_enabled, trace, warn, Level};
 use md5::Md5;
+use notify::{RecursiveMode, Watcher};
 use sha1::{Digest, Sha1};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use sha2::Sha256;
+use std::cell::{Cell, RefCell};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
 use strum::{Display, IntoStaticStr};
-use zip::ZipArchive;
 
-use crate::models::{HashType, MatchType, Rom, ScannedFile};
-use crate::{database, models};
-
-macro_rules! debug_log {
-    ($debug:expr, $($arg:tt)*) => {
-        if $debug {
-            eprintln!("{}", format!("Debug: {}", format!($($arg)*)));
-        }
-    };
-}
+use crate::archive::{ArchiveKind, Nesting};
+use crate::checkpoint::Checkpoint;
+use crate::header_rules::{HeaderArgs, HeaderRule, HeaderRules};
+use crate::models::{Disk, HashType, MatchType, Rom, RomMatch, ScannedFile};
+use crate::output::{self, OutputFormat, OutputSink, Table};
+use crate::report::{self, FileReport, FileStatus, GameStatus, Outcome, ReportOptions, Reporter, RomReport};
+use crate::{archive, database, models, ui};
 
 #[derive(Subcommand)]
 pub enum FileCommands {
     /// Scan all files in the directory and store the results in the database
-    Scan(ScanArgs),
+    Scan {
+        #[command(flatten)]
+        args: ScanArgs,
+
+        #[command(flatten)]
+        rescan: RescanArgs,
+    },
     /// Update files in the database from the directory, checking for new, renamed and removed files
     Update(ScanArgs),
+    /// Watch the directory and scan files into the database as they are added or changed
+    Watch(ScanArgs),
     /// Check all files in the directory against the database
     Check {
         /// Directory to scan (defaults to current directory)
@@ -37,7 +47,58 @@ pub enum FileCommands {
         /// Scan for files recursively
         #[arg(short, long)]
         recursive: bool,
+
+        /// Write the results to this file instead of stdout, a name ending in .gz is written compressed.
+        /// The terminal still shows progress and errors
+        #[arg(long)]
+        output: Option<Utf8PathBuf>,
+
+        #[command(flatten)]
+        headers: HeaderArgs,
+    },
+    /// Hash every file scanned into the database in the directory again, and report any that changed or are gone
+    Verify {
+        /// Directory to verify (defaults to current directory)
+        #[arg(default_value = ".")]
+        directory: Utf8PathBuf,
+
+        /// Verify files in subdirectories too
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Remove files that no longer exist from the database
+        #[arg(long)]
+        remove_gone: bool,
+
+        #[command(flatten)]
+        headers: HeaderArgs,
+    },
+    /// Hash all files in the directory and report matched games without storing anything in the database
+    Stats {
+        /// Directory to scan (defaults to current directory)
+        #[arg(default_value = ".")]
+        directory: Utf8PathBuf,
+
+        /// Hash methods to use, comma separated, all computed in one read of each file.
+        /// "auto" picks the strongest hash the DAT has for every rom
+        #[arg(short, long, value_enum, value_delimiter = ',', default_value = "sha1")]
+        method: Vec<HashMethod>,
+
+        /// What makes a hash match exact: the file name matching the rom name, or the size matching the rom size
+        #[arg(long, value_enum, default_value = "name")]
+        match_by: MatchBy,
+
+        /// Scan for files recursively
+        #[arg(short, long)]
+        recursive: bool,
+
+        #[command(flatten)]
+        headers: HeaderArgs,
     },
+    /// Print the hash of every file in the directory like sha1sum does, without using the database
+    Hash(HashArgs),
+    /// Hash a file, or the files in a directory, with each method in turn and report how fast each one is
+    Bench(BenchArgs),
     /// List all files scanned into the database in the directory
     List {
         /// Directory to scan (defaults to current directory)
@@ -47,41 +108,138 @@ pub enum FileCommands {
         /// Scan for files recursively
         #[arg(short, long)]
         recursive: bool,
+
+        /// Only list files that match a rom by hash but not by name, with the name they should have
+        #[arg(long)]
+        canonical_only: bool,
+
+        /// With --canonical-only, print a shell script of mv commands instead of a report
+        #[arg(long, requires = "canonical_only")]
+        script: bool,
+
+        /// Show the status of every rom in the given game instead of listing files
+        #[arg(long, value_name = "GAME", conflicts_with = "canonical_only")]
+        per_rom: Option<String>,
+
+        /// Write the files to this file instead of stdout, a name ending in .gz is written compressed
+        #[arg(long, conflicts_with_all = ["canonical_only", "per_rom"])]
+        output: Option<Utf8PathBuf>,
+
+        /// Also show when each file was first seen and when it was last scanned or checked unchanged
+        #[arg(long, conflicts_with_all = ["canonical_only", "per_rom"])]
+        show_dates: bool,
+    },
+    /// List files scanned into the database in the directory that have the same contents, most wasted space first
+    Dupes {
+        /// Directory to scan (defaults to current directory)
+        #[arg(default_value = ".")]
+        directory: Utf8PathBuf,
+
+        /// Scan for files recursively
+        #[arg(short, long)]
+        recursive: bool,
     },
-}
+    /// Remove files from the database that no longer exist on disk, without scanning anything
+    Clean {
+        /// Only look at files scanned from this directory and those under it, rather than the whole database
+        #[arg(long)]
+        base_path: Option<Utf8PathBuf>,
 
-impl ValueEnum for HashType {
-    fn value_variants<'a>() -> &'a [Self] {
-        &[Self::Crc, Self::Md5, Self::Sha1]
-    }
+        /// Only list the files that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Point the database at files that were moved from one directory to another outside of rcr2, without scanning them
+    Relocate {
+        /// Directory the files were scanned in, which need not exist anymore
+        #[arg(long)]
+        from: Utf8PathBuf,
 
-    fn to_possible_value(&self) -> Option<PossibleValue> {
-        Some(match self {
-            Self::Crc => PossibleValue::new("crc"),
-            Self::Md5 => PossibleValue::new("md5"),
-            Self::Sha1 => PossibleValue::new("sha1"),
-        })
-    }
+        /// Directory the files are in now
+        #[arg(long)]
+        to: Utf8PathBuf,
+
+        /// Hash each moved file again to confirm it is the one scanned, rather than only comparing sizes.
+        /// Archive members move with their archive, so `file verify` is left to check them
+        #[arg(long)]
+        verify: bool,
+
+        /// Only list the files that would be relocated
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Pack the loose files of every game they match completely into a zip named after the game
+    ///
+    /// The zips are canonical, so the same roms always give the same archive byte for byte. The README lists the
+    /// rules they follow. Only files already scanned as exact matches are packed
+    Rebuild {
+        /// Directory to rebuild (defaults to current directory)
+        #[arg(default_value = ".")]
+        directory: Utf8PathBuf,
+
+        /// Look for files recursively
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Directory to write the zips to (defaults to the directory rebuilt)
+        #[arg(long)]
+        output: Option<Utf8PathBuf>,
+
+        /// Remove the loose files of a game once its zip is written
+        #[arg(long)]
+        remove_source: bool,
+
+        /// Only list the zips that would be written
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Args)]
 pub struct ScanArgs {
-    /// Hash method to use
-    #[arg(short, long, value_enum, default_value = "sha1")]
-    method: HashType,
+    /// Hash methods to use, comma separated, all computed in one read of each file.
+    /// The first is the one stored for the file, any of them may match a rom.
+    /// "auto" picks the strongest hash the DAT has for every rom, or if none is, each hash any rom has
+    #[arg(short = 'm', long = "method", value_enum, value_delimiter = ',', default_value = "sha1")]
+    methods: Vec<HashMethod>,
 
     /// Display method for files
     #[arg(long, value_enum, value_delimiter = ',', default_value = "exact,partial,miss")]
     file_display: Vec<DisplayMethod>,
 
-    /// Stop after first exact match for each file
-    #[arg(short, long, default_value = "false")]
+    /// Only report the first exact match for each file, and none of its partial matches.
+    /// Either way a file is stored with the first of its matches
+    #[arg(long, default_value = "false")]
     first_match: bool,
 
     /// Ignore partial matches if there are exact matches
     #[arg(short, long, default_value = "true")]
     ignore_partial: bool,
 
+    /// What makes a hash match exact: the file name matching the rom name, or the size matching the rom size
+    #[arg(long, value_enum, default_value = "name")]
+    match_by: MatchBy,
+
+    /// Compare file names with rom names ignoring ASCII case, so a file named "Game.BIN" is taken as named after
+    /// its rom "Game.bin" and is not renamed by --fix. Letters outside ASCII keep their case. Only the name comparison
+    /// is affected, not hash matching
+    #[arg(long)]
+    case_insensitive_names: bool,
+
+    /// Only accept a hash match when the file size also matches the rom size, reporting mismatches as [SIZE]
+    #[arg(long)]
+    strict_size: bool,
+
+    /// Accept a CRC match whatever the rom size, for DATs with zero or unknown sizes.
+    /// By default a CRC only matches roms of the same size as the file
+    #[arg(long)]
+    no_size_check: bool,
+
+    /// Decompress zip members to hash them even when crc is the only method, checking the CRC the zip records as it goes.
+    /// By default that recorded CRC is used as is, which is much faster but does not notice a corrupt zip
+    #[arg(long)]
+    verify_zip_crc: bool,
+
     /// Directory to scan (defaults to current directory)
     #[arg(default_value = ".")]
     directory: Utf8PathBuf,
@@ -90,574 +248,2591 @@ pub struct ScanArgs {
     #[arg(short, long)]
     fix: bool,
 
+    /// After fixing a file name, check the file at the new name is the one renamed and hash it again, reporting
+    /// [FIXED] or [FIX-FAILED]. A file that no longer hashes as its rom is renamed back before it is stored
+    #[arg(long, requires = "fix")]
+    verify_after_fix: bool,
+
+    /// With --fix, only report the renames that would be made as [WOULD], without renaming or storing those files
+    #[arg(long, requires = "fix")]
+    dry_run: bool,
+
+    /// With --fix, ask which rom to rename a file to when it matches several, instead of leaving it as it is.
+    /// Only asked when stdin is a terminal
+    #[arg(long, requires = "fix")]
+    interactive: bool,
+
+    /// Skip a file if no rom is chosen for it within this many seconds
+    #[arg(long, requires = "interactive", value_name = "SECONDS")]
+    interactive_timeout: Option<u64>,
+
+    /// Remove directories left empty by moved files, never the scanned directory itself
+    #[arg(long, requires = "fix")]
+    prune_empty_dirs: bool,
+
+    /// With --fix, replace a file already where a file is renamed to. Without it the file is left as it is and
+    /// reported as [SKIP]
+    #[arg(long, requires = "fix")]
+    force: bool,
+
+    /// Where --fix moves a file, relative to the directory it is in, with {game}, {rom} and {ext} (the extension
+    /// of the rom name) filled in, e.g. "{game}/{rom}". Directories are created as needed, and the file always
+    /// ends up named after its rom. A file already there is only replaced with --force
+    #[arg(long, requires = "fix", default_value = "{rom}", value_parser = parse_rename_template)]
+    rename_template: String,
+
     /// Scan for files recursively
     #[arg(short, long)]
     recursive: bool,
-}
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, ValueEnum, IntoStaticStr, Display)]
-enum DisplayMethod {
-    /// Display exact matches only
-    Exact,
-    /// Display partial matches only
-    Partial,
-    /// Display misses only
-    Miss,
+    /// Only hash files modified since this time, either a while ago like "7d", "24h" or "30m", or a date like "2024-01-31".
+    /// Archive members go by the modified time of the archive file, not the times stored inside it.
+    /// Older files keep what the database already has for them, so scan removes nothing and update marks nothing gone
+    #[arg(long, value_parser = parse_newer_than)]
+    newer_than: Option<SystemTime>,
+
+    /// Write the results to this file instead of stdout, a name ending in .gz is written compressed.
+    /// The terminal still shows progress and errors
+    #[arg(long)]
+    output: Option<Utf8PathBuf>,
+
+    /// At the end of a scan or update, print the files and bytes hashed, how long it took and the throughput to stderr,
+    /// with the time spent in each hash method. Archive members count their uncompressed bytes
+    #[arg(long)]
+    stats: bool,
+
+    /// Hash files with a header both without and with it, for DATs that list some roms headered, trying the headered
+    /// hashes only when the others match nothing, e.g. "--skip-header smc=512 --try-headerless". Without other header
+    /// options the default rules are used, as with --strip-headers
+    #[arg(long)]
+    try_headerless: bool,
+
+    #[command(flatten)]
+    headers: HeaderArgs,
 }
 
-#[derive(Default)]
-struct GameStatus {
-    roms: Vec<Rom>,
-    exact_matches: HashMap<String, HashSet<String>>,
-    partial_matches: HashMap<String, HashSet<String>>,
+/// Which files a scan hashes again and which entries it keeps, options that update and watch have no use for
+#[derive(Args)]
+pub struct RescanArgs {
+    /// Only hash files that are not already stored as exact matches, or that were modified since they were scanned,
+    /// going by the modified time of the archive for its members. The matches skipped stay in the database as if
+    /// scanned again, rather than being removed with the entries of files no longer there. Update already skips
+    /// every file it has an entry for
+    #[arg(long)]
+    only_missing: bool,
+
+    /// Keep the entries of files that are not stored again by a scan, such as ones now filtered out or that failed
+    /// to hash, only removing the entries of files that are gone from the disk
+    #[arg(long)]
+    incremental: bool,
+
+    /// Carry on from where an interrupted scan of the directory stopped, skipping the files it finished.
+    /// Scans save the files they finish to a .rcr2-checkpoint file in the directory, deleted once they complete
+    #[arg(long)]
+    resume: bool,
+
+    /// How many files a scan finishes between saving its checkpoint, committing what it stored so far with it
+    #[arg(long, value_name = "FILES", default_value = "500")]
+    checkpoint_every: usize,
 }
 
-pub fn handle_command(
-    db: &mut database::Database,
-    debug: bool,
-    command: &mut FileCommands,
-    exclude_extensions: &[String],
-) -> Result<()> {
-    match command {
-        FileCommands::Scan(args) => {
-            args.directory = resolve_directory(&args.directory)?;
-            scan_directory(db, args, debug, exclude_extensions).context("Failed to scan directory")?;
-        }
-        FileCommands::Update(args) => {
-            args.directory = resolve_directory(&args.directory)?;
-            update_directory(db, args, debug, exclude_extensions).context("Failed to update directory")?;
-        }
-        FileCommands::Check { directory, recursive } => {
-            let directory = resolve_directory(directory)?;
-            check_directory(db, debug, exclude_extensions, &directory, *recursive).context("Failed to check directory")?;
-        }
-        FileCommands::List { directory, recursive } => {
-            let directory = resolve_directory(directory)?;
-            list_directory(db, &directory, debug, exclude_extensions, *recursive).context("Failed to list directory")?;
-        }
-    }
-    Ok(())
+/// A scan, update or watch as it runs: the arguments it was given, with what is worked out from them and the
+/// database before it starts, and what it keeps track of along the way
+struct ScanContext<'a> {
+    args: &'a ScanArgs,
+    // the directory given, resolved
+    directory: Utf8PathBuf,
+    // the methods hashed with, once auto is resolved against the database
+    method: Vec<HashType>,
+    rules: Option<HeaderRules>,
+    // only match against the database, never write to it
+    read_only: bool,
+    // no rom has a hash of the methods used, so files are matched by name and size instead
+    name_only: bool,
+    reporter: Box<dyn Reporter>,
+    // directories that files were moved out of, candidates for --prune-empty-dirs
+    moved_from: RefCell<BTreeSet<Utf8PathBuf>>,
+    // totals for --stats
+    hashed: RefCell<HashStats>,
+    // exact matches left as they are by --only-missing
+    kept: Cell<u64>,
 }
 
-fn resolve_directory(directory: &Utf8PathBuf) -> Result<Utf8PathBuf> {
-    if !directory.exists() {
-        return Err(anyhow!("Directory does not exist: {}", directory));
-    }
-    if !directory.is_dir() {
-        return Err(anyhow!("Not a directory: {}", directory));
-    }
-    directory
-        .canonicalize_utf8()
-        .context("Failed to resolve directory to full path")
+#[derive(Args)]
+pub struct HashArgs {
+    /// Directory to hash (defaults to current directory)
+    #[arg(default_value = ".")]
+    directory: Utf8PathBuf,
+
+    /// Hash files recursively
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Hash methods to print, comma separated, more than one needs --style bsd
+    #[arg(short, long, value_enum, value_delimiter = ',', default_value = "sha1")]
+    method: Vec<HashType>,
+
+    /// How each line is written, a structured --format lists a row for each hash instead
+    #[arg(long, value_enum, default_value = "gnu")]
+    style: ChecksumStyle,
+
+    /// Hash archives as they are rather than the files inside them, so every line can be checked with sha1sum -c.
+    /// Files inside archives are listed as the archive path followed by their path inside it
+    #[arg(long)]
+    whole_archives: bool,
 }
 
-// scan functions
+#[derive(Args)]
+pub struct BenchArgs {
+    /// File or directory to hash (defaults to current directory)
+    #[arg(default_value = ".")]
+    path: Utf8PathBuf,
 
-fn scan_directory(db: &database::Database, args: &ScanArgs, debug: bool, exclude_extensions: &[String]) -> Result<()> {
-    debug_log!(debug, "Using hash type: {}", args.method);
+    /// Hash files in the directory recursively
+    #[arg(short, long)]
+    recursive: bool,
 
-    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+    /// Hash methods to compare, comma separated
+    #[arg(short, long, value_enum, value_delimiter = ',', default_value = "crc,md5,sha1,sha256")]
+    method: Vec<HashType>,
 
-    let mut dir_stack: Vec<Utf8PathBuf> = Vec::new();
-    dir_stack.push(args.directory.clone());
+    /// How many times to hash the files with each method, the throughput is averaged over all of them
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+}
 
-    while let Some(current_path) = dir_stack.pop() {
-        println!("Scanning directory: {}", current_path);
+/// How the lines printed by file hash are laid out
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumStyle {
+    /// "HASH  PATH", as sha1sum, md5sum and sha256sum write and check it
+    Gnu,
+    /// "SHA1 (PATH) = HASH", as sha1sum --tag and the BSD tools write it, a line for each method
+    Bsd,
+    /// "PATH CRC", a simple file verification (.sfv) list of crc
+    Sfv,
+}
 
-        // Read directory contents and sort by path
-        let mut entries: Vec<Utf8DirEntry> = current_path.read_dir_utf8()?.filter_map(Result::ok).collect();
-        entries.sort_by_key(|entry| entry.path().to_owned());
+/// Which files the file commands look at, and which of them are opened as archives
+#[derive(Args)]
+pub struct FileFilter {
+    /// Only look at files with one of these extensions, comma separated e.g. "sfc,smc".
+    /// Applied first, --exclude-extensions then removes files from those included.
+    /// Archives are still opened, and their members filtered the same way
+    #[arg(long, value_delimiter = ',')]
+    include_extensions: Vec<String>,
 
-        //before we start scanning the directory, we need to clear the database of any files that have the same base path
-        db.clear_files_by_base_path(current_path.as_str())?;
+    /// List of file extensions to exclude, comma separated
+    #[arg(short, long, value_delimiter = ',', default_value = "m3u,dat")]
+    exclude_extensions: Vec<String>,
 
-        for entry in entries {
-            let full_path = entry.path();
+    /// Only look at files of at least this size, in bytes or with a K, M, G or T suffix e.g. "4M".
+    /// Archive members are filtered on their uncompressed size
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
 
-            if full_path.is_dir() {
-                if args.recursive {
-                    debug_log!(debug, "\nDebug: Queuing directory: {}", full_path);
-                    dir_stack.push(full_path.into());
-                }
-                continue;
-            }
+    /// Only look at files of at most this size, in bytes or with a K, M, G or T suffix e.g. "1G"
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
 
-            if should_skip_file(full_path, exclude_extensions) {
-                continue;
-            }
+    /// Recognise archives by their extension only, rather than by reading the start of every file.
+    /// Faster, but a misnamed archive is hashed as a plain file and a file misnamed as an archive fails to open
+    #[arg(long)]
+    no_content_detection: bool,
 
-            let rel_path = full_path
-                .strip_prefix(&args.directory)
-                .expect("should be able to strip prefix");
+    /// How many levels of archives inside archives to open, counting the outermost one.
+    /// Archives nested deeper than this are hashed as files, 1 opens no nested archives
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u32).range(1..))]
+    max_archive_depth: u32,
 
-            if is_zip_file(full_path) {
-                if let Err(e) =
-                    scan_zip_contents(db, args, debug, &current_path, full_path, rel_path, exclude_extensions, &mut found_games)
-                {
-                    //continue to next file if we have an error
-                    eprintln!("Failed to process ZIP file: {}", e);
-                }
-                continue;
-            }
+    /// Follow symlinked directories when recursing, each directory is still only processed once.
+    /// By default they are skipped, so links out of the tree are not pulled in
+    #[arg(long)]
+    follow_symlinks: bool,
 
-            if let Err(e) = File::open(full_path).context("Unable to open file").and_then(|mut file| {
-                scan_file_contents(db, args, debug, &current_path, full_path, rel_path, &mut file, &mut found_games, true)
-            }) {
-                //continue to next file if we have an error
-                eprintln!("Failed to process file: {}", e);
-            }
+    /// With --recursive, go at most this many directory levels below the directory given, 0 stays in it.
+    /// Limits the commands that read directories, scan, update, watch and check
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Skip files and directories whose path relative to the directory given matches one of these glob patterns,
+    /// comma separated e.g. "Scans,*.bak". Paths are matched with / separators on every platform and a * matches
+    /// across them, a directory matched is skipped with everything under it
+    #[arg(long, value_delimiter = ',', value_parser = parse_glob)]
+    exclude_paths: Vec<Glob>,
+
+    // the patterns of --exclude-paths, compiled once by `compile_exclusions`
+    #[arg(skip)]
+    excluded_paths: GlobSet,
+}
+
+impl FileFilter {
+    /// Compile the --exclude-paths patterns, before any file is looked at
+    pub fn compile_exclusions(&mut self) -> Result<()> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in &self.exclude_paths {
+            builder.add(glob.clone());
         }
+        self.excluded_paths = builder.build().context("Invalid --exclude-paths pattern")?;
+        Ok(())
     }
 
-    print_found_games(&found_games);
+    // whether the path relative to the directory given, or a directory it is in, matches --exclude-paths
+    fn excludes_path(&self, rel_path: &Utf8Path) -> bool {
+        !self.excluded_paths.is_empty()
+            && rel_path
+                .ancestors()
+                .take_while(|ancestor| !ancestor.as_str().is_empty())
+                .any(|ancestor| self.excluded_paths.is_match(ancestor))
+    }
 
-    Ok(())
-}
+    fn includes(&self, extension: &str) -> bool {
+        self.include_extensions.is_empty() || self.include_extensions.iter().any(|included| included == extension)
+    }
 
-fn scan_zip_contents(
-    db: &database::Database,
-    args: &ScanArgs,
-    debug: bool,
-    current_path: &Utf8Path,
-    zip_path: &Utf8Path,
-    rel_zip_path: &Utf8Path,
-    exclude_extensions: &[String],
-    found_games: &mut BTreeMap<String, GameStatus>,
-) -> Result<()> {
-    let zip_file = File::open(zip_path)?;
-    let mut archive = ZipArchive::new(zip_file)?;
+    fn excludes(&self, extension: &str) -> bool {
+        self.exclude_extensions.iter().any(|excluded| excluded == extension)
+    }
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        if file.is_dir() {
-            continue;
-        }
+    fn has_size_range(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some()
+    }
 
-        if let Some(inner_path) = file.enclosed_name().and_then(|p| Utf8PathBuf::try_from(p).ok()) {
-            if let Some(extension) = inner_path.extension() {
-                if exclude_extensions.contains(&extension.to_owned()) {
-                    continue;
-                }
-            }
+    fn includes_size(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
 
-            let full_file_path = zip_path.join(&inner_path);
-            let rel_file_path = rel_zip_path.join(&inner_path);
-            if let Err(e) =
-                scan_file_contents(db, args, debug, current_path, &full_file_path, &rel_file_path, &mut file, found_games, false)
-            {
-                //continue to next file if we have an error
-                eprintln!("Failed to process file: {}", e);
-            }
+    fn nesting(&self) -> Nesting {
+        Nesting {
+            max_depth: self.max_archive_depth,
+            detect_content: !self.no_content_detection,
+        }
+    }
+
+    fn archive_kind(&self, path: &Utf8Path) -> Option<ArchiveKind> {
+        if self.no_content_detection {
+            ArchiveKind::from_extension(path)
+        } else {
+            ArchiveKind::detect(path)
         }
     }
-    Ok(())
 }
 
-fn scan_file_contents(
-    db: &database::Database,
-    args: &ScanArgs,
-    debug: bool,
-    current_path: &Utf8Path,
-    full_file_path: &Utf8Path,
-    rel_file_path: &Utf8Path,
-    file: &mut impl Read,
-    found_games: &mut BTreeMap<String, GameStatus>,
-    can_rename: bool,
-) -> Result<String> {
-    debug_log!(debug, "\nDebug: Processing file: {}", rel_file_path);
-    let hash = read_and_hash(file, args.method)?;
+fn parse_glob(s: &str) -> Result<Glob, String> {
+    Glob::new(s).map_err(|e| e.to_string())
+}
 
-    let filename = full_file_path.file_name().ok_or_else(|| anyhow!("Invalid file name"))?;
+// a size in bytes, with an optional binary K, M, G or T suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size `{s}`, expected a number of bytes with an optional K, M, G or T suffix")),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size `{s}`, expected a number of bytes with an optional K, M, G or T suffix"))
+}
 
-    let mut criteria = HashMap::new();
-    criteria.insert(args.method.into(), hash.as_str());
+// a duration back from now with a s, m, h, d or w suffix, or a date taken as the start of that day in UTC
+fn parse_newer_than(s: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid time `{s}`, expected a duration like \"7d\" or \"24h\", or a date like \"2024-01-31\"");
+    let date: Vec<Option<u64>> = s.splitn(3, '-').map(|part| part.parse().ok()).collect();
+    if let [Some(year), Some(month), Some(day)] = date[..] {
+        if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        let since_epoch = Duration::from_secs(days_from_civil(year, month, day) * 24 * 60 * 60);
+        return SystemTime::UNIX_EPOCH.checked_add(since_epoch).ok_or_else(invalid);
+    }
 
-    let results = db.search_roms(&criteria, &HashMap::new())?;
-    let mut scanned_file = models::ScannedFile {
-        base_path: current_path.as_str().to_owned(), // base path is the current directory we are scanning
-        path: full_file_path.as_str().to_owned(),    // full path is the full path to the file from file system root
-        hash: hash.to_owned(),
-        hash_type: args.method,
-        match_type: MatchType::None,
-        game_name: None,
-        rom_name: None,
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: u64 = match &s[digits.len()..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
     };
-    if results.is_empty() {
-        debug_log!(debug, "No matches found in database");
-        if args.file_display.contains(&DisplayMethod::Miss) {
-            println!("[MISS] {} {}", hash, rel_file_path);
-        }
-        db.store_file(&scanned_file)?;
-    } else {
-        debug_log!(debug, "Found {} matching entries in database", results.len());
-        let matches = check_rom_matches(db, args, debug, rel_file_path, filename, &results, found_games)?;
-        handle_rom_matches(db, args, debug, full_file_path, rel_file_path, &mut scanned_file, &matches, can_rename)?;
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .and_then(|seconds| SystemTime::now().checked_sub(Duration::from_secs(seconds)))
+        .ok_or_else(invalid)
+}
+
+// a relative path ending in the rom name, that cannot climb out of the directory the file is in
+fn parse_rename_template(s: &str) -> Result<String, String> {
+    let path = Utf8Path::new(s);
+    if path.is_absolute() || path.components().any(|component| component.as_str() == "..") {
+        return Err(format!("invalid rename template `{s}`, it must be a relative path that stays within the directory"));
+    }
+    if path.file_name() != Some("{rom}") {
+        return Err(format!("invalid rename template `{s}`, it must end in {{rom}} so the file is named after its rom"));
     }
-    Ok(hash)
+    Ok(s.to_owned())
 }
 
-// update functions
+// a timestamp as sqlite stores CURRENT_TIMESTAMP, "YYYY-MM-DD HH:MM:SS" in UTC
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once(' ')?;
+    let date: Vec<u64> = date.splitn(3, '-').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let time: Vec<u64> = time.splitn(3, ':').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let (&[year, month, day], &[hours, minutes, seconds]) = (&date[..], &time[..]) else {
+        return None;
+    };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let since_epoch = Duration::from_secs(((days * 24 + hours) * 60 + minutes) * 60 + seconds);
+    SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+}
 
-fn update_directory(db: &database::Database, args: &ScanArgs, debug: bool, exclude_extensions: &[String]) -> Result<()> {
-    debug_log!(debug, "Using hash type: {}", args.method);
+// days since 1970-01-01 of a date from then on, counting march as the first month so leap days come last
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year;
+    //the same count for 1970-01-01
+    days - 719468
+}
 
-    let mut dir_stack: Vec<Utf8PathBuf> = Vec::new();
-    dir_stack.push(args.directory.clone());
+impl ScanArgs {
+    // whether the file is named after the rom, ignoring case with --case-insensitive-names
+    fn is_rom_name(&self, file_name: &str, rom_name: &str) -> bool {
+        if self.case_insensitive_names {
+            file_name.eq_ignore_ascii_case(rom_name)
+        } else {
+            file_name == rom_name
+        }
+    }
 
-    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+    // the rename template filled in for the rom, relative to the directory of the file
+    fn rename_target(&self, game_name: &str, rom_name: &str) -> Utf8PathBuf {
+        let ext = Utf8Path::new(rom_name).extension().unwrap_or_default();
+        Utf8PathBuf::from(
+            self.rename_template
+                .replace("{game}", &path_component(game_name))
+                .replace("{rom}", &path_component(rom_name))
+                .replace("{ext}", &path_component(ext)),
+        )
+    }
 
-    let mut db_files = BTreeMap::new();
-    let mut hash_to_file: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    // the header rules asked for, --try-headerless falls back to the default rules like --strip-headers
+    fn header_rules(&self) -> Result<Option<HeaderRules>> {
+        match self.headers.load()? {
+            None if self.try_headerless => Ok(Some(HeaderRules::defaults()?)),
+            rules => Ok(rules),
+        }
+    }
 
-    while let Some(current_path) = dir_stack.pop() {
-        println!("Updating directory: {}", current_path);
+    // whether --newer-than leaves the file out, a file whose time can't be read is always looked at
+    fn is_older(&self, path: &Utf8Path) -> bool {
+        self.newer_than.is_some_and(|threshold| {
+            path.metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified < threshold)
+        })
+    }
 
-        // Get all entries in the database with the same base path
-        let files = db.get_files_by_base_path(current_path.as_str())?;
-        for file in files {
-            db_files.insert(file.path.clone(), file);
-        }
+    // with --only-missing, the exact match stored for the path if the file has not been modified since it was scanned.
+    // the modified time is read from the disk path, the archive for a member of one
+    fn known_match<'a>(
+        &self,
+        known: &'a BTreeMap<String, ScannedFile>,
+        path: &Utf8Path,
+        disk_path: &Utf8Path,
+    ) -> Option<&'a ScannedFile> {
+        let scanned_file = known.get(path.as_str())?;
+        //the time is stored to the second, so a file modified in the same second is hashed again
+        let scanned = scanned_file.last_scanned.as_deref().and_then(parse_timestamp)?;
+        let modified = disk_path.metadata().and_then(|metadata| metadata.modified()).ok()?;
+        (modified < scanned).then_some(scanned_file)
+    }
+}
 
-        // Read directory contents and sort by path
-        let mut entries: Vec<Utf8DirEntry> = current_path.read_dir_utf8()?.filter_map(Result::ok).collect();
-        entries.sort_by_key(|entry| entry.path().to_owned());
+impl<'a> ScanContext<'a> {
+    // resolve the directory, header rules and hash methods the arguments ask for, reporting what auto picked
+    fn new(db: &database::Database, args: &'a ScanArgs, reporter: Box<dyn Reporter>, read_only: bool) -> Result<Self> {
+        let mut scan = Self {
+            args,
+            directory: resolve_directory(&args.directory)?,
+            method: Vec::new(),
+            rules: args.header_rules()?,
+            read_only,
+            name_only: false,
+            reporter,
+            moved_from: RefCell::default(),
+            hashed: RefCell::default(),
+            kept: Cell::default(),
+        };
+        scan.resolve_methods(db)?;
+        Ok(scan)
+    }
 
-        for entry in entries {
-            let full_path = entry.path();
+    // whether the CRC an archive records for a member will do instead of hashing it. Not when the CRC would cover
+    // a header that has to be skipped, or for a CHD, which is identified by the SHA1 in its header
+    fn uses_recorded_crc(&self, path: &Utf8Path, header: Option<&HeaderRule>) -> bool {
+        self.method == [HashType::Crc] && !self.args.verify_zip_crc && header.is_none() && !is_chd_file(path)
+    }
 
-            if full_path.is_dir() {
-                if args.recursive {
-                    debug_log!(debug, "\nDebug: Queuing directory: {}", full_path);
-                    dir_stack.push(full_path.into());
+    // turn the methods asked for into hash types, reporting what auto picked
+    fn resolve_methods(&mut self, db: &database::Database) -> Result<()> {
+        let mut hash_types = Vec::new();
+        for method in &self.args.methods {
+            let picked = match method {
+                HashMethod::Crc => vec![HashType::Crc],
+                HashMethod::Md5 => vec![HashType::Md5],
+                HashMethod::Sha1 => vec![HashType::Sha1],
+                HashMethod::Sha256 => vec![HashType::Sha256],
+                HashMethod::Auto => {
+                    let picked = auto_hash_types(db)?;
+                    let names: Vec<String> = picked.iter().map(|hash_type| hash_type.to_string().to_lowercase()).collect();
+                    self.reporter.info(&format!("Hash method auto picked: {}", names.join(", ")));
+                    picked
+                }
+            };
+            for hash_type in picked {
+                if !hash_types.contains(&hash_type) {
+                    hash_types.push(hash_type);
                 }
-                continue;
             }
+        }
+        //a DAT that only lists names and sizes can still be matched, just not by content
+        let (roms, counts) = db.count_rom_hashes()?;
+        self.name_only = roms > 0
+            && !counts
+                .iter()
+                .any(|(counted, count)| hash_types.contains(counted) && *count > 0);
+        if self.name_only {
+            let names: Vec<String> = hash_types
+                .iter()
+                .map(|hash_type| hash_type.to_string().to_lowercase())
+                .collect();
+            warn!("no rom in the database has a {} hash, matching files by name and size instead", names.join(" or "));
+        }
+        self.method = hash_types;
+        Ok(())
+    }
 
-            if should_skip_file(full_path, exclude_extensions) {
-                continue;
-            }
+    // count the stored match as found, and as scanned now so it is not removed with the stale entries
+    fn keep_known_match(
+        &self,
+        db: &database::Database,
+        rel_file_path: &Utf8Path,
+        scanned_file: &ScannedFile,
+        found_games: &mut BTreeMap<String, GameStatus>,
+    ) -> Result<()> {
+        debug!("Already matched: {}", rel_file_path);
+        update_found_file(db, rel_file_path, scanned_file, found_games)?;
+        if !self.read_only {
+            db.touch_file(&scanned_file.path)?;
+        }
+        self.kept.set(self.kept.get() + 1);
+        Ok(())
+    }
 
-            //relative path from start of scan
-            let rel_file_path = full_path
-                .strip_prefix(&args.directory)
-                .expect("should be able to strip prefix");
-            debug_log!(debug, "\nDebug: Processing file: {}", rel_file_path);
+    // report the file if its kind of result is displayed, it is counted in the summary either way
+    fn report_file(&self, display: DisplayMethod, report: FileReport) {
+        if self.args.file_display.contains(&display) {
+            self.reporter.file(report);
+        } else {
+            self.reporter.count(report.status);
+        }
+    }
+}
 
-            //check if this is a zip file and treat it accorgingly
-            if is_zip_file(full_path) {
-                if let Err(e) = update_zip_contents(
-                    db,
-                    args,
-                    debug,
-                    &current_path,
-                    full_path,
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, ValueEnum, IntoStaticStr, Display)]
+enum DisplayMethod {
+    /// Display exact matches only
+    Exact,
+    /// Display partial matches only
+    Partial,
+    /// Display misses only
+    Miss,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum MatchBy {
+    /// Exact when the file name matches the rom name
+    Name,
+    /// Exact when the size matches the rom size, the rom name is only used to rename with --fix
+    Hash,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum HashMethod {
+    Crc,
+    Md5,
+    Sha1,
+    Sha256,
+    /// Pick from the hashes the roms in the database have, crc when they have none and files are matched by name
+    Auto,
+}
+
+// strongest first, the order auto prefers them in
+const HASH_STRENGTH: [HashType; 4] = [HashType::Sha256, HashType::Sha1, HashType::Md5, HashType::Crc];
+
+// the strongest hash every rom has, or if the DAT is mixed, every hash that some rom has so each can still be matched
+fn auto_hash_types(db: &database::Database) -> Result<Vec<HashType>> {
+    let (roms, counts) = db.count_rom_hashes()?;
+    let populated: Vec<HashType> = HASH_STRENGTH
+        .into_iter()
+        .filter(|hash_type| counts.iter().any(|(counted, count)| counted == hash_type && *count > 0))
+        .collect();
+    if let Some(full) = HASH_STRENGTH
+        .into_iter()
+        .find(|hash_type| counts.iter().any(|(counted, count)| counted == hash_type && *count == roms))
+        .filter(|_| roms > 0)
+    {
+        return Ok(vec![full]);
+    }
+    if populated.is_empty() {
+        if roms == 0 {
+            return Err(anyhow!("Unable to pick a hash method, no rom in the database has a hash"));
+        }
+        //files are matched by name, the cheapest hash is still stored to notice them changing
+        return Ok(vec![HashType::Crc]);
+    }
+    Ok(populated)
+}
+
+pub fn handle_command(
+    db: &mut database::Database,
+    format: OutputFormat,
+    options: ReportOptions,
+    command: &FileCommands,
+    filter: &FileFilter,
+) -> Result<Outcome> {
+    let outcome = match command {
+        FileCommands::Scan { args, rescan } => {
+            output::require_human_or_json(format, "file scan")?;
+            let reporter = report::create_for_output(format, options, args.output.as_deref())?;
+            let scan = ScanContext::new(db, args, reporter, false)?;
+            if args.fix && !args.dry_run {
+                ui::require_confirmation("rename files with --fix")?;
+            }
+            let started = Instant::now();
+            scan_directory(db, &scan, rescan, filter).context("Failed to scan directory")?;
+            prune_moved_from(&scan)?;
+            let outcome = scan.reporter.finish()?;
+            if args.stats {
+                scan.hashed.borrow().print(started.elapsed());
+            }
+            outcome
+        }
+        FileCommands::Update(args) => {
+            output::require_human_or_json(format, "file update")?;
+            let reporter = report::create_for_output(format, options, args.output.as_deref())?;
+            let scan = ScanContext::new(db, args, reporter, false)?;
+            if args.fix && !args.dry_run {
+                ui::require_confirmation("rename files with --fix")?;
+            }
+            let started = Instant::now();
+            update_directory(db, &scan, filter).context("Failed to update directory")?;
+            prune_moved_from(&scan)?;
+            let outcome = scan.reporter.finish()?;
+            if args.stats {
+                scan.hashed.borrow().print(started.elapsed());
+            }
+            outcome
+        }
+        FileCommands::Check {
+            directory,
+            recursive,
+            output,
+            headers,
+        } => {
+            output::require_human_or_json(format, "file check")?;
+            let directory = resolve_directory(directory)?;
+            let rules = headers.load()?;
+            let reporter = report::create_for_output(format, options, output.as_deref())?;
+            check_directory(db, reporter.as_ref(), filter, &directory, *recursive, rules.as_ref())
+                .context("Failed to check directory")?;
+            reporter.finish()?
+        }
+        FileCommands::Verify {
+            directory,
+            recursive,
+            remove_gone,
+            headers,
+        } => {
+            output::require_human_or_json(format, "file verify")?;
+            let directory = resolve_directory(directory)?;
+            let rules = headers.load()?;
+            let reporter = report::create(format, options);
+            verify_files(db, reporter.as_ref(), filter, &directory, *recursive, *remove_gone, rules.as_ref())
+                .context("Failed to verify files")?;
+            reporter.finish()?
+        }
+        FileCommands::Watch(args) => {
+            output::require_human(format, "file watch")?;
+            let reporter = report::create_for_output(format, options, args.output.as_deref())?;
+            let scan = ScanContext::new(db, args, reporter, false)?;
+            if args.fix && !args.dry_run {
+                ui::require_confirmation("rename files with --fix")?;
+            }
+            watch_directory(db, &scan, filter).context("Failed to watch directory")?;
+            Outcome::Complete
+        }
+        FileCommands::Stats {
+            directory,
+            method,
+            match_by,
+            recursive,
+            headers,
+        } => {
+            let args = ScanArgs {
+                methods: method.clone(),
+                file_display: Vec::new(),
+                match_by: *match_by,
+                directory: directory.clone(),
+                recursive: *recursive,
+                headers: headers.clone(),
+                ..default_args()
+            };
+            let scan = ScanContext::new(db, &args, report::create(format, options), true)?;
+            scan_directory(db, &scan, &default_args(), filter).context("Failed to scan directory")?;
+            scan.reporter.finish()?
+        }
+        FileCommands::Hash(args) => {
+            hash_directory(args, filter, format)?;
+            Outcome::Complete
+        }
+        FileCommands::Bench(args) => {
+            bench_hashes(args, filter, format)?;
+            Outcome::Complete
+        }
+        FileCommands::List {
+            directory,
+            recursive,
+            canonical_only,
+            script,
+            per_rom,
+            output,
+            show_dates,
+        } => {
+            let directory = resolve_directory(directory)?;
+            if let Some(game_name) = per_rom {
+                list_game_roms(db, &directory, format, *recursive, game_name).context("Failed to list game roms")?;
+            } else if *canonical_only {
+                if *script {
+                    output::require_human(format, "file list --script")?;
+                }
+                list_non_canonical(db, &directory, format, *recursive, *script).context("Failed to list directory")?;
+            } else {
+                list_directory(db, &directory, format, options, output.as_deref(), *recursive, *show_dates)
+                    .context("Failed to list directory")?;
+            }
+            Outcome::Complete
+        }
+        FileCommands::Dupes { directory, recursive } => {
+            let directory = resolve_directory(directory)?;
+            list_duplicates(db, &directory, format, *recursive).context("Failed to list duplicates")?;
+            Outcome::Complete
+        }
+        FileCommands::Clean { base_path, dry_run } => {
+            output::require_human(format, "file clean")?;
+            let base_path = base_path.as_ref().map(resolve_directory).transpose()?;
+            clean_files(db, base_path.as_deref(), *dry_run).context("Failed to clean database")?;
+            Outcome::Complete
+        }
+        FileCommands::Relocate {
+            from,
+            to,
+            verify,
+            dry_run,
+        } => {
+            output::require_human(format, "file relocate")?;
+            let to = resolve_directory(to)?;
+            //the files were moved away, so the old directory is usually gone and cannot be canonicalized
+            let from = match from.canonicalize_utf8() {
+                Ok(from) => from,
+                Err(_) => Utf8PathBuf::try_from(std::path::absolute(from)?)?,
+            };
+            if from == to {
+                return Err(anyhow!("The directories to relocate from and to are the same"));
+            }
+            relocate_files(db, &from, &to, *verify, *dry_run).context("Failed to relocate files")?
+        }
+        FileCommands::Rebuild {
+            directory,
+            recursive,
+            output,
+            remove_source,
+            dry_run,
+        } => {
+            output::require_human(format, "file rebuild")?;
+            let directory = resolve_directory(directory)?;
+            let output = output.as_ref().map(resolve_directory).transpose()?;
+            if *remove_source && !*dry_run {
+                ui::require_confirmation("remove files with --remove-source")?;
+            }
+            let output = output.as_deref().unwrap_or(&directory);
+            rebuild_games(db, &directory, output, *recursive, *remove_source, *dry_run).context("Failed to rebuild games")?
+        }
+    };
+    Ok(outcome)
+}
+
+/// The arguments as clap fills them in when none are given, for commands that scan on their own terms
+pub fn default_args<T: Args + FromArgMatches>() -> T {
+    let matches = T::augment_args(Command::new("rcr2")).get_matches_from(["rcr2"]);
+    T::from_arg_matches(&matches).expect("the defaults should parse")
+}
+
+fn resolve_directory(directory: &Utf8PathBuf) -> Result<Utf8PathBuf> {
+    if !directory.exists() {
+        return Err(anyhow!("Directory does not exist: {}", directory));
+    }
+    if !directory.is_dir() {
+        return Err(anyhow!("Not a directory: {}", directory));
+    }
+    directory.read_dir_utf8().map_err(|e| describe_read_dir_error(directory, e))?;
+    directory
+        .canonicalize_utf8()
+        .context("Failed to resolve directory to full path")
+}
+
+fn describe_read_dir_error(directory: &Utf8Path, error: std::io::Error) -> anyhow::Error {
+    if error.kind() == ErrorKind::PermissionDenied {
+        anyhow!("Directory not readable (permission denied): {}", directory)
+    } else {
+        anyhow::Error::new(error).context(format!("Failed to read directory: {}", directory))
+    }
+}
+
+// read the directory contents sorted by path
+fn read_sorted_entries(directory: &Utf8Path) -> Result<Vec<Utf8DirEntry>> {
+    let mut entries: Vec<Utf8DirEntry> = directory
+        .read_dir_utf8()
+        .map_err(|e| describe_read_dir_error(directory, e))?
+        .filter_map(Result::ok)
+        .collect();
+    entries.sort_by_key(|entry| entry.path().to_owned());
+    Ok(entries)
+}
+
+// the directories still to be processed in a walk of the tree. Symlinked directories are skipped unless
+// following them, and then no directory is queued twice so a symlink cycle can't loop forever
+struct DirStack {
+    root: Utf8PathBuf,
+    // each directory with how many levels below the root it is
+    pending: Vec<(Utf8PathBuf, usize)>,
+    // depth of the directory last popped, whose subdirectories are the ones pushed
+    depth: usize,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    // canonical paths of the directories queued so far, only kept when following symlinks
+    visited: HashSet<Utf8PathBuf>,
+    excluded_paths: GlobSet,
+}
+
+impl DirStack {
+    fn new(root: &Utf8Path, filter: &FileFilter) -> Self {
+        let mut visited = HashSet::new();
+        if filter.follow_symlinks {
+            visited.insert(root.canonicalize_utf8().unwrap_or_else(|_| root.to_owned()));
+        }
+        DirStack {
+            root: root.to_owned(),
+            pending: vec![(root.to_owned(), 0)],
+            depth: 0,
+            max_depth: filter.max_depth,
+            follow_symlinks: filter.follow_symlinks,
+            visited,
+            excluded_paths: filter.excluded_paths.clone(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Utf8PathBuf> {
+        let (directory, depth) = self.pending.pop()?;
+        self.depth = depth;
+        Some(directory)
+    }
+
+    fn push(&mut self, directory: &Utf8Path) {
+        let depth = self.depth + 1;
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            debug!("Skipping directory below --max-depth: {}", directory);
+            return;
+        }
+        //its parent was already checked when it was queued, so only the directory itself is left to match
+        let rel_path = directory.strip_prefix(&self.root).unwrap_or(directory);
+        if self.excluded_paths.is_match(rel_path) {
+            debug!("Skipping directory matching --exclude-paths: {}", directory);
+            return;
+        }
+        if !self.follow_symlinks {
+            if directory.is_symlink() {
+                debug!("Skipping symlinked directory: {}", directory);
+                return;
+            }
+        } else if !directory
+            .canonicalize_utf8()
+            .is_ok_and(|canonical| self.visited.insert(canonical))
+        {
+            debug!("Skipping directory already visited: {}", directory);
+            return;
+        }
+        trace!("Queuing directory: {}", directory);
+        self.pending.push((directory.to_owned(), depth));
+    }
+}
+
+// what was hashed, for --stats
+#[derive(Default)]
+struct HashStats {
+    files: u64,
+    bytes: u64,
+    // in the order the methods were asked for
+    hashing: HashTimes,
+}
+
+impl HashStats {
+    fn add(&mut self, file_hash: &FileHash) {
+        self.files += 1;
+        self.bytes += file_hash.size;
+        for (method, elapsed) in &file_hash.hash_time {
+            match self.hashing.iter_mut().find(|(existing, _)| existing == method) {
+                Some((_, total)) => *total += *elapsed,
+                None => self.hashing.push((*method, *elapsed)),
+            }
+        }
+    }
+
+    fn print(&self, elapsed: Duration) {
+        let megabytes = self.bytes as f64 / (1 << 20) as f64;
+        eprintln!(
+            "\nHashed {} file(s), {:.1} MB in {:.2}s ({:.1} MB/s)",
+            self.files,
+            megabytes,
+            elapsed.as_secs_f64(),
+            throughput(megabytes, elapsed)
+        );
+        for (method, hashing) in &self.hashing {
+            eprintln!(
+                "  {:<6} {:.2}s ({:.1} MB/s)",
+                method.to_string().to_lowercase(),
+                hashing.as_secs_f64(),
+                throughput(megabytes, *hashing)
+            );
+        }
+    }
+}
+
+fn throughput(megabytes: f64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        megabytes / elapsed.as_secs_f64()
+    }
+}
+
+fn print_unreadable_directories(unreadable: &[Utf8PathBuf]) {
+    if !unreadable.is_empty() {
+        eprintln!("\nSkipped {} unreadable director(ies):", unreadable.len());
+        for directory in unreadable {
+            eprintln!("[SKIP] {}", directory);
+        }
+    }
+}
+
+// scan functions
+
+fn scan_directory(db: &database::Database, scan: &ScanContext, rescan_args: &RescanArgs, filter: &FileFilter) -> Result<()> {
+    debug!("Using hash types: {:?}", scan.method);
+
+    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+    let mut unreadable = Vec::new();
+
+    ui::progress_start("Scanning");
+
+    //only a scan that stores its results can be resumed, from what it stored
+    let mut checkpoint = if scan.read_only {
+        None
+    } else {
+        Some(Checkpoint::open(&scan.directory, rescan_args.checkpoint_every, rescan_args.resume)?)
+    };
+    let resuming = checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.len() > 0);
+    let mut resumed = 0;
+
+    let mut dir_stack = DirStack::new(&scan.directory, filter);
+
+    while let Some(current_path) = dir_stack.pop() {
+        scan.reporter.info(&format!("Scanning directory: {}", current_path));
+
+        // Read directory contents and sort by path
+        let entries = match read_sorted_entries(&current_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                //keep going with the rest of the tree, the directory is reported at the end
+                eprintln!("{:#}", e);
+                unreadable.push(current_path);
+                continue;
+            }
+        };
+
+        //read before the entries are marked stale, which clears the time they were scanned
+        let known = if rescan_args.only_missing {
+            known_matches(db, &current_path)?
+        } else {
+            BTreeMap::new()
+        };
+        //what the interrupted scan stored for the files it finished, and what earlier scans stored for the files
+        //older than --newer-than
+        let mut stored: BTreeMap<String, ScannedFile> = if resuming || scan.args.newer_than.is_some() {
+            db.get_files_by_base_path(current_path.as_str())?
+                .into_iter()
+                .map(|scanned_file| (scanned_file.path.clone(), scanned_file))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        //the entries of the directory are replaced in one transaction, so a scan stopped part way leaves them as they were,
+        //and sqlite syncs to disk once for the directory rather than once for every file stored.
+        //saving the checkpoint commits it early, as the files it lists must have their entries stored
+        let mut batch = (!scan.read_only).then(|| db.begin_batch()).transpose()?;
+
+        //files with the same base path that are not stored again by the end of the directory are removed from the database.
+        //not when only newer files are scanned, as the older ones keep their entries
+        let rescan = !scan.read_only && !rescan_args.incremental && scan.args.newer_than.is_none();
+        if rescan {
+            db.mark_files_stale(current_path.as_str())?;
+        }
+
+        for entry in entries {
+            let full_path = entry.path();
+
+            if full_path.is_dir() {
+                if scan.args.recursive {
+                    dir_stack.push(full_path);
+                }
+                continue;
+            }
+
+            if should_skip_file(full_path, &scan.directory, filter) {
+                continue;
+            }
+
+            let rel_path = full_path
+                .strip_prefix(&scan.directory)
+                .expect("should be able to strip prefix");
+
+            if scan.args.is_older(full_path) {
+                //not looked at again, whatever the database has for it (or anything inside it) still counts as found
+                if let Err(e) = keep_stored_files(db, full_path, rel_path, &mut stored, &mut found_games) {
+                    eprintln!("Failed to process file: {}", e);
+                }
+                continue;
+            }
+
+            if checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.contains(full_path)) {
+                //kept as the interrupted scan stored them, so they are not removed as stale
+                for path in keep_stored_files(db, full_path, rel_path, &mut stored, &mut found_games)? {
+                    db.touch_file(&path)?;
+                }
+                resumed += 1;
+                continue;
+            }
+
+            let result = if let Some(kind) = filter.archive_kind(full_path) {
+                scan_archive_contents(kind, db, scan, &current_path, full_path, rel_path, filter, &known, &mut found_games)
+                    .with_context(|| format!("Failed to process {} file", kind))
+            } else if let Some(scanned_file) = scan.args.known_match(&known, full_path, full_path) {
+                scan.keep_known_match(db, rel_path, scanned_file, &mut found_games)?;
+                Ok(())
+            } else {
+                File::open(full_path)
+                    .context("Unable to open file")
+                    .and_then(|mut file| {
+                        scan_file_contents(db, scan, &current_path, full_path, rel_path, &mut file, None, &mut found_games, true)
+                    })
+                    .context("Failed to process file")
+                    .map(drop)
+            };
+            match result {
+                //a file that failed is left out of the checkpoint, so a resumed scan tries it again
+                Err(e) => eprintln!("{:#}", e),
+                Ok(()) => {
+                    if let Some(checkpoint) = checkpoint.as_mut() {
+                        if checkpoint.finish(full_path) {
+                            if let Some(committed) = batch.take() {
+                                committed.commit()?;
+                                batch = Some(db.begin_batch()?);
+                            }
+                            checkpoint.save();
+                        }
+                    }
+                }
+            }
+        }
+
+        if rescan {
+            db.delete_stale_files(current_path.as_str())?;
+        } else if rescan_args.incremental && !scan.read_only {
+            delete_gone_files(db, &current_path)?;
+        }
+        if let Some(batch) = batch {
+            batch.commit()?;
+        }
+    }
+
+    ui::progress_finish();
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.remove()?;
+    }
+    if resumed > 0 {
+        scan.reporter
+            .info(&format!("Resumed after {} file(s) finished by the interrupted scan", resumed));
+    }
+    if rescan_args.only_missing {
+        scan.reporter
+            .info(&format!("Skipped {} file(s) already matched exactly", scan.kept.get()));
+    }
+    scan.reporter.games(&found_games);
+    print_unreadable_directories(&unreadable);
+
+    Ok(())
+}
+
+// remove the entries with the base path of files no longer on the disk
+fn delete_gone_files(db: &database::Database, base_path: &Utf8Path) -> Result<()> {
+    for scanned_file in db.get_files_by_base_path(base_path.as_str())? {
+        if !is_on_disk(Utf8Path::new(&scanned_file.path)) {
+            debug!("deleting database entry: {}", scanned_file.path);
+            db.delete_file(&scanned_file.path)?;
+        }
+    }
+    Ok(())
+}
+
+// the files with the base path stored as exact matches, by path
+fn known_matches(db: &database::Database, base_path: &Utf8Path) -> Result<BTreeMap<String, ScannedFile>> {
+    Ok(db
+        .get_files_by_base_path(base_path.as_str())?
+        .into_iter()
+        .filter(|scanned_file| scanned_file.match_type == MatchType::Exact)
+        .map(|scanned_file| (scanned_file.path.clone(), scanned_file))
+        .collect())
+}
+
+fn scan_archive_contents(
+    kind: ArchiveKind,
+    db: &database::Database,
+    scan: &ScanContext,
+    current_path: &Utf8Path,
+    archive_path: &Utf8Path,
+    rel_archive_path: &Utf8Path,
+    filter: &FileFilter,
+    known: &BTreeMap<String, ScannedFile>,
+    found_games: &mut BTreeMap<String, GameStatus>,
+) -> Result<()> {
+    let mut archive = kind.open(archive_path, filter.nesting())?;
+    archive.for_each_file_with_crc(&mut |inner_path, size, crc, mut file| {
+        if is_excluded_member(inner_path, size, filter) {
+            return Ok(());
+        }
+
+        let full_file_path = archive_path.join(inner_path);
+        let rel_file_path = rel_archive_path.join(inner_path);
+        if let Some(scanned_file) = scan.args.known_match(known, &full_file_path, archive_path) {
+            return scan.keep_known_match(db, &rel_file_path, scanned_file, found_games);
+        }
+        let recorded_crc = crc.map(|crc| (crc, size));
+        if let Err(e) =
+            scan_file_contents(db, scan, current_path, &full_file_path, &rel_file_path, &mut file, recorded_crc, found_games, false)
+        {
+            //continue to next file if we have an error
+            eprintln!("Failed to process file: {}", e);
+        }
+        Ok(())
+    })
+}
+
+fn scan_file_contents(
+    db: &database::Database,
+    scan: &ScanContext,
+    current_path: &Utf8Path,
+    full_file_path: &Utf8Path,
+    rel_file_path: &Utf8Path,
+    file: &mut impl Read,
+    recorded_crc: Option<(u32, u64)>,
+    found_games: &mut BTreeMap<String, GameStatus>,
+    can_rename: bool,
+) -> Result<String> {
+    debug!("Processing file: {}", rel_file_path);
+    ui::progress_tick(rel_file_path.as_str());
+    let header = scan.rules.as_ref().and_then(|rules| rules.for_path(full_file_path));
+    let mut file_hash = match recorded_crc.filter(|_| scan.uses_recorded_crc(full_file_path, header)) {
+        Some((crc, size)) => FileHash::recorded_crc(crc, size),
+        None => {
+            let file_hash = hash_contents(file, full_file_path, &scan.method, header, scan.args.try_headerless)?;
+            scan.hashed.borrow_mut().add(&file_hash);
+            file_hash
+        }
+    };
+
+    let filename = full_file_path.file_name().ok_or_else(|| anyhow!("Invalid file name"))?;
+
+    let mut results = search_file_hash(db, scan, filename, &file_hash)?;
+    let mut with_header = false;
+    if results.is_empty() {
+        //the dat may list the rom with its header, so it is stored as hashed whole and checked that way from then on
+        if let Some(headered) = file_hash.with_header() {
+            let headered_results = search_file_hash(db, scan, filename, &headered)?;
+            if !headered_results.is_empty() {
+                debug!("Matched with the header kept");
+                file_hash = headered;
+                results = headered_results;
+                with_header = true;
+            }
+        }
+    }
+    let size = file_hash.size;
+    let hash_type = file_hash.hashes[0].0;
+    let hash = file_hash.primary();
+    let hash_count = file_hash.hashes.len();
+    let mut scanned_file = models::ScannedFile {
+        base_path: current_path.as_str().to_owned(), // base path is the current directory we are scanning
+        path: full_file_path.as_str().to_owned(),    // full path is the full path to the file from file system root
+        hash: hash.to_owned(),
+        hash_type,
+        match_type: MatchType::None,
+        game_name: None,
+        rom_name: None,
+        //a disk is hashed from its header, so how much was read says nothing about its size
+        size: (!file_hash.disk).then_some(size),
+        header_offset: Some(file_hash.header_offset),
+        first_seen: None,
+        last_scanned: None,
+    };
+    if results.is_empty() {
+        debug!("No matches found in database");
+        scan.report_file(DisplayMethod::Miss, FileReport::new(FileStatus::Miss, rel_file_path.as_str(), Some(hash)));
+        store_file(db, scan, &scanned_file)?;
+    } else {
+        debug!("Found {} matching entries in database", results.len());
+        let mut matches = check_rom_matches(db, scan, rel_file_path, filename, size, hash_count, &results, found_games)?;
+        matches.with_header = with_header;
+        handle_rom_matches(db, scan, full_file_path, rel_file_path, &mut scanned_file, &matches, can_rename)?;
+    }
+    Ok(hash.to_owned())
+}
+
+// the database entries the hashed file matches
+fn search_file_hash(
+    db: &database::Database,
+    scan: &ScanContext,
+    filename: &str,
+    file_hash: &FileHash,
+) -> Result<Vec<(models::Game, Vec<RomMatch>)>> {
+    //the first hash is the one stored, disks are only ever identified by the SHA1 in their header
+    let hash = file_hash
+        .hashes
+        .first()
+        .map(|(_, hash)| hash.as_str())
+        .ok_or_else(|| anyhow!("No hash method given"))?;
+    if file_hash.disk {
+        return search_disks(db, hash);
+    }
+    if scan.name_only {
+        return db.search_roms_by_name(filename);
+    }
+    let hashes: Vec<(HashType, &str)> = file_hash
+        .hashes
+        .iter()
+        .map(|(hash_type, hash)| (*hash_type, hash.as_str()))
+        .collect();
+    let mut results = db.search_roms_by_hash(&hashes, crc_size(scan, file_hash.size))?;
+    if file_hash.size == 0 {
+        results.extend(db.search_empty_roms(filename)?);
+    }
+    Ok(results)
+}
+
+// update functions
+
+fn update_directory(db: &database::Database, scan: &ScanContext, filter: &FileFilter) -> Result<()> {
+    debug!("Using hash types: {:?}", scan.method);
+
+    let mut dir_stack = DirStack::new(&scan.directory, filter);
+
+    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+
+    let mut db_files = BTreeMap::new();
+    let mut hash_to_file: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+    let mut unreadable = Vec::new();
+
+    ui::progress_start("Updating");
+
+    while let Some(current_path) = dir_stack.pop() {
+        scan.reporter.info(&format!("Updating directory: {}", current_path));
+
+        // Read directory contents and sort by path, before loading the database entries so that
+        // files in an unreadable directory are not reported as gone
+        let entries = match read_sorted_entries(&current_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                //keep going with the rest of the tree, the directory is reported at the end
+                eprintln!("{:#}", e);
+                unreadable.push(current_path);
+                continue;
+            }
+        };
+
+        //the new files of the directory are stored in one transaction, committed once the directory is done
+        let batch = db.begin_batch()?;
+
+        // Get all entries in the database with the same base path
+        let files = db.get_files_by_base_path(current_path.as_str())?;
+        for file in files {
+            db_files.insert(file.path.clone(), file);
+        }
+
+        for entry in entries {
+            let full_path = entry.path();
+
+            if full_path.is_dir() {
+                if scan.args.recursive {
+                    dir_stack.push(full_path);
+                }
+                continue;
+            }
+
+            if should_skip_file(full_path, &scan.directory, filter) {
+                continue;
+            }
+
+            //relative path from start of scan
+            let rel_file_path = full_path
+                .strip_prefix(&scan.directory)
+                .expect("should be able to strip prefix");
+            debug!("Processing file: {}", rel_file_path);
+
+            if scan.args.is_older(full_path) {
+                //not looked at again, whatever the database has for it (or anything inside it) still counts as found
+                if let Err(e) = keep_stored_files(db, full_path, rel_file_path, &mut db_files, &mut found_games) {
+                    eprintln!("Failed to process file: {}", e);
+                }
+                continue;
+            }
+
+            //check if this is an archive and treat it accorgingly
+            if let Some(kind) = filter.archive_kind(full_path) {
+                if let Err(e) = update_archive_contents(
+                    kind,
+                    db,
+                    scan,
+                    &current_path,
+                    full_path,
                     rel_file_path,
-                    exclude_extensions,
+                    filter,
                     &mut db_files,
                     &mut hash_to_file,
                     &mut found_games,
                 ) {
                     //continue to next file if we have an error
-                    eprintln!("Failed to process ZIP file: {}", e);
+                    eprintln!("Failed to process {} file: {}", kind, e);
+                }
+                continue;
+            }
+
+            if let Some(scanned_file) = db_files.remove(full_path.as_str()) {
+                //just treat the database as correct, and add it to the game status without recalculating the hash
+                if let Err(e) = update_found_file(db, rel_file_path, &scanned_file, &mut found_games) {
+                    eprintln!("Failed to process file: {}", e);
+                }
+            } else {
+                match File::open(full_path).context("Unable to open file").and_then(|mut file| {
+                    scan_file_contents(db, scan, &current_path, full_path, rel_file_path, &mut file, None, &mut found_games, true)
+                }) {
+                    Ok(hash) => {
+                        //store the file and the hash in a hash table so that we can find renamed files
+                        hash_to_file
+                            .entry(hash.clone())
+                            .or_default()
+                            .insert(full_path.as_str().to_owned());
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to process file: {}", e);
+                    }
+                }
+            }
+        }
+        batch.commit()?;
+    }
+
+    ui::progress_finish();
+    trace!("Hash to file: {:?}", hash_to_file);
+
+    //if there are missing file then we should remove them from the database, but we need to check if they were renamed first
+    let batch = db.begin_batch()?;
+    for db_file in db_files.values() {
+        debug!("Checking missing file: {} with hash: {}", db_file.path, db_file.hash);
+        if let Some(filenames) = hash_to_file.get(&db_file.hash) {
+            if filenames.len() == 1 {
+                //we have a single file with the same hash, so we can assume it was renamed
+                debug!("deleting database entry: {}", db_file.path);
+                db.delete_file(&db_file.path)?;
+            }
+
+            scan.reporter
+                .file(FileReport::new(FileStatus::Move, &db_file.path, Some(&db_file.hash)));
+        } else {
+            scan.reporter
+                .file(FileReport::new(FileStatus::Gone, &db_file.path, Some(&db_file.hash)));
+        }
+    }
+    batch.commit()?;
+
+    scan.reporter.games(&found_games);
+    print_unreadable_directories(&unreadable);
+
+    Ok(())
+}
+
+fn update_archive_contents(
+    kind: ArchiveKind,
+    db: &database::Database,
+    scan: &ScanContext,
+    current_path: &Utf8Path,
+    archive_path: &Utf8Path,
+    rel_archive_path: &Utf8Path,
+    filter: &FileFilter,
+    db_files: &mut BTreeMap<String, models::ScannedFile>,
+    hash_to_file: &mut BTreeMap<String, HashSet<String>>,
+    found_games: &mut BTreeMap<String, GameStatus>,
+) -> Result<()> {
+    let mut archive = kind.open(archive_path, filter.nesting())?;
+    archive.for_each_file_with_crc(&mut |inner_path, size, crc, mut file| {
+        if is_excluded_member(inner_path, size, filter) {
+            return Ok(());
+        }
+
+        debug!("Processing {} entry: {}", kind, inner_path);
+
+        let file_path = archive_path.join(inner_path);
+        let rel_file_path = rel_archive_path.join(inner_path);
+
+        if let Some(scanned_file) = db_files.remove(file_path.as_str()) {
+            //just treat the database as correct, and add it to the game status
+            if let Err(e) = update_found_file(db, &rel_file_path, &scanned_file, found_games) {
+                eprintln!("Failed to process file: {}", e);
+            }
+        } else {
+            //doesn't seem to be in the database, so check the hash and add it to the database
+            let recorded_crc = crc.map(|crc| (crc, size));
+            match scan_file_contents(
+                db,
+                scan,
+                current_path,
+                &file_path,
+                &rel_file_path,
+                &mut file,
+                recorded_crc,
+                found_games,
+                false,
+            ) {
+                Ok(hash) => {
+                    //store the file and the hash in a hash table so that we can find renamed files
+                    hash_to_file
+                        .entry(hash.clone())
+                        .or_default()
+                        .insert(file_path.as_str().to_owned());
+                }
+                Err(e) => {
+                    eprintln!("Failed to process file: {}", e);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// take the database entries for a file, and for the members of an archive, as found without opening it.
+// returns the paths of the entries taken
+fn keep_stored_files(
+    db: &database::Database,
+    full_path: &Utf8Path,
+    rel_file_path: &Utf8Path,
+    db_files: &mut BTreeMap<String, models::ScannedFile>,
+    found_games: &mut BTreeMap<String, GameStatus>,
+) -> Result<Vec<String>> {
+    let mut kept = Vec::new();
+    if let Some(scanned_file) = db_files.remove(full_path.as_str()) {
+        update_found_file(db, rel_file_path, &scanned_file, found_games)?;
+        kept.push(scanned_file.path);
+    }
+
+    let member_prefix = format!("{}/", full_path);
+    let members: Vec<String> = db_files
+        .range(member_prefix.clone()..)
+        .take_while(|(path, _)| path.starts_with(&member_prefix))
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in members {
+        let scanned_file = db_files.remove(&path).expect("should still have the member");
+        update_found_file(db, &rel_file_path.join(&path[member_prefix.len()..]), &scanned_file, found_games)?;
+        kept.push(path);
+    }
+    Ok(kept)
+}
+
+fn update_found_file(
+    db: &database::Database,
+    rel_file_path: &Utf8Path,
+    scanned_file: &models::ScannedFile,
+    found_games: &mut BTreeMap<String, GameStatus>,
+) -> Result<()> {
+    if let Some(game_name) = scanned_file.game_name.as_ref() {
+        let game_status = get_game_status(db, found_games, game_name)?;
+        let rom_name = scanned_file
+            .rom_name
+            .as_ref()
+            .ok_or_else(|| anyhow!("Scanned file {} has a game but no rom name", scanned_file.path))?;
+        if scanned_file.match_type == MatchType::Exact {
+            game_status
+                .exact_matches
+                .entry(rom_name.to_owned())
+                .or_default()
+                .insert(rel_file_path.as_str().to_owned());
+        } else {
+            game_status
+                .partial_matches
+                .entry(rom_name.to_owned())
+                .or_default()
+                .insert(rel_file_path.as_str().to_owned());
+        }
+    }
+    Ok(())
+}
+
+// check functions
+
+fn check_directory(
+    db: &database::Database,
+    reporter: &dyn Reporter,
+    filter: &FileFilter,
+    directory: &Utf8Path,
+    recursive: bool,
+    rules: Option<&HeaderRules>,
+) -> Result<()> {
+    let mut dir_stack = DirStack::new(directory, filter);
+
+    let mut db_files = BTreeMap::new();
+    let mut unreadable = Vec::new();
+
+    ui::progress_start("Checking");
+
+    while let Some(current_path) = dir_stack.pop() {
+        reporter.info(&format!("Checking directory: {}", current_path));
+
+        // Read directory contents and sort by path, before loading the database entries so that
+        // files in an unreadable directory are not reported as gone
+        let entries = match read_sorted_entries(&current_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                //keep going with the rest of the tree, the directory is reported at the end
+                eprintln!("{:#}", e);
+                unreadable.push(current_path);
+                continue;
+            }
+        };
+
+        // Get all entries in the database with the same base path
+        let files = db.get_files_by_base_path(current_path.as_str())?;
+        // Create a HashMap of the files in the database
+        for file in files {
+            db_files.insert(file.path.clone(), file);
+        }
+
+        // for each file in the directory, check if its in the database or not
+        // and report it on the console
+        for entry in entries {
+            let full_path = entry.path();
+
+            if full_path.is_dir() {
+                if recursive {
+                    dir_stack.push(full_path);
+                }
+                continue;
+            }
+
+            if should_skip_file(full_path, directory, filter) {
+                continue;
+            }
+
+            let rel_file_path = full_path.strip_prefix(directory).expect("should be able to strip prefix");
+            debug!("Processing file: {}", rel_file_path);
+
+            if let Some(kind) = filter.archive_kind(full_path) {
+                if let Err(e) = check_archive_file(kind, db, reporter, full_path, rel_file_path, filter, rules, &mut db_files) {
+                    //continue to next file if we have an error
+                    eprintln!("Failed to process {} file: {}", kind, e);
                 }
                 continue;
             }
 
             if let Some(scanned_file) = db_files.remove(full_path.as_str()) {
-                //just treat the database as correct, and add it to the game status without recalculating the hash
-                update_found_file(db, rel_file_path, &scanned_file, &mut found_games);
-            } else {
+                ui::progress_tick(rel_file_path.as_str());
                 match File::open(full_path).context("Unable to open file").and_then(|mut file| {
-                    scan_file_contents(db, args, debug, &current_path, full_path, rel_file_path, &mut file, &mut found_games, true)
+                    let header = recorded_header(&scanned_file, rules, full_path);
+                    hash_contents(&mut file, full_path, &[scanned_file.hash_type], header.as_ref(), false)
                 }) {
-                    Ok(hash) => {
-                        //store the file and the hash in a hash table so that we can find renamed files
-                        hash_to_file
-                            .entry(hash.clone())
-                            .or_default()
-                            .insert(full_path.as_str().to_owned());
+                    Ok(file_hash) => {
+                        touch_if_unchanged(db, file_hash.primary(), &scanned_file)?;
+                        reporter.file(scanned_file_report(
+                            db,
+                            file_hash.primary(),
+                            Some(file_hash.size),
+                            rel_file_path,
+                            &scanned_file,
+                        )?);
                     }
                     Err(e) => {
                         eprintln!("Failed to process file: {}", e);
                     }
                 }
+            } else {
+                reporter.file(FileReport::new(FileStatus::New, full_path.as_str(), None));
             }
         }
     }
 
-    debug_log!(debug, "Hash to file: {:?}", hash_to_file);
+    ui::progress_finish();
 
-    //if there are missing file then we should remove them from the database, but we need to check if they were renamed first
+    // Print entries in the database that were not found in the directory
     for db_file in db_files.values() {
-        debug_log!(debug, "Checking missing file: {} with hash: {}", db_file.path, db_file.hash);
-        if let Some(filenames) = hash_to_file.get(&db_file.hash) {
-            if filenames.len() == 1 {
-                //we have a single file with the same hash, so we can assume it was renamed
-                debug_log!(debug, "deleting database entry: {}", db_file.path);
-                db.delete_file(&db_file.path)?;
+        reporter.file(FileReport::new(FileStatus::Gone, &db_file.path, Some(&db_file.hash)));
+    }
+    print_unreadable_directories(&unreadable);
+    Ok(())
+}
+
+fn check_archive_file(
+    kind: ArchiveKind,
+    db: &database::Database,
+    reporter: &dyn Reporter,
+    full_archive_path: &Utf8Path,
+    rel_archive_path: &Utf8Path,
+    filter: &FileFilter,
+    rules: Option<&HeaderRules>,
+    db_files: &mut BTreeMap<String, models::ScannedFile>,
+) -> Result<()> {
+    let mut archive = kind.open(full_archive_path, filter.nesting())?;
+    archive.for_each_file(&mut |inner_path, size, mut file| {
+        if is_excluded_member(inner_path, size, filter) {
+            return Ok(());
+        }
+
+        debug!("Processing {} entry: {}", kind, inner_path);
+        let file_path = full_archive_path.join(inner_path);
+        let rel_file_path = rel_archive_path.join(inner_path);
+
+        if let Some(scanned_file) = db_files.remove(file_path.as_str()) {
+            ui::progress_tick(rel_file_path.as_str());
+            let header = recorded_header(&scanned_file, rules, inner_path);
+            match hash_contents(&mut file, inner_path, &[scanned_file.hash_type], header.as_ref(), false) {
+                Ok(file_hash) => {
+                    touch_if_unchanged(db, file_hash.primary(), &scanned_file)?;
+                    reporter.file(scanned_file_report(
+                        db,
+                        file_hash.primary(),
+                        Some(file_hash.size),
+                        &rel_file_path,
+                        &scanned_file,
+                    )?);
+                }
+                Err(e) => {
+                    eprintln!("Failed to process file: {}", e);
+                }
+            }
+        } else {
+            reporter.file(FileReport::new(FileStatus::New, file_path.as_str(), None));
+        }
+        Ok(())
+    })
+}
+
+// hash functions
+
+/// Print the hashes of the files in the directory, and of the files inside archives unless they are hashed whole.
+/// Nothing is looked up in or stored to the database, which does not have to exist
+pub fn hash_directory(args: &HashArgs, filter: &FileFilter, format: OutputFormat) -> Result<()> {
+    if format == OutputFormat::Human {
+        match args.style {
+            ChecksumStyle::Gnu if args.method.len() > 1 => {
+                return Err(anyhow!("--style gnu takes a single --method, use --style bsd for several"))
+            }
+            ChecksumStyle::Sfv if args.method != [HashType::Crc] => return Err(anyhow!("--style sfv only lists --method crc")),
+            _ => {}
+        }
+    }
+    let directory = resolve_directory(&args.directory)?;
+    let mut listing = HashListing {
+        format,
+        style: args.style,
+        out: OutputSink::stdout(),
+        table: Table::new(&["path", "method", "hash"]),
+    };
+    let mut failed = 0;
+
+    let mut dir_stack = DirStack::new(&directory, filter);
+    ui::progress_start("Hashing");
+    while let Some(current_path) = dir_stack.pop() {
+        let entries = match read_sorted_entries(&current_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                failed += 1;
+                continue;
+            }
+        };
+        for entry in entries {
+            let full_path = entry.path();
+            if full_path.is_dir() {
+                if args.recursive {
+                    dir_stack.push(full_path);
+                }
+                continue;
+            }
+            if should_skip_file(full_path, &directory, filter) {
+                continue;
+            }
+            let rel_path = full_path.strip_prefix(&directory).unwrap_or(full_path);
+            ui::progress_tick(rel_path.as_str());
+
+            let result = match filter.archive_kind(full_path).filter(|_| !args.whole_archives) {
+                Some(kind) => kind.open(full_path, filter.nesting()).and_then(|mut archive| {
+                    archive.for_each_file(&mut |inner_path, size, mut file| {
+                        if is_excluded_member(inner_path, size, filter) {
+                            return Ok(());
+                        }
+                        let member_path = rel_path.join(inner_path);
+                        match read_and_hash_multi(&mut file, &member_path, &args.method, None, false) {
+                            Ok(file_hash) => listing.add(&member_path, &file_hash.hashes)?,
+                            Err(e) => {
+                                eprintln!("Failed to hash {}: {:#}", member_path, e);
+                                failed += 1;
+                            }
+                        }
+                        Ok(())
+                    })
+                }),
+                //read in full, a CHD is hashed like any other file so the hash is the one checksum tools give
+                None => File::open(full_path)
+                    .context("Unable to open file")
+                    .and_then(|mut file| read_and_hash_multi(&mut file, full_path, &args.method, None, false))
+                    .and_then(|file_hash| listing.add(rel_path, &file_hash.hashes)),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to hash {}: {:#}", rel_path, e);
+                failed += 1;
+            }
+        }
+    }
+    ui::progress_finish();
+    listing.finish()?;
+    if failed > 0 {
+        return Err(anyhow!("Unable to hash {} file(s)", failed));
+    }
+    Ok(())
+}
+
+// the hashes printed by file hash, written as they come in checksum style or collected for a structured format
+struct HashListing {
+    format: OutputFormat,
+    style: ChecksumStyle,
+    out: OutputSink,
+    table: Table,
+}
+
+impl HashListing {
+    fn add(&mut self, path: &Utf8Path, hashes: &[(HashType, String)]) -> Result<()> {
+        if self.format != OutputFormat::Human {
+            for (hash_type, hash) in hashes {
+                self.table.push(vec![
+                    path.as_str().into(),
+                    hash_type.to_string().to_lowercase().into(),
+                    hash.as_str().into(),
+                ]);
+            }
+            return Ok(());
+        }
+        for (hash_type, hash) in hashes {
+            match self.style {
+                //like sha1sum, a name with a backslash or newline is escaped and the line marked with a backslash
+                ChecksumStyle::Gnu if path.as_str().contains(['\\', '\n']) => {
+                    writeln!(self.out, "\\{}  {}", hash, path.as_str().replace('\\', "\\\\").replace('\n', "\\n"))?
+                }
+                ChecksumStyle::Gnu => writeln!(self.out, "{}  {}", hash, path)?,
+                ChecksumStyle::Bsd => writeln!(self.out, "{} ({}) = {}", checksum_tag(*hash_type), path, hash)?,
+                ChecksumStyle::Sfv => writeln!(self.out, "{} {}", path, hash)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.format != OutputFormat::Human {
+            self.table.print(self.format)?;
+        }
+        self.out.finish()
+    }
+}
+
+// the name BSD style checksum lines give each hash
+fn checksum_tag(hash_type: HashType) -> &'static str {
+    match hash_type {
+        HashType::Crc => "CRC32",
+        HashType::Md5 => "MD5",
+        HashType::Sha1 => "SHA1",
+        HashType::Sha256 => "SHA256",
+    }
+}
+
+// bench functions
+
+/// Hash the files with each method in turn, the same number of times, and report the throughput of each. Only the
+/// time spent in the hasher counts towards it, so it is not skewed by the first method reading the files from disk.
+/// Archives and CHDs are hashed whole, like any other file
+pub fn bench_hashes(args: &BenchArgs, filter: &FileFilter, format: OutputFormat) -> Result<()> {
+    let files = bench_files(args, filter)?;
+    if files.is_empty() {
+        return Err(anyhow!("No files to hash in {}", args.path));
+    }
+
+    let started = Instant::now();
+    //bytes hashed and time spent in the hasher, over every pass, for each method
+    let mut results: Vec<(HashType, u64, Duration)> = Vec::new();
+    ui::progress_start("Benchmarking");
+    for method in &args.method {
+        let mut stats = HashStats::default();
+        for _ in 0..args.iterations {
+            for path in &files {
+                ui::progress_tick(path.as_str());
+                let file_hash = File::open(path)
+                    .context("Unable to open file")
+                    .and_then(|file| read_and_hash_multi(&mut BufReader::new(file), path, &[*method], None, false))
+                    .with_context(|| format!("Failed to hash {}", path))?;
+                stats.add(&file_hash);
+            }
+        }
+        let hashing = stats.hashing.first().map(|(_, hashing)| *hashing).unwrap_or_default();
+        results.push((*method, stats.bytes, hashing));
+    }
+    ui::progress_finish();
+    let elapsed = started.elapsed();
+    let iterations = f64::from(args.iterations);
+    let megabytes = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+
+    if format != OutputFormat::Human {
+        //every row is a single pass, so bytes over seconds is the throughput. The total is one pass of every method,
+        //timed by the clock, reading the files included
+        let mut table = Table::new(&["method", "files", "bytes", "seconds", "mib_per_second"]);
+        let pass_bytes = |bytes: u64| bytes / u64::from(args.iterations);
+        for (method, bytes, hashing) in &results {
+            table.push(vec![
+                method.to_string().to_lowercase().into(),
+                files.len().into(),
+                pass_bytes(*bytes).into(),
+                (hashing.as_secs_f64() / iterations).into(),
+                throughput(megabytes(*bytes), *hashing).into(),
+            ]);
+        }
+        let total: u64 = results.iter().map(|(_, bytes, _)| pass_bytes(*bytes)).sum();
+        let pass_elapsed = elapsed.div_f64(iterations);
+        table.push(vec![
+            "total".into(),
+            files.len().into(),
+            total.into(),
+            pass_elapsed.as_secs_f64().into(),
+            throughput(megabytes(total), pass_elapsed).into(),
+        ]);
+        return table.print(format);
+    }
+
+    let pass = results.first().map_or(0, |(_, bytes, _)| *bytes) as f64 / iterations;
+    println!(
+        "Hashed {} file(s), {:.1} MB, {} time(s) with each method in {:.2}s",
+        files.len(),
+        pass / (1 << 20) as f64,
+        args.iterations,
+        elapsed.as_secs_f64()
+    );
+    for (method, bytes, hashing) in &results {
+        println!(
+            "  {:<6} {:.3}s per pass ({:.1} MB/s)",
+            method.to_string().to_lowercase(),
+            hashing.as_secs_f64() / iterations,
+            throughput(megabytes(*bytes), *hashing)
+        );
+    }
+    Ok(())
+}
+
+// the path given if it is a file, or the files in the directory the filter leaves
+fn bench_files(args: &BenchArgs, filter: &FileFilter) -> Result<Vec<Utf8PathBuf>> {
+    if args.path.is_file() {
+        return Ok(vec![args.path.clone()]);
+    }
+    let directory = resolve_directory(&args.path)?;
+    let mut files = Vec::new();
+    let mut dir_stack = DirStack::new(&directory, filter);
+    while let Some(current_path) = dir_stack.pop() {
+        for entry in read_sorted_entries(&current_path)? {
+            let full_path = entry.path();
+            if full_path.is_dir() {
+                if args.recursive {
+                    dir_stack.push(full_path);
+                }
+            } else if !should_skip_file(full_path, &directory, filter) {
+                files.push(full_path.to_owned());
+            }
+        }
+    }
+    Ok(files)
+}
+
+// verify functions
+
+// whether the file is there, or for an archive member whether its archive is, without opening it
+fn is_on_disk(path: &Utf8Path) -> bool {
+    path.exists() || path.ancestors().skip(1).any(Utf8Path::is_file)
+}
+
+// walks the files recorded in the database rather than the directory, so files changed in place are found
+// an archive member counts as long as its archive does, whether it is still inside is left to verify
+fn clean_files(db: &database::Database, base_path: Option<&Utf8Path>, dry_run: bool) -> Result<()> {
+    let mut files = match base_path {
+        Some(base_path) => db.get_files_under_base_path(base_path.as_str())?,
+        None => db.get_all_files()?,
+    };
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let batch = db.begin_batch()?;
+    let mut removed = 0;
+    for scanned_file in files {
+        let path = Utf8Path::new(&scanned_file.path);
+        if is_on_disk(path) {
+            continue;
+        }
+        println!("[GONE] {}", path);
+        if !dry_run {
+            db.delete_file(path.as_str())?;
+        }
+        removed += 1;
+    }
+    batch.commit()?;
+
+    if dry_run {
+        println!("\nWould remove {} file(s)", removed);
+    } else {
+        println!("\nRemoved {} file(s)", removed);
+    }
+    Ok(())
+}
+
+// moves the database entries of files under one directory to the same place under another, for those found
+// there with the recorded size, or hash with `verify`. Entries for files not found there are left alone
+fn relocate_files(db: &database::Database, from: &Utf8Path, to: &Utf8Path, verify: bool, dry_run: bool) -> Result<Outcome> {
+    let mut files = db.get_files_under_base_path(from.as_str())?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    //all the entries move or none do
+    let batch = db.begin_batch()?;
+    let (mut relocated, mut missing, mut skipped) = (0, 0, 0);
+    for scanned_file in files {
+        let path = Utf8Path::new(&scanned_file.path);
+        let new_path = relocated_path(path, from, to);
+        let new_base_path = relocated_path(Utf8Path::new(&scanned_file.base_path), from, to);
+        if !is_relocated(&scanned_file, &new_path, verify)? {
+            println!("[GONE] {}", path);
+            missing += 1;
+            continue;
+        }
+        if !dry_run && !db.relocate_file(path.as_str(), new_path.as_str(), new_base_path.as_str())? {
+            println!("[SKIP] {} (Already recorded: {})", path, new_path);
+            skipped += 1;
+            continue;
+        }
+        println!("[MOVE] {} -> {}", path, new_path);
+        relocated += 1;
+    }
+    batch.commit()?;
+
+    let verb = if dry_run { "Would relocate" } else { "Relocated" };
+    println!(
+        "\n{} {} file(s), {} not found at the new location, {} already recorded there",
+        verb, relocated, missing, skipped
+    );
+    //files not where they were expected to be count as gone
+    Ok(if missing > 0 { Outcome::Mismatched } else { Outcome::Complete })
+}
+
+// walks the exact matches recorded in the database, so only files already scanned are packed
+fn rebuild_games(
+    db: &database::Database,
+    directory: &Utf8Path,
+    output: &Utf8Path,
+    recursive: bool,
+    remove_source: bool,
+    dry_run: bool,
+) -> Result<Outcome> {
+    let files = if recursive {
+        db.get_files_under_base_path(directory.as_str())?
+    } else {
+        db.get_files_by_base_path(directory.as_str())?
+    };
+    //archive members are already packed, so only files of their own are taken, the first found for each rom
+    let mut by_game: BTreeMap<String, BTreeMap<String, ScannedFile>> = BTreeMap::new();
+    for scanned_file in files {
+        if scanned_file.match_type != MatchType::Exact || !Utf8Path::new(&scanned_file.path).is_file() {
+            continue;
+        }
+        if let (Some(game_name), Some(rom_name)) = (scanned_file.game_name.clone(), scanned_file.rom_name.clone()) {
+            by_game.entry(game_name).or_default().entry(rom_name).or_insert(scanned_file);
+        }
+    }
+
+    let (mut rebuilt, mut incomplete, mut present, mut skipped) = (0, 0, 0, 0);
+    for (game_name, game_files) in by_game {
+        let Some(game) = db.search_by_game_name(&game_name, false)?.into_iter().next() else {
+            continue;
+        };
+        //disks stay CHD files of their own, so a game with nothing but disks has nothing to pack
+        if game.roms.is_empty() {
+            continue;
+        }
+        //a rom listed without a hash or size has no file, it is packed empty. Only the files packed are re-pointed
+        //and removed, not those matched to the game's disks
+        let mut members = Vec::new();
+        let mut packed = Vec::new();
+        let mut missing = 0;
+        for rom in &game.roms {
+            match game_files.get(&rom.name) {
+                Some(scanned_file) => {
+                    members.push((rom.name.as_str(), Some(Utf8Path::new(&scanned_file.path))));
+                    packed.push((rom.name.as_str(), scanned_file));
+                }
+                None if rom.is_empty() => members.push((rom.name.as_str(), None)),
+                None => missing += 1,
+            }
+        }
+        if missing > 0 {
+            println!("[SKIP] {} ({} of {} rom(s) have no loose file)", game.name, missing, game.roms.len());
+            incomplete += 1;
+            continue;
+        }
+
+        let zip_path = output.join(format!("{}.zip", path_component(&game.name)));
+        if zip_path.exists() {
+            //rebuilt before, which is not a problem with the files
+            println!("[SKIP] {} (Already there: {})", game.name, zip_path);
+            present += 1;
+            continue;
+        }
+        if let Some((_, changed)) = packed.iter().find(|(_, scanned_file)| !is_unchanged(scanned_file)) {
+            println!("[SKIP] {} (Changed since it was scanned: {})", game.name, changed.path);
+            skipped += 1;
+            continue;
+        }
+        println!("[ZIP ] {} -> {}", game.name, zip_path);
+        rebuilt += 1;
+        if dry_run {
+            continue;
+        }
+
+        archive::write_canonical_zip(&zip_path, &members).with_context(|| format!("Failed to write {}", zip_path))?;
+        for (rom_name, scanned_file) in &packed {
+            let mut member = (*scanned_file).clone();
+            member.path = zip_path.join(rom_name).into_string();
+            output.as_str().clone_into(&mut member.base_path);
+            db.store_file(&member)?;
+        }
+        if remove_source {
+            for (_, scanned_file) in &packed {
+                if ui::confirm(&format!("Remove {}?", scanned_file.path))? {
+                    std::fs::remove_file(&scanned_file.path).with_context(|| format!("Failed to remove {}", scanned_file.path))?;
+                    db.delete_file(&scanned_file.path)?;
+                }
             }
+        }
+    }
+
+    let verb = if dry_run { "Would rebuild" } else { "Rebuilt" };
+    println!(
+        "\n{} {} game(s), {} without all their files loose, {} already there, {} skipped",
+        verb, rebuilt, incomplete, present, skipped
+    );
+    Ok(if skipped > 0 { Outcome::Mismatched } else { Outcome::Complete })
+}
+
+// whether the file still has the hash it was scanned with
+fn is_unchanged(scanned_file: &ScannedFile) -> bool {
+    let path = Utf8Path::new(&scanned_file.path);
+    let header = recorded_header(scanned_file, None, path);
+    File::open(path)
+        .context("Unable to open file")
+        .and_then(|mut file| hash_contents(&mut file, path, &[scanned_file.hash_type], header.as_ref(), false))
+        .is_ok_and(|file_hash| file_hash.primary() == scanned_file.hash)
+}
 
-            println!("[MOVE] {} {}", db_file.hash, db_file.path);
-        } else {
-            println!("[GONE] {} {}", db_file.hash, db_file.path);
-        }
+// a name from the DAT as a single path component, with separators replaced and . or .. made harmless
+fn path_component(name: &str) -> String {
+    match name.replace(['/', '\\'], "_") {
+        name if name == "." || name == ".." => name.replace('.', "_"),
+        name => name,
     }
+}
 
-    print_found_games(&found_games);
+// the path under `to` that the path has under `from`, without the trailing separator joining an empty path adds
+fn relocated_path(path: &Utf8Path, from: &Utf8Path, to: &Utf8Path) -> Utf8PathBuf {
+    match path.strip_prefix(from).expect("should be able to strip prefix") {
+        rel if rel.as_str().is_empty() => to.to_owned(),
+        rel => to.join(rel),
+    }
+}
 
-    Ok(())
+// whether the scanned file is at its new path, an archive member is as long as its archive is
+fn is_relocated(scanned_file: &ScannedFile, new_path: &Utf8Path, verify: bool) -> Result<bool> {
+    if !new_path.is_file() {
+        return Ok(new_path.ancestors().skip(1).any(Utf8Path::is_file));
+    }
+    if verify {
+        let header = recorded_header(scanned_file, None, new_path);
+        let mut file = File::open(new_path).context("Unable to open file")?;
+        let file_hash = hash_contents(&mut file, new_path, &[scanned_file.hash_type], header.as_ref(), false)?;
+        return Ok(file_hash.primary() == scanned_file.hash);
+    }
+    //the recorded size excludes the header, and disks have none recorded
+    Ok(match scanned_file.size {
+        Some(size) => new_path.metadata()?.len() == size + scanned_file.header_offset.unwrap_or_default(),
+        None => true,
+    })
 }
 
-fn update_zip_contents(
+fn verify_files(
     db: &database::Database,
-    args: &ScanArgs,
-    debug: bool,
-    current_path: &Utf8Path,
-    zip_path: &Utf8Path,
-    rel_zip_path: &Utf8Path,
-    exclude_extensions: &[String],
-    db_files: &mut BTreeMap<String, models::ScannedFile>,
-    hash_to_file: &mut BTreeMap<String, HashSet<String>>,
-    found_games: &mut BTreeMap<String, GameStatus>,
+    reporter: &dyn Reporter,
+    filter: &FileFilter,
+    directory: &Utf8Path,
+    recursive: bool,
+    remove_gone: bool,
+    rules: Option<&HeaderRules>,
 ) -> Result<()> {
-    let file = File::open(zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
+    let mut files = if recursive {
+        db.get_files_under_base_path(directory.as_str())?
+    } else {
+        db.get_files_by_base_path(directory.as_str())?
+    };
+    files.sort_by(|a, b| a.path.cmp(&b.path));
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        if file.is_dir() {
-            continue;
-        }
+    reporter.info(&format!("Verifying directory: {}", directory));
+    ui::progress_start("Verifying");
+    //the times files are verified and the removal of those gone are committed together at the end
+    let batch = db.begin_batch()?;
 
-        if let Some(inner_path) = file.enclosed_name().and_then(|p| Utf8PathBuf::try_from(p).ok()) {
-            if let Some(extension) = inner_path.extension() {
-                if exclude_extensions.contains(&extension.to_owned()) {
-                    continue;
+    //archive members are verified together, in one pass over each archive
+    let mut archives: BTreeMap<Utf8PathBuf, BTreeMap<Utf8PathBuf, ScannedFile>> = BTreeMap::new();
+    let mut gone = Vec::new();
+    for scanned_file in files {
+        let path = Utf8PathBuf::from(&scanned_file.path);
+        if path.is_file() {
+            let rel_file_path = path.strip_prefix(directory).unwrap_or(&path);
+            ui::progress_tick(rel_file_path.as_str());
+            match File::open(&path).context("Unable to open file").and_then(|mut file| {
+                let header = recorded_header(&scanned_file, rules, &path);
+                hash_contents(&mut file, &path, &[scanned_file.hash_type], header.as_ref(), false)
+            }) {
+                Ok(file_hash) => {
+                    touch_if_unchanged(db, file_hash.primary(), &scanned_file)?;
+                    reporter.file(verified_report(file_hash.primary(), rel_file_path, &scanned_file));
                 }
+                Err(e) => eprintln!("Failed to process file: {}", e),
             }
+        } else if let Some(archive_path) = path.ancestors().skip(1).find(|ancestor| ancestor.is_file()) {
+            let inner_path = path
+                .strip_prefix(archive_path)
+                .expect("should be able to strip prefix")
+                .to_owned();
+            archives
+                .entry(archive_path.to_owned())
+                .or_default()
+                .insert(inner_path, scanned_file);
+        } else {
+            gone.push(scanned_file);
+        }
+    }
 
-            debug_log!(debug, "\nDebug: Processing zip entry: {}", inner_path);
-
-            let file_path = zip_path.join(&inner_path);
-            let rel_file_path = rel_zip_path.join(&inner_path);
-
-            if let Some(scanned_file) = db_files.remove(file_path.as_str()) {
-                //just treat the database as correct, and add it to the game status
-                update_found_file(db, &rel_file_path, &scanned_file, found_games);
-            } else {
-                //doesn't seem to be in the database, so check the hash and add it to the database
-                match scan_file_contents(db, args, debug, current_path, &file_path, &rel_file_path, &mut file, found_games, false) {
-                    Ok(hash) => {
-                        //store the file and the hash in a hash table so that we can find renamed files
-                        hash_to_file
-                            .entry(hash.clone())
-                            .or_default()
-                            .insert(file_path.as_str().to_owned());
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to process file: {}", e);
+    for (archive_path, mut members) in archives {
+        let Some(kind) = filter.archive_kind(&archive_path) else {
+            //replaced by a file that is not an archive
+            gone.extend(members.into_values());
+            continue;
+        };
+        let rel_archive_path = archive_path.strip_prefix(directory).unwrap_or(&archive_path);
+        let result = kind.open(&archive_path, filter.nesting()).and_then(|mut archive| {
+            archive.for_each_file(&mut |inner_path, _size, mut file| {
+                if let Some(scanned_file) = members.remove(inner_path) {
+                    let rel_file_path = rel_archive_path.join(inner_path);
+                    ui::progress_tick(rel_file_path.as_str());
+                    let header = recorded_header(&scanned_file, rules, inner_path);
+                    match hash_contents(&mut file, inner_path, &[scanned_file.hash_type], header.as_ref(), false) {
+                        Ok(file_hash) => {
+                            touch_if_unchanged(db, file_hash.primary(), &scanned_file)?;
+                            reporter.file(verified_report(file_hash.primary(), &rel_file_path, &scanned_file));
+                        }
+                        Err(e) => eprintln!("Failed to process file: {}", e),
                     }
                 }
-            }
+                Ok(())
+            })
+        });
+        match result {
+            //whatever was not in the archive is gone from it
+            Ok(()) => gone.extend(members.into_values()),
+            Err(e) => eprintln!("Failed to process {} file: {}", kind, e),
+        }
+    }
+
+    ui::progress_finish();
+
+    gone.sort_by(|a, b| a.path.cmp(&b.path));
+    for scanned_file in gone {
+        let path = Utf8Path::new(&scanned_file.path);
+        let rel_file_path = path.strip_prefix(directory).unwrap_or(path);
+        reporter.file(FileReport::new(FileStatus::Gone, rel_file_path.as_str(), Some(&scanned_file.hash)));
+        if remove_gone {
+            db.delete_file(&scanned_file.path)?;
         }
     }
+    batch.commit()?;
     Ok(())
 }
 
-fn update_found_file(
-    db: &database::Database,
-    rel_file_path: &Utf8Path,
-    scanned_file: &models::ScannedFile,
-    found_games: &mut BTreeMap<String, GameStatus>,
-) {
-    if let Some(game_name) = scanned_file.game_name.as_ref() {
-        let game_status = get_game_status(db, found_games, game_name);
-        let rom_name = scanned_file
-            .rom_name
-            .as_ref()
-            .expect("should have a rom name if there is a game name");
-        if scanned_file.match_type == MatchType::Exact {
-            game_status
-                .exact_matches
-                .entry(rom_name.to_owned())
-                .or_default()
-                .insert(rel_file_path.as_str().to_owned());
-        } else {
-            game_status
-                .partial_matches
-                .entry(rom_name.to_owned())
-                .or_default()
-                .insert(rel_file_path.as_str().to_owned());
-        }
+// a file hashed again with the hash it was scanned with counts as scanned now
+fn touch_if_unchanged(db: &database::Database, hash: &str, scanned_file: &ScannedFile) -> Result<()> {
+    if hash == scanned_file.hash {
+        db.touch_file(&scanned_file.path)?;
     }
+    Ok(())
 }
 
-// check functions
+// whether the file still has the hash it was scanned with, whatever it matched
+fn verified_report(hash: &str, rel_file_path: &Utf8Path, scanned_file: &ScannedFile) -> FileReport {
+    if hash == scanned_file.hash {
+        FileReport::new(FileStatus::Ok, rel_file_path.as_str(), Some(hash))
+    } else {
+        let mut report = FileReport::new(FileStatus::Hash, rel_file_path.as_str(), Some(hash));
+        report.expected_hash = Some(scanned_file.hash.clone());
+        report
+    }
+}
 
-fn check_directory(
+// list functions
+
+fn list_directory(
     db: &database::Database,
-    debug: bool,
-    exclude_extensions: &[String],
     directory: &Utf8Path,
+    format: OutputFormat,
+    options: ReportOptions,
+    output: Option<&Utf8Path>,
     recursive: bool,
+    show_dates: bool,
 ) -> Result<()> {
-    let mut dir_stack: Vec<Utf8PathBuf> = Vec::new();
-    dir_stack.push(directory.into());
+    let files = if recursive {
+        db.get_files_under_base_path(directory.as_str())?
+    } else {
+        db.get_files_by_base_path(directory.as_str())?
+    };
 
-    let mut db_files = BTreeMap::new();
+    if format != OutputFormat::Human {
+        let mut columns = vec!["path", "hash", "hash_type", "size", "match", "game", "rom"];
+        if show_dates {
+            columns.extend(["first_seen", "last_scanned"]);
+        }
+        let mut table = Table::new(&columns);
+        for scanned_file in files {
+            let file_path = Utf8PathBuf::from(&scanned_file.path);
+            let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+            let mut row = vec![
+                rel_file_path.as_str().into(),
+                scanned_file.hash.into(),
+                scanned_file.hash_type.to_string().to_lowercase().into(),
+                scanned_file.size.into(),
+                scanned_file.match_type.to_string().to_lowercase().into(),
+                scanned_file.game_name.into(),
+                scanned_file.rom_name.into(),
+            ];
+            if show_dates {
+                row.extend([scanned_file.first_seen.into(), scanned_file.last_scanned.into()]);
+            }
+            table.push(row);
+        }
+        let mut out = OutputSink::create(output, false)?;
+        table.write(format, &mut out)?;
+        return out.finish();
+    }
 
-    while let Some(current_path) = dir_stack.pop() {
-        println!("Checking directory: {}", current_path);
+    let reporter = report::create_for_output(format, options, output)?;
+    reporter.info(&format!("Listing directory: {}", directory));
+    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
 
-        // Get all entries in the database with the same base path
-        let files = db.get_files_by_base_path(current_path.as_str())?;
-        // Create a HashMap of the files in the database
-        for file in files {
-            db_files.insert(file.path.clone(), file);
+    for scanned_file in files {
+        let file_path = Utf8PathBuf::from(&scanned_file.path);
+        let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+        if let Err(e) = update_found_file(db, rel_file_path, &scanned_file, &mut found_games) {
+            eprintln!("Failed to process file: {}", e);
         }
-        // Read directory contents and sort by path
-        let mut entries: Vec<_> = current_path.read_dir_utf8()?.filter_map(Result::ok).collect();
-        entries.sort_by_key(|entry| entry.path().to_owned());
+        let mut report = scanned_file_report(db, &scanned_file.hash, scanned_file.size, rel_file_path, &scanned_file)?;
+        if show_dates {
+            report.dates = Some((scanned_file.first_seen.clone(), scanned_file.last_scanned.clone()));
+        }
+        reporter.file(report);
+    }
 
-        // for each file in the directory, check if its in the database or not
-        // and report it on the console
-        for entry in entries {
-            let full_path = entry.path();
+    reporter.games(&found_games);
+    reporter.finish()?;
+    Ok(())
+}
 
-            if full_path.is_dir() {
-                if recursive {
-                    debug_log!(debug, "\nDebug: Queuing directory: {}", full_path);
-                    dir_stack.push(full_path.into());
-                }
-                continue;
-            }
+fn list_game_roms(
+    db: &database::Database,
+    directory: &Utf8Path,
+    format: OutputFormat,
+    recursive: bool,
+    game_name: &str,
+) -> Result<()> {
+    if db.search_by_game_name(game_name, false)?.is_empty() {
+        return Err(anyhow!("Game not found in database: {}", game_name));
+    }
 
-            if should_skip_file(full_path, exclude_extensions) {
-                continue;
-            }
+    if format == OutputFormat::Human {
+        println!("Listing roms for game: {} in directory: {}", game_name, directory);
+    }
 
-            let rel_file_path = full_path.strip_prefix(directory).expect("should be able to strip prefix");
-            debug_log!(debug, "\nDebug: Processing file: {}", rel_file_path);
+    let files = if recursive {
+        db.get_files_under_base_path(directory.as_str())?
+    } else {
+        db.get_files_by_base_path(directory.as_str())?
+    };
 
-            if is_zip_file(full_path) {
-                if let Err(e) = check_zip_file(debug, full_path, rel_file_path, exclude_extensions, &mut db_files) {
-                    //continue to next file if we have an error
-                    eprintln!("Failed to process ZIP file: {}", e);
-                }
-                continue;
+    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+    for scanned_file in files {
+        if scanned_file.game_name.as_deref() == Some(game_name) {
+            let file_path = Utf8PathBuf::from(&scanned_file.path);
+            let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+            if let Err(e) = update_found_file(db, rel_file_path, &scanned_file, &mut found_games) {
+                eprintln!("Failed to process file: {}", e);
             }
+        }
+    }
 
-            if let Some(scanned_file) = db_files.remove(full_path.as_str()) {
-                match File::open(full_path)
-                    .context("Unable to open file")
-                    .and_then(|mut file| read_and_hash(&mut file, scanned_file.hash_type))
-                {
-                    Ok(hash) => {
-                        print_scanned_file(&hash, rel_file_path, &scanned_file);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to process file: {}", e);
-                    }
-                }
-            } else {
-                println!("[NEW ] {}", full_path);
-            }
+    let status = get_game_status(db, &mut found_games, game_name)?;
+    let mut roms: Vec<&Rom> = status.roms.iter().collect();
+    roms.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // (rom name, status, file)
+    let mut rows: Vec<(&str, MatchType, Option<&str>)> = Vec::new();
+    for rom in roms {
+        if let Some(filenames) = status.exact_matches.get(&rom.name) {
+            rows.extend(
+                filenames
+                    .iter()
+                    .map(|filename| (rom.name.as_str(), MatchType::Exact, Some(filename.as_str()))),
+            );
+        } else if let Some(filenames) = status.partial_matches.get(&rom.name) {
+            rows.extend(
+                filenames
+                    .iter()
+                    .map(|filename| (rom.name.as_str(), MatchType::Partial, Some(filename.as_str()))),
+            );
+        } else {
+            rows.push((rom.name.as_str(), MatchType::None, None));
         }
     }
 
-    // Print entries in the database that were not found in the directory
-    for db_file in db_files.values() {
-        println!("[GONE] {} {}", db_file.hash, db_file.path);
+    if format != OutputFormat::Human {
+        let mut table = Table::new(&["rom", "status", "file"]);
+        for (rom_name, match_type, filename) in rows {
+            let status = match match_type {
+                MatchType::Exact => "ok",
+                MatchType::Partial => "name",
+                MatchType::None => "miss",
+            };
+            table.push(vec![rom_name.into(), status.into(), filename.into()]);
+        }
+        return table.print(format);
+    }
+
+    for (rom_name, match_type, filename) in rows {
+        match (match_type, filename) {
+            (MatchType::Exact, Some(filename)) => println!("[OK  ] {} (File: {})", rom_name, filename),
+            (MatchType::Partial, Some(filename)) => println!("[NAME] {} (File: {})", rom_name, filename),
+            _ => println!("[MISS] {}", rom_name),
+        }
     }
 
     Ok(())
 }
 
-fn check_zip_file(
-    debug: bool,
-    full_zip_path: &Utf8Path,
-    rel_zip_path: &Utf8Path,
-    exclude_extensions: &[String],
-    db_files: &mut BTreeMap<String, models::ScannedFile>,
+fn list_non_canonical(
+    db: &database::Database,
+    directory: &Utf8Path,
+    format: OutputFormat,
+    recursive: bool,
+    script: bool,
 ) -> Result<()> {
-    let zip_file = File::open(full_zip_path)?;
-    let mut archive = ZipArchive::new(zip_file)?;
+    let files = if recursive {
+        db.get_files_under_base_path(directory.as_str())?
+    } else {
+        db.get_files_by_base_path(directory.as_str())?
+    };
+
+    let mut table = Table::new(&["path", "canonical_path"]);
+    if script {
+        println!("#!/bin/sh");
+    } else if format == OutputFormat::Human {
+        println!("Listing non-canonical names in directory: {}", directory);
+    }
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        if file.is_dir() {
+    for scanned_file in files {
+        if scanned_file.match_type != MatchType::Partial {
             continue;
         }
+        let Some(rom_name) = scanned_file.rom_name.as_ref() else {
+            continue;
+        };
+        let file_path = Utf8PathBuf::from(&scanned_file.path);
+        let canonical_path = file_path.with_file_name(rom_name);
+        //files inside archives have an archive file as their parent, and can't be renamed with mv
+        let in_archive = file_path.parent().is_some_and(Utf8Path::is_file);
 
-        if let Some(inner_path) = file.enclosed_name().and_then(|p| Utf8PathBuf::try_from(p).ok()) {
-            if let Some(extension) = inner_path.extension() {
-                if exclude_extensions.contains(&extension.to_owned()) {
-                    continue;
-                }
+        if script {
+            if in_archive {
+                println!("# skipped, inside an archive: {}", file_path);
+            } else {
+                println!("mv -n -- {} {}", shell_quote(file_path.as_str()), shell_quote(canonical_path.as_str()));
             }
-
-            debug_log!(debug, "\nDebug: Processing zip entry: {}", inner_path);
-            let file_path = full_zip_path.to_path_buf().join(&inner_path);
-            let rel_file_path = rel_zip_path.join(&inner_path);
-
-            if let Some(scanned_file) = db_files.remove(file_path.as_str()) {
-                match read_and_hash(&mut file, scanned_file.hash_type) {
-                    Ok(hash) => {
-                        print_scanned_file(&hash, &rel_file_path, &scanned_file);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to process file: {}", e);
-                    }
-                }
+        } else {
+            let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+            let rel_canonical_path = canonical_path
+                .strip_prefix(directory)
+                .expect("should be able to strip prefix");
+            if format == OutputFormat::Human {
+                println!("[RENAME-NEEDED] {} -> {}", rel_file_path, rel_canonical_path);
             } else {
-                println!("[NEW ] {}", file_path);
+                table.push(vec![rel_file_path.as_str().into(), rel_canonical_path.as_str().into()]);
             }
         }
     }
+
+    if format != OutputFormat::Human && !script {
+        table.print(format)?;
+    }
     Ok(())
 }
 
-// list functions
-
-fn list_directory(
-    db: &database::Database,
-    directory: &Utf8Path,
-    _debug: bool,
-    _exclude_extensions: &[String],
-    recursive: bool,
-) -> Result<()> {
-    println!("Listing directory: {}", directory);
-
+// scanned files grouped by hash, only comparing hashes of the same type
+fn list_duplicates(db: &database::Database, directory: &Utf8Path, format: OutputFormat, recursive: bool) -> Result<()> {
     let files = if recursive {
         db.get_files_under_base_path(directory.as_str())?
     } else {
         db.get_files_by_base_path(directory.as_str())?
     };
 
-    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
-
+    let mut by_hash: BTreeMap<(String, String), Vec<ScannedFile>> = BTreeMap::new();
     for scanned_file in files {
-        let file_path = Utf8PathBuf::from(&scanned_file.path);
-        let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
-        update_found_file(db, rel_file_path, &scanned_file, &mut found_games);
-        print_scanned_file(&scanned_file.hash, rel_file_path, &scanned_file);
+        by_hash
+            .entry((scanned_file.hash_type.to_string(), scanned_file.hash.clone()))
+            .or_default()
+            .push(scanned_file);
     }
 
-    print_found_games(&found_games);
+    //files scanned before sizes were recorded count as wasting nothing, as the space is not known
+    let mut groups: Vec<(u64, Vec<ScannedFile>)> = by_hash
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .map(|files| {
+            let size = files.iter().find_map(|file| file.size).unwrap_or(0);
+            (size * (files.len() as u64 - 1), files)
+        })
+        .collect();
+    groups.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    if format != OutputFormat::Human {
+        let mut table = Table::new(&["hash", "hash_type", "size", "copies", "wasted", "path"]);
+        for (wasted, files) in &groups {
+            for scanned_file in files {
+                let file_path = Utf8Path::new(&scanned_file.path);
+                let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+                table.push(vec![
+                    scanned_file.hash.clone().into(),
+                    scanned_file.hash_type.to_string().to_lowercase().into(),
+                    scanned_file.size.into(),
+                    files.len().into(),
+                    (*wasted).into(),
+                    rel_file_path.as_str().into(),
+                ]);
+            }
+        }
+        return table.print(format);
+    }
+
+    println!("Duplicates in directory: {}", directory);
+    let mut total_wasted = 0;
+    for (wasted, files) in &groups {
+        println!("[DUPE] {} ({} copies, {} bytes wasted)", files[0].hash, files.len(), wasted);
+        for scanned_file in files {
+            let file_path = Utf8Path::new(&scanned_file.path);
+            let rel_file_path = file_path.strip_prefix(directory).expect("should be able to strip prefix");
+            println!("         {}", rel_file_path);
+        }
+        total_wasted += wasted;
+    }
+    println!("\nFound {} sets of duplicates, {} bytes wasted", groups.len(), total_wasted);
+    Ok(())
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// watch functions
+
+// how long the directory must be quiet before changed files are scanned, so files still being written are not hashed
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(1);
+
+fn watch_directory(db: &database::Database, scan: &ScanContext, filter: &FileFilter) -> Result<()> {
+    debug!("Using hash types: {:?}", scan.method);
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let mode = if scan.args.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher.watch(scan.directory.as_std_path(), mode)?;
+
+    scan.reporter.info(&format!("Watching directory: {}", scan.directory));
+
+    let mut found_games: BTreeMap<String, GameStatus> = BTreeMap::new();
+
+    // block until something happens, then keep collecting until things settle down
+    while let Ok(event) = receiver.recv() {
+        let mut changed: BTreeSet<Utf8PathBuf> = BTreeSet::new();
+        collect_changed_paths(event, &mut changed);
+        while let Ok(event) = receiver.recv_timeout(WATCH_DEBOUNCE) {
+            collect_changed_paths(event, &mut changed);
+        }
+
+        for full_path in &changed {
+            if !full_path.is_dir() {
+                //removed, renamed away or rewritten, so the entry is stale, and for an archive those of its members
+                debug!("deleting database entries: {}", full_path);
+                db.delete_files_under(full_path.as_str())?;
+            }
+            if !full_path.exists() {
+                continue;
+            }
+
+            if should_skip_file(full_path, &scan.directory, filter) {
+                continue;
+            }
+
+            let Some(current_path) = full_path.parent() else {
+                continue;
+            };
+            let rel_path = full_path
+                .strip_prefix(&scan.directory)
+                .expect("should be able to strip prefix");
+            //the whole tree is watched, but files deeper than --max-depth are left alone as a scan would
+            if filter
+                .max_depth
+                .is_some_and(|max_depth| rel_path.components().count() > max_depth + 1)
+            {
+                continue;
+            }
+
+            if let Some(kind) = filter.archive_kind(full_path) {
+                if let Err(e) = scan_archive_contents(
+                    kind,
+                    db,
+                    scan,
+                    current_path,
+                    full_path,
+                    rel_path,
+                    filter,
+                    &BTreeMap::new(),
+                    &mut found_games,
+                ) {
+                    eprintln!("Failed to process {} file: {}", kind, e);
+                }
+                continue;
+            }
+
+            if let Err(e) = File::open(full_path).context("Unable to open file").and_then(|mut file| {
+                scan_file_contents(db, scan, current_path, full_path, rel_path, &mut file, None, &mut found_games, true)
+            }) {
+                eprintln!("Failed to process file: {}", e);
+            }
+        }
+    }
 
     Ok(())
 }
 
+fn collect_changed_paths(event: notify::Result<notify::Event>, changed: &mut BTreeSet<Utf8PathBuf>) {
+    match event {
+        Ok(event) if !event.kind.is_access() => {
+            for path in event.paths {
+                match Utf8PathBuf::try_from(path) {
+                    Ok(path) => {
+                        debug!("Change detected: {}", path);
+                        changed.insert(path);
+                    }
+                    Err(e) => eprintln!("Ignoring non UTF-8 path: {}", e),
+                }
+            }
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to watch directory: {}", e),
+    }
+}
+
 // common code
 
-fn should_skip_file(path: &Utf8Path, exclude_extensions: &[String]) -> bool {
-    // Skip directories and non-files
-    if !path.is_file() {
+fn should_skip_file(path: &Utf8Path, root: &Utf8Path, filter: &FileFilter) -> bool {
+    // Skip directories and non-files
+    if !path.is_file() {
+        return true;
+    }
+
+    if filter.excludes_path(path.strip_prefix(root).unwrap_or(path)) {
         return true;
     }
 
+    //archives are opened whatever the include list and size range say, their members are filtered instead.
+    //only looked for when it matters, as it reads the start of the file
+    let is_archive = || filter.archive_kind(path).is_some();
     if let Some(extension) = path.extension() {
-        if exclude_extensions.contains(&extension.to_owned()) {
+        if !(filter.includes(extension) || is_archive()) || filter.excludes(extension) {
             return true;
         }
     } else {
@@ -675,102 +2850,443 @@ fn should_skip_file(path: &Utf8Path, exclude_extensions: &[String]) -> bool {
         return true;
     }
 
+    if filter.has_size_range() && !is_archive() {
+        //only stat the file when there is a range to check it against
+        return !path.metadata().is_ok_and(|metadata| filter.includes_size(metadata.len()));
+    }
+
     false
 }
 
-fn is_zip_file(path: &Utf8Path) -> bool {
-    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+// archive members are only filtered on their extension and uncompressed size, they have no hidden or extensionless
+// checks like files
+fn is_excluded_member(inner_path: &Utf8Path, size: u64, filter: &FileFilter) -> bool {
+    let excluded = match inner_path.extension() {
+        Some(extension) => !filter.includes(extension) || filter.excludes(extension),
+        None => !filter.include_extensions.is_empty(),
+    };
+    excluded || !filter.includes_size(size)
 }
 
-fn read_and_hash(file: &mut impl Read, method: HashType) -> Result<String> {
-    match method {
-        HashType::Crc => {
-            //CRC does not implement Write, so we need to do it manually
-            let mut hasher = Hasher::new();
-            let mut reader = BufReader::new(file);
-            let mut buffer = [0u8; 8192];
-            //loop and read date into buffer and update the hasher
-            loop {
-                let read = reader.read(&mut buffer)?;
-                if read == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..read]);
+// the header skipped when the file was scanned, so it is hashed the same way again, or for files scanned
+// before header offsets were recorded, whatever the given rules say
+fn recorded_header(scanned_file: &ScannedFile, rules: Option<&HeaderRules>, path: &Utf8Path) -> Option<HeaderRule> {
+    match scanned_file.header_offset {
+        Some(0) => None,
+        Some(offset) => usize::try_from(offset).ok().map(HeaderRule::fixed),
+        None => rules.and_then(|rules| rules.for_path(path)).cloned(),
+    }
+}
+
+struct FileHash {
+    // one hash for each method asked for, in the same order
+    hashes: Vec<(HashType, String)>,
+    // number of bytes hashed, excluding any stripped header
+    size: u64,
+    // the hash is the SHA1 from a CHD header rather than a hash of the contents
+    disk: bool,
+    // number of header bytes skipped before hashing
+    header_offset: u64,
+    hash_time: HashTimes,
+    // the hashes including the stripped header, only when they were asked for and a header was stripped
+    headered: Vec<(HashType, String)>,
+}
+
+// the time spent in each hash method
+type HashTimes = Vec<(HashType, Duration)>;
+
+const CHD_MAGIC: &[u8] = b"MComprHD";
+// long enough for the largest supported CHD header (v5)
+const CHD_HEADER_LENGTH: u64 = 124;
+
+impl FileHash {
+    // the hash of a member from the CRC32 and size its archive records, without reading it
+    fn recorded_crc(crc: u32, size: u64) -> Self {
+        FileHash {
+            hashes: vec![(HashType::Crc, format!("{:08x}", crc))],
+            size,
+            disk: false,
+            header_offset: 0,
+            hash_time: Vec::new(),
+            headered: Vec::new(),
+        }
+    }
+
+    // the same file as it would be hashed with its header kept, if the headered hashes were taken
+    fn with_header(&self) -> Option<FileHash> {
+        (!self.headered.is_empty()).then(|| FileHash {
+            hashes: self.headered.clone(),
+            size: self.size + self.header_offset,
+            disk: false,
+            header_offset: 0,
+            hash_time: Vec::new(),
+            headered: Vec::new(),
+        })
+    }
+
+    // the hash that is stored and displayed, from the first method asked for
+    fn primary(&self) -> &str {
+        self.hashes.first().map(|(_, hash)| hash.as_str()).unwrap_or_default()
+    }
+}
+
+fn is_chd_file(path: &Utf8Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("chd"))
+}
+
+// the SHA1 of the data and metadata that DATs list for a disk, at a version dependent offset
+fn chd_sha1(header: &[u8]) -> Option<String> {
+    if !header.starts_with(CHD_MAGIC) {
+        return None;
+    }
+    let version = u32::from_be_bytes(header.get(12..16)?.try_into().ok()?);
+    let offset = match version {
+        3 => 80,
+        4 => 48,
+        5 => 84,
+        _ => return None,
+    };
+    let sha1 = header.get(offset..offset + 20)?;
+    Some(sha1.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// hash the contents of a file, except for CHD files where the SHA1 is taken from the header
+// so that multi-gigabyte disk images are not read in full. With keep_headered, a file that has its header
+// stripped is also hashed with the header in the same pass
+fn hash_contents(
+    file: &mut impl Read,
+    path: &Utf8Path,
+    methods: &[HashType],
+    header: Option<&HeaderRule>,
+    keep_headered: bool,
+) -> Result<FileHash> {
+    if !is_chd_file(path) {
+        return read_and_hash_multi(file, path, methods, header, keep_headered);
+    }
+    let mut prefix = Vec::new();
+    file.by_ref().take(CHD_HEADER_LENGTH).read_to_end(&mut prefix)?;
+    match chd_sha1(&prefix) {
+        Some(hash) => Ok(FileHash {
+            hashes: vec![(HashType::Sha1, hash)],
+            size: 0,
+            disk: true,
+            header_offset: 0,
+            hash_time: Vec::new(),
+            headered: Vec::new(),
+        }),
+        //not a CHD we understand, so treat it like any other file
+        None => read_and_hash_multi(&mut prefix.as_slice().chain(file), path, methods, header, keep_headered),
+    }
+}
+
+// counts the bytes passing through, so the hashed size is known without a separate stat
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+fn read_and_hash_multi(
+    file: &mut impl Read,
+    path: &Utf8Path,
+    methods: &[HashType],
+    header: Option<&HeaderRule>,
+    keep_headered: bool,
+) -> Result<FileHash> {
+    if let Some(rule) = header {
+        let mut prefix = Vec::with_capacity(rule.length);
+        file.by_ref().take(rule.length as u64).read_to_end(&mut prefix)?;
+        if prefix.len() < rule.length {
+            warn!("{} is shorter than its {} byte header, hashing the whole file", path, rule.length);
+        }
+        //only strip the header if the file is long enough and the magic bytes (if any) match
+        let strip = prefix.len() == rule.length && rule.magic.as_ref().is_none_or(|magic| prefix.starts_with(magic));
+        let header_offset = if strip { prefix.len() as u64 } else { 0 };
+        let stripped = if strip { std::mem::take(&mut prefix) } else { Vec::new() };
+        let mut reader = CountingReader {
+            inner: prefix.as_slice().chain(file),
+            count: 0,
+        };
+        let (hashes, hash_time, headered) = if strip && keep_headered {
+            //a second set of hashers starts with the header, so both are taken from one read
+            let mut headered = MultiHasher::new(methods);
+            headered.update(&stripped);
+            let [hasher, headered] = feed_hashers(&mut reader, [MultiHasher::new(methods), headered])?;
+            let (hashes, mut hash_time) = hasher.finalize(methods);
+            let (headered, headered_time) = headered.finalize(methods);
+            for ((_, time), (_, extra)) in hash_time.iter_mut().zip(headered_time) {
+                *time += extra;
+            }
+            (hashes, hash_time, headered)
+        } else {
+            let (hashes, hash_time) = hash_reader(&mut reader, methods)?;
+            (hashes, hash_time, Vec::new())
+        };
+        Ok(FileHash {
+            hashes,
+            size: reader.count,
+            disk: false,
+            header_offset,
+            hash_time,
+            headered,
+        })
+    } else {
+        let mut reader = CountingReader { inner: file, count: 0 };
+        let (hashes, hash_time) = hash_reader(&mut reader, methods)?;
+        Ok(FileHash {
+            hashes,
+            size: reader.count,
+            disk: false,
+            header_offset: 0,
+            hash_time,
+            headered: Vec::new(),
+        })
+    }
+}
+
+// feeds the same bytes to every requested hasher, so a file is read once however many hashes are wanted
+#[derive(Default)]
+struct MultiHasher {
+    crc: Option<Hasher>,
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+    // time spent in each of the hashers above, in the same order
+    elapsed: [Duration; 4],
+}
+
+impl MultiHasher {
+    fn new(methods: &[HashType]) -> Self {
+        let mut hasher = MultiHasher::default();
+        for method in methods {
+            match method {
+                HashType::Crc => hasher.crc = Some(Hasher::new()),
+                HashType::Md5 => hasher.md5 = Some(Md5::new()),
+                HashType::Sha1 => hasher.sha1 = Some(Sha1::new()),
+                HashType::Sha256 => hasher.sha256 = Some(Sha256::new()),
             }
+        }
+        hasher
+    }
 
-            let checksum = hasher.finalize();
-            Ok(format!("{:08x}", checksum))
+    fn update(&mut self, data: &[u8]) {
+        if let Some(crc) = self.crc.as_mut() {
+            let started = Instant::now();
+            crc.update(data);
+            self.elapsed[0] += started.elapsed();
+        }
+        if let Some(md5) = self.md5.as_mut() {
+            let started = Instant::now();
+            md5.update(data);
+            self.elapsed[1] += started.elapsed();
         }
-        HashType::Md5 => {
-            let mut hasher = Md5::new();
-            std::io::copy(file, &mut hasher)?;
-            let result = hasher.finalize();
-            Ok(format!("{:x}", result))
+        if let Some(sha1) = self.sha1.as_mut() {
+            let started = Instant::now();
+            sha1.update(data);
+            self.elapsed[2] += started.elapsed();
         }
-        HashType::Sha1 => {
-            let mut hasher = Sha1::new();
-            std::io::copy(file, &mut hasher)?;
-            let result = hasher.finalize();
-            Ok(format!("{:x}", result))
+        if let Some(sha256) = self.sha256.as_mut() {
+            let started = Instant::now();
+            sha256.update(data);
+            self.elapsed[3] += started.elapsed();
+        }
+    }
+
+    // the hashes in the order the methods were asked for, and the time spent on each
+    fn finalize(self, methods: &[HashType]) -> (Vec<(HashType, String)>, HashTimes) {
+        let mut crc = self.crc.map(|hasher| format!("{:08x}", hasher.finalize()));
+        let mut md5 = self.md5.map(|hasher| format!("{:x}", hasher.finalize()));
+        let mut sha1 = self.sha1.map(|hasher| format!("{:x}", hasher.finalize()));
+        let mut sha256 = self.sha256.map(|hasher| format!("{:x}", hasher.finalize()));
+        methods
+            .iter()
+            .filter_map(|method| {
+                //take so a method listed twice is only reported once
+                let (hash, elapsed) = match method {
+                    HashType::Crc => (crc.take(), self.elapsed[0]),
+                    HashType::Md5 => (md5.take(), self.elapsed[1]),
+                    HashType::Sha1 => (sha1.take(), self.elapsed[2]),
+                    HashType::Sha256 => (sha256.take(), self.elapsed[3]),
+                };
+                hash.map(|hash| ((*method, hash), (*method, elapsed)))
+            })
+            .unzip()
+    }
+}
+
+// files are hashed a chunk at a time, so memory use is the same for a small rom or a multi-gigabyte disc image
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+fn hash_reader(file: &mut impl Read, methods: &[HashType]) -> Result<(Vec<(HashType, String)>, HashTimes)> {
+    let [hasher] = feed_hashers(file, [MultiHasher::new(methods)])?;
+    Ok(hasher.finalize(methods))
+}
+
+// read the file to the end, giving every chunk to each of the hashers
+fn feed_hashers<const N: usize>(file: &mut impl Read, mut hashers: [MultiHasher; N]) -> Result<[MultiHasher; N]> {
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+    //loop and read data into buffer and update the hashers
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[..read]);
         }
     }
+    Ok(hashers)
+}
+
+struct MatchedRom {
+    game_name: String,
+    rom_name: String,
+    matched_by: Vec<HashType>,
 }
 
 struct Matches {
-    exact: Vec<(String, String)>,
-    partial: Vec<(String, String)>,
+    exact: Vec<MatchedRom>,
+    partial: Vec<MatchedRom>,
+    // hash matches rejected by --strict-size, with the size the rom should have been
+    size_mismatches: Vec<(MatchedRom, i64)>,
+    // size of the file after any header was stripped
+    size: u64,
+    // number of hashes searched, which hash matched is only worth reporting when there was a choice
+    hash_count: usize,
+    // matched by the hashes taken with the header kept, see --try-headerless
+    with_header: bool,
+}
+
+impl MatchedRom {
+    fn report(&self) -> RomReport {
+        RomReport::new(&self.game_name, &self.rom_name, &self.matched_by)
+    }
+}
+
+impl Matches {
+    // a report of the file matching the given roms
+    fn report<'a>(
+        &self,
+        status: FileStatus,
+        scanned_file: &ScannedFile,
+        rel_file_path: &Utf8Path,
+        matched: impl IntoIterator<Item = &'a MatchedRom>,
+    ) -> FileReport {
+        let mut report = FileReport::new(status, rel_file_path.as_str(), Some(&scanned_file.hash));
+        report.size = Some(self.size);
+        report.matches = matched.into_iter().map(MatchedRom::report).collect();
+        report.show_provenance = self.hash_count > 1;
+        report.with_header = self.with_header;
+        report
+    }
+
+    // a report of the file matching several roms by name, with the roms of games that only differ in their region or
+    // revision tags next to each other, in the order the first of each was matched
+    fn report_candidates(&self, scanned_file: &ScannedFile, rel_file_path: &Utf8Path) -> FileReport {
+        let mut report = self.report(FileStatus::Name, scanned_file, rel_file_path, &self.partial);
+        let mut titles: Vec<String> = Vec::new();
+        for matched in &mut report.matches {
+            let title = base_title(&matched.game);
+            if !titles.contains(&title) {
+                titles.push(title.clone());
+            }
+            matched.base_title = Some(title);
+        }
+        report
+            .matches
+            .sort_by_key(|matched| titles.iter().position(|title| matched.base_title.as_ref() == Some(title)));
+        report
+    }
+}
+
+// the game name without the tags in parentheses or brackets that No-Intro and similar DATs use for region, revision
+// and other variants, e.g. "Game (USA) (Rev 1)" and "Game (Europe)" are both "Game". A name that is only tags is kept
+fn base_title(game_name: &str) -> String {
+    let mut title = String::with_capacity(game_name.len());
+    let mut depth = 0usize;
+    for c in game_name.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => title.push(c),
+            _ => {}
+        }
+    }
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        game_name.to_owned()
+    } else {
+        title
+    }
 }
 
 fn check_rom_matches(
     db: &database::Database,
-    args: &ScanArgs,
-    debug: bool,
+    scan: &ScanContext,
     rel_file_path: &Utf8Path,
     filename: &str,
-    results: &Vec<(models::Game, Vec<models::Rom>)>,
+    size: u64,
+    hash_count: usize,
+    results: &Vec<(models::Game, Vec<RomMatch>)>,
     found_games: &mut BTreeMap<String, GameStatus>,
 ) -> Result<Matches> {
     let mut exact_matches = Vec::new();
     let mut partial_matches = Vec::new();
+    let mut size_mismatches = Vec::new();
 
     for (game, roms) in results {
-        let game_status = get_game_status(db, found_games, &game.name);
-        for rom in roms {
-            if debug {
-                debug_log!(debug, "Comparing with database entry:");
-                debug_log!(debug, "  Game: {}", game.name);
-                debug_log!(debug, "  ROM: {}", rom.name);
-                debug_log!(debug, "  Size: {}", rom.size);
-                match args.method {
-                    HashType::Crc => {
-                        if let Some(h) = &rom.crc {
-                            debug_log!(debug, "  CRC: {}", h);
-                        }
-                    }
-                    HashType::Md5 => {
-                        if let Some(h) = &rom.md5 {
-                            debug_log!(debug, "  MD5: {}", h);
-                        }
-                    }
-                    HashType::Sha1 => {
-                        if let Some(h) = &rom.sha1 {
-                            debug_log!(debug, "  SHA1: {}", h);
-                        }
+        let game_status = get_game_status(db, found_games, &game.name)?;
+        for RomMatch { rom, matched_by } in roms {
+            if log_enabled!(Level::Trace) {
+                trace!("Comparing with database entry:");
+                trace!("  Game: {}", game.name);
+                trace!("  ROM: {}", rom.name);
+                trace!("  Size: {}", rom.size);
+                for hash_type in matched_by {
+                    if let Some(h) = rom.hash(*hash_type) {
+                        trace!("  {}: {}", hash_type, h);
                     }
                 }
             }
 
-            if rom.name == filename {
-                debug_log!(debug, "Found exact match for file: {}", rel_file_path);
+            let matched = MatchedRom {
+                game_name: game.name.clone(),
+                rom_name: rom.name.clone(),
+                matched_by: matched_by.clone(),
+            };
+
+            //matched by name the size is all there is to go on, so it has to agree
+            if (scan.args.strict_size || scan.name_only) && !u64::try_from(rom.size).is_ok_and(|rom_size| rom_size == size) {
+                debug!("Rejected match for file: {} due to size {}", rel_file_path, size);
+                size_mismatches.push((matched, rom.size));
+                continue;
+            }
+
+            let is_exact = match scan.args.match_by {
+                MatchBy::Name => scan.args.is_rom_name(filename, &rom.name),
+                MatchBy::Hash => u64::try_from(rom.size).is_ok_and(|rom_size| rom_size == size),
+            };
+
+            if is_exact {
+                debug!("Found exact match for file: {}", rel_file_path);
                 game_status
                     .exact_matches
                     .entry(rom.name.clone())
                     .or_default()
                     .insert(rel_file_path.as_str().to_owned());
-                exact_matches.push((game.name.clone(), rom.name.clone()));
+                exact_matches.push(matched);
             } else {
-                debug_log!(debug, "Found partial match for file: {}", rel_file_path);
-                partial_matches.push((game.name.clone(), rom.name.clone()));
+                debug!("Found partial match for file: {}", rel_file_path);
+                partial_matches.push(matched);
                 game_status
                     .partial_matches
                     .entry(rom.name.clone())
@@ -782,115 +3298,457 @@ fn check_rom_matches(
     Ok(Matches {
         exact: exact_matches,
         partial: partial_matches,
+        size_mismatches,
+        size,
+        hash_count,
+        with_header: false,
     })
 }
 
+// disks matching a CHD header SHA1, presented as roms so they go through the same matching
+fn search_disks(db: &database::Database, sha1: &str) -> Result<Vec<(models::Game, Vec<RomMatch>)>> {
+    let results = db.search_disks_by_sha1(sha1)?;
+    Ok(results
+        .into_iter()
+        .map(|(game, disks)| {
+            let matches = disks
+                .iter()
+                .map(|disk| RomMatch {
+                    rom: disk.as_rom(),
+                    matched_by: vec![HashType::Sha1],
+                })
+                .collect();
+            (game, matches)
+        })
+        .collect())
+}
+
 fn get_game_status<'a>(
     db: &database::Database,
     game_status: &'a mut BTreeMap<String, GameStatus>,
     game_name: &str,
-) -> &'a mut GameStatus {
-    game_status.entry(game_name.to_owned()).or_insert_with(|| {
-        let games = db
-            .search_by_game_name(game_name, false)
-            .expect("Game could not be found in database");
-        let game = games.first().expect("Game could not be found in database");
-        GameStatus {
-            roms: game.roms.clone(),
-            exact_matches: HashMap::new(),
-            partial_matches: HashMap::new(),
+) -> Result<&'a mut GameStatus> {
+    match game_status.entry(game_name.to_owned()) {
+        btree_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+        btree_map::Entry::Vacant(entry) => {
+            let games = db
+                .search_by_game_name(game_name, false)
+                .with_context(|| format!("Failed to look up game {}", game_name))?;
+            //the game can be gone if it was removed from the database after the file was scanned
+            let game = games
+                .first()
+                .ok_or_else(|| anyhow!("Game {} could not be found in database", game_name))?;
+            Ok(entry.insert(GameStatus {
+                roms: game.roms.iter().cloned().chain(game.disks.iter().map(Disk::as_rom)).collect(),
+                exact_matches: BTreeMap::new(),
+                partial_matches: BTreeMap::new(),
+            }))
         }
-    })
+    }
 }
 
 fn handle_rom_matches(
     db: &database::Database,
-    args: &ScanArgs,
-    debug: bool,
+    scan: &ScanContext,
     full_file_path: &Utf8Path,
     rel_file_path: &Utf8Path,
     scanned_file: &mut models::ScannedFile,
     matches: &Matches,
     can_rename: bool,
 ) -> Result<()> {
-    debug_log!(debug, "Checking matches for file: {}", rel_file_path);
+    debug!("Checking matches for file: {}", rel_file_path);
+
+    if matches.exact.is_empty() && matches.partial.is_empty() {
+        //every hash match had the wrong size, so the file is treated as unmatched
+        let mut report = matches.report(FileStatus::Size, scanned_file, rel_file_path, []);
+        report.matches = matches
+            .size_mismatches
+            .iter()
+            .map(|(matched, rom_size)| RomReport {
+                expected_size: Some(*rom_size),
+                ..matched.report()
+            })
+            .collect();
+        scan.report_file(DisplayMethod::Miss, report);
+        return store_file(db, scan, scanned_file);
+    }
 
     if !matches.exact.is_empty() {
-        for (game_name, rom_name) in &matches.exact {
-            update_scanned(scanned_file, MatchType::Exact, game_name, rom_name);
-            print_exact_match(&args.file_display, scanned_file, rel_file_path);
-            db.store_file(scanned_file)?;
-            //if this is set, don't bother with other exact matches, not very dependable
-            if args.first_match {
-                return Ok(());
+        //when matching by hash the name can still be wrong, so fix it if all exact matches agree on the rom name,
+        //and on the game too when the rename template puts files in a directory for their game
+        if scan.args.match_by == MatchBy::Hash && can_rename && scan.args.fix {
+            if let Some(first) = matches.exact.first() {
+                let rom_name = &first.rom_name;
+                let by_game = scan.args.rename_template.contains("{game}");
+                let agreed = matches
+                    .exact
+                    .iter()
+                    .all(|other| &other.rom_name == rom_name && (!by_game || other.game_name == first.game_name));
+                let named = full_file_path
+                    .file_name()
+                    .is_some_and(|name| scan.args.is_rom_name(name, rom_name));
+                if agreed && !named {
+                    match rename_to_rom(scan, full_file_path, rel_file_path, scanned_file, &first.game_name, rom_name, false)? {
+                        Rename::Renamed(renamed) => {
+                            if !verify_fix(db, scan, rel_file_path, scanned_file, &renamed, &first.game_name, rom_name)? {
+                                //not confirmed to be the rom under its name, so it is not stored as exact
+                                update_scanned(scanned_file, MatchType::Partial, &first.game_name, rom_name);
+                                store_file(db, scan, scanned_file)?;
+                                scan.report_file(
+                                    DisplayMethod::Partial,
+                                    matches.report(FileStatus::Name, scanned_file, rel_file_path, [first]),
+                                );
+                                return Ok(());
+                            }
+                        }
+                        Rename::DryRun => return Ok(()),
+                        Rename::Skipped => {}
+                    }
+                }
             }
         }
+
+        //a file is stored with a single match, the first in game order, whichever other roms it also matches
+        let first = matches.exact.first().expect("should have an exact match");
+        update_scanned(scanned_file, MatchType::Exact, &first.game_name, &first.rom_name);
+        store_file(db, scan, scanned_file)?;
+        //if this is set, don't bother reporting other exact matches, not very dependable
+        let reported = if scan.args.first_match {
+            &matches.exact[..1]
+        } else {
+            &matches.exact[..]
+        };
+        scan.report_file(DisplayMethod::Exact, matches.report(FileStatus::Ok, scanned_file, rel_file_path, reported));
         //if this is set, don't bother with partial matches
-        if args.ignore_partial {
+        if scan.args.first_match || scan.args.ignore_partial {
             return Ok(());
         }
     }
 
     if !matches.partial.is_empty() {
-        if matches.partial.len() == 1 {
-            let (game_name, rom_name) = matches.partial.first().expect("should have a partial match");
-            update_scanned(scanned_file, MatchType::Partial, game_name, rom_name);
-
-            if can_rename && args.fix {
-                let new_pathname = full_file_path.with_file_name(rom_name);
-                debug_log!(debug, "Renaming file from: {} to: {}", scanned_file.path, new_pathname);
-                if let Err(e) = std::fs::rename(&scanned_file.path, &new_pathname) {
-                    eprintln!("Failed to rename file: {}", e);
-                    print_partial_match(&args.file_display, scanned_file, rel_file_path);
-                } else {
-                    //we renamed the file so we need to fix to file data
-                    scanned_file.match_type = MatchType::Exact;
-                    scanned_file.path = new_pathname.as_str().to_owned();
-                    print_exact_match(&args.file_display, scanned_file, rel_file_path);
+        if !matches.exact.is_empty() {
+            //already stored with its exact match, which a partial one must not replace (or rename the file away from)
+            scan.report_file(DisplayMethod::Partial, matches.report_candidates(scanned_file, rel_file_path));
+        } else if let Some(matched) = pick_partial(scan, rel_file_path, &matches.partial, can_rename)? {
+            //a match picked from several was chosen to rename to, so it is not confirmed again
+            let chosen = matches.partial.len() > 1;
+            update_scanned(scanned_file, MatchType::Partial, &matched.game_name, &matched.rom_name);
+
+            let rename = if can_rename && scan.args.fix {
+                rename_to_rom(scan, full_file_path, rel_file_path, scanned_file, &matched.game_name, &matched.rom_name, chosen)?
+            } else {
+                Rename::Skipped
+            };
+            let verified = match rename {
+                Rename::DryRun => return Ok(()),
+                Rename::Renamed(renamed) => {
+                    verify_fix(db, scan, rel_file_path, scanned_file, &renamed, &matched.game_name, &matched.rom_name)?
                 }
+                Rename::Skipped => false,
+            };
+
+            if verified {
+                //we renamed the file so it now matches exactly
+                scanned_file.match_type = MatchType::Exact;
+                scan.report_file(DisplayMethod::Exact, matches.report(FileStatus::Ok, scanned_file, rel_file_path, [matched]));
             } else {
-                print_partial_match(&args.file_display, scanned_file, rel_file_path);
+                scan.report_file(DisplayMethod::Partial, matches.report(FileStatus::Name, scanned_file, rel_file_path, [matched]));
             }
 
-            db.store_file(scanned_file)?;
+            store_file(db, scan, scanned_file)?;
+        } else {
+            let first = matches.partial.first().expect("should have a partial match");
+            update_scanned(scanned_file, MatchType::Partial, &first.game_name, &first.rom_name);
+            store_file(db, scan, scanned_file)?;
+
+            scan.report_file(DisplayMethod::Partial, matches.report_candidates(scanned_file, rel_file_path));
+        }
+    }
+    Ok(())
+}
+
+// the partial match to go with: the only one, or with --interactive the one chosen when there are several
+fn pick_partial<'a>(
+    scan: &ScanContext,
+    rel_file_path: &Utf8Path,
+    partial: &'a [MatchedRom],
+    can_rename: bool,
+) -> Result<Option<&'a MatchedRom>> {
+    if let [matched] = partial {
+        return Ok(Some(matched));
+    }
+    if !(scan.args.interactive && scan.args.fix && can_rename) {
+        return Ok(None);
+    }
+    let options: Vec<String> = partial
+        .iter()
+        .map(|matched| format!("{} (Game: {})", matched.rom_name, matched.game_name))
+        .collect();
+    let question = format!("{} matches {} roms, which should it be renamed to?", rel_file_path, partial.len());
+    let timeout = scan.args.interactive_timeout.map(Duration::from_secs);
+    Ok(ui::choose(&question, &options, timeout)?.map(|index| &partial[index]))
+}
+
+// what became of renaming a file to its rom name
+enum Rename {
+    Renamed(RenamedFile),
+    Skipped,
+    // only reported, the file and its database entry are left alone
+    DryRun,
+}
+
+// where a renamed file was, and its size and time then, for --verify-after-fix to check it by and put it back
+struct RenamedFile {
+    path: Utf8PathBuf,
+    base_path: String,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+// rename the file to where the rename template puts it after confirmation, unless already confirmed, or with --dry-run
+// only report it
+fn rename_to_rom(
+    scan: &ScanContext,
+    full_file_path: &Utf8Path,
+    rel_file_path: &Utf8Path,
+    scanned_file: &mut models::ScannedFile,
+    game_name: &str,
+    rom_name: &str,
+    confirmed: bool,
+) -> Result<Rename> {
+    let target = scan.args.rename_target(game_name, rom_name);
+    if scan.args.dry_run {
+        let mut report = FileReport::new(FileStatus::Would, rel_file_path.as_str(), Some(&scanned_file.hash));
+        report.renamed_to = Some(target.into_string());
+        scan.reporter.file(report);
+        return Ok(Rename::DryRun);
+    }
+    let new_pathname = full_file_path.with_file_name(&target);
+    let old_path = Utf8PathBuf::from(&scanned_file.path);
+    let skip = || {
+        let mut report = FileReport::new(FileStatus::Skip, rel_file_path.as_str(), Some(&scanned_file.hash));
+        report.renamed_to = Some(target.to_string());
+        scan.reporter.file(report);
+        Ok(Rename::Skipped)
+    };
+    //a name that only differs in case is the same file on a case-insensitive file system, and is renamed over it
+    let same_file = new_pathname.exists() && is_same_file(&old_path, &new_pathname);
+    if new_pathname.exists() && !same_file && !scan.args.force {
+        return skip();
+    }
+    if !confirmed && !ui::confirm(&format!("Rename {} to {}?", rel_file_path, target))? {
+        return Ok(Rename::Skipped);
+    }
+    debug!("Renaming file from: {} to: {}", scanned_file.path, new_pathname);
+    let new_parent = new_pathname.parent().expect("should have a parent directory");
+    let renamed = old_path.metadata().and_then(|metadata| {
+        std::fs::create_dir_all(new_parent)?;
+        if same_file || scan.args.force {
+            std::fs::rename(&old_path, &new_pathname)?;
         } else {
-            for (game_name, rom_name) in &matches.partial {
-                update_scanned(scanned_file, MatchType::Partial, game_name, rom_name);
-                db.store_file(scanned_file)?;
+            rename_no_replace(&old_path, &new_pathname)?;
+        }
+        Ok(metadata)
+    });
+    match renamed {
+        //something was put there since it was checked
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => skip(),
+        Err(e) => {
+            eprintln!("Failed to rename file: {}", e);
+            Ok(Rename::Skipped)
+        }
+        Ok(metadata) => {
+            //we renamed the file so we need to fix to file data, it belongs to the directory it was moved to
+            let old_base_path = std::mem::replace(&mut scanned_file.base_path, new_parent.as_str().to_owned());
+            scanned_file.path = new_pathname.as_str().to_owned();
+            if let Some(parent) = full_file_path.parent() {
+                scan.moved_from.borrow_mut().insert(parent.to_owned());
+            }
+            Ok(Rename::Renamed(RenamedFile {
+                path: old_path,
+                base_path: old_base_path,
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            }))
+        }
+    }
+}
+
+// whether both paths are the same file
+#[cfg(unix)]
+fn is_same_file(a: &Utf8Path, b: &Utf8Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// whether both paths are the same file, going by the canonical path as std has no stable file id here. It has the
+// case the file system stored, whatever case the path was given in
+#[cfg(not(unix))]
+fn is_same_file(a: &Utf8Path, b: &Utf8Path) -> bool {
+    match (a.canonicalize_utf8(), b.canonicalize_utf8()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// rename a file unless another is at the new path, which a plain rename replaces on most platforms. Linking the
+// file there first fails if anything is, so nothing can appear in between. File systems without hard links, such as
+// FAT, fall back to checking the path before renaming
+fn rename_no_replace(from: &Utf8Path, to: &Utf8Path) -> io::Result<()> {
+    match std::fs::hard_link(from, to) {
+        Ok(()) => std::fs::remove_file(from),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(e),
+        Err(_) if to.exists() => Err(ErrorKind::AlreadyExists.into()),
+        Err(_) => std::fs::rename(from, to),
+    }
+}
+
+// remove the directories files were moved out of, and their parents, if that left them empty
+fn prune_moved_from(scan: &ScanContext) -> Result<()> {
+    let moved_from = scan.moved_from.borrow();
+    if !scan.args.prune_empty_dirs || moved_from.is_empty() {
+        return Ok(());
+    }
+    //deepest first, so a parent is only looked at once its children are gone
+    let mut candidates: Vec<&Utf8PathBuf> = moved_from.iter().collect();
+    candidates.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+
+    for candidate in candidates {
+        let mut current = candidate.as_path();
+        while current != scan.directory && current.starts_with(&scan.directory) {
+            let metadata = std::fs::symlink_metadata(current)?;
+            if metadata.file_type().is_symlink() || !metadata.is_dir() || current.read_dir_utf8()?.next().is_some() {
+                break;
+            }
+            debug!("Removing empty directory: {}", current);
+            //remove_dir refuses to remove a directory that is no longer empty
+            if let Err(e) = std::fs::remove_dir(current) {
+                eprintln!("Failed to remove directory {}: {}", current, e);
+                break;
             }
+            let rel_path = current.strip_prefix(&scan.directory).unwrap_or(current);
+            scan.reporter
+                .file(FileReport::new(FileStatus::Prune, rel_path.as_str(), None));
+            match current.parent() {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
 
-            if args.file_display.contains(&DisplayMethod::Partial) {
-                println!("[NAME] {} {}", scanned_file.hash, rel_file_path);
-                for (game_name, rom_name) in &matches.partial {
-                    println!("------ Rom: {} Game: {}", rom_name, game_name);
+// when --verify-after-fix is set, check the renamed file really is the rom now, returning whether it is.
+// a file that is not is renamed back, unless the file at the new name is not the one renamed at all
+fn verify_fix(
+    db: &database::Database,
+    scan: &ScanContext,
+    rel_file_path: &Utf8Path,
+    scanned_file: &mut models::ScannedFile,
+    renamed: &RenamedFile,
+    game_name: &str,
+    rom_name: &str,
+) -> Result<bool> {
+    if !scan.args.verify_after_fix {
+        return Ok(true);
+    }
+    let target = scan.args.rename_target(game_name, rom_name);
+    let new_path = Utf8PathBuf::from(&scanned_file.path);
+    let checked = check_renamed_file(&new_path, renamed).and_then(|()| {
+        check_fixed_file(db, scan, &new_path, scanned_file, rom_name).map_err(|e| {
+            match rename_no_replace(&new_path, &renamed.path) {
+                Ok(()) => {
+                    scanned_file.path = renamed.path.as_str().to_owned();
+                    scanned_file.base_path.clone_from(&renamed.base_path);
+                    e.context("renamed back")
                 }
+                Err(back) => e.context(format!("could not rename it back: {}", back)),
             }
+        })
+    });
+    match checked {
+        Ok(()) => {
+            let mut report = FileReport::new(FileStatus::Fixed, rel_file_path.as_str(), Some(&scanned_file.hash));
+            report.renamed_to = Some(target.to_string());
+            scan.reporter.file(report);
+            Ok(true)
+        }
+        Err(e) => {
+            debug!("Verification of {} failed: {:#}", new_path, e);
+            let mut report = FileReport::new(FileStatus::FixFailed, rel_file_path.as_str(), Some(&scanned_file.hash));
+            report.renamed_to = Some(target.to_string());
+            report.error = Some(format!("{:#}", e));
+            scan.reporter.file(report);
+            Ok(false)
         }
     }
+}
+
+// the file at the new name has the size and modified time the file renamed had, which a rename keeps
+fn check_renamed_file(new_path: &Utf8Path, renamed: &RenamedFile) -> Result<()> {
+    let metadata = new_path.symlink_metadata().context("renamed file is gone")?;
+    if !metadata.is_file() || metadata.len() != renamed.len || metadata.modified().ok() != renamed.modified {
+        return Err(anyhow!("{} is not the file renamed", new_path));
+    }
     Ok(())
 }
 
-fn print_exact_match(file_display: &[DisplayMethod], scanned_file: &ScannedFile, rel_file_path: &Utf8Path) {
-    if file_display.contains(&DisplayMethod::Exact) {
-        println!(
-            "[OK  ] {} {}\n------ Rom: {} Game: {}",
-            scanned_file.hash,
-            rel_file_path,
-            scanned_file.rom_name.as_ref().expect("should have a rom name"),
-            scanned_file.game_name.as_ref().expect("should have a game name")
-        );
+fn check_fixed_file(
+    db: &database::Database,
+    scan: &ScanContext,
+    new_path: &Utf8Path,
+    scanned_file: &models::ScannedFile,
+    rom_name: &str,
+) -> Result<()> {
+    //look for the exact name in the directory, a case-insensitive file system would accept any case on open
+    let parent = new_path
+        .parent()
+        .ok_or_else(|| anyhow!("Renamed file has no parent directory"))?;
+    let file_name = new_path.file_name().ok_or_else(|| anyhow!("Renamed file has no file name"))?;
+    let listed = parent
+        .read_dir_utf8()?
+        .filter_map(Result::ok)
+        .any(|entry| entry.file_name() == file_name);
+    if !listed {
+        return Err(anyhow!("{} not found in {}", file_name, parent));
+    }
+
+    let header = recorded_header(scanned_file, scan.rules.as_ref(), new_path);
+    let mut file = BufReader::new(File::open(new_path)?);
+    let file_hash = hash_contents(&mut file, new_path, &[scanned_file.hash_type], header.as_ref(), false)?;
+    let hash = file_hash.primary();
+    if hash != scanned_file.hash {
+        return Err(anyhow!("hash changed to {}", hash));
+    }
+
+    let results = if file_hash.disk {
+        search_disks(db, hash)?
+    } else {
+        db.search_roms_by_hash(&[(scanned_file.hash_type, hash)], crc_size(scan, file_hash.size))?
+    };
+    let exact = results
+        .iter()
+        .any(|(_, roms)| roms.iter().any(|rom_match| rom_match.rom.name == rom_name));
+    if exact {
+        Ok(())
+    } else {
+        Err(anyhow!("no rom named {} has this hash", rom_name))
     }
 }
 
-fn print_partial_match(file_display: &[DisplayMethod], scanned_file: &ScannedFile, rel_file_path: &Utf8Path) {
-    if file_display.contains(&DisplayMethod::Partial) {
-        println!(
-            "[NAME] {} {}\n------ Rom: {} Game: {}",
-            scanned_file.hash,
-            rel_file_path,
-            scanned_file.rom_name.as_ref().expect("should have a rom name"),
-            scanned_file.game_name.as_ref().expect("should have a game name")
-        );
+// the size a CRC match must also have, unless the check was turned off
+fn crc_size(scan: &ScanContext, size: u64) -> Option<u64> {
+    (!scan.args.no_size_check).then_some(size)
+}
+
+fn store_file(db: &database::Database, scan: &ScanContext, scanned_file: &models::ScannedFile) -> Result<()> {
+    if scan.read_only {
+        Ok(())
+    } else {
+        db.store_file(scanned_file)
     }
 }
 
@@ -900,73 +3758,413 @@ fn update_scanned(scanned_file: &mut models::ScannedFile, match_type: MatchType,
     scanned_file.rom_name = Some(rom_name.to_owned());
 }
 
-fn print_found_games(found_games: &BTreeMap<String, GameStatus>) {
-    println!("\nFound Games:");
-    for (game_name, status) in found_games {
-        let exact_count = status.exact_matches.len();
-        let partial_count = status.partial_matches.len();
-        let total_count = exact_count + partial_count;
-
-        //only count the game as matched if we have at least one exact match or all the roms are matched
-        if exact_count > 0 || total_count == status.roms.len() {
-            if exact_count == status.roms.len() {
-                println!("[FULL] {}", game_name);
-                for (rom_name, filenames) in &status.exact_matches {
-                    if filenames.len() > 1 {
-                        for filename in filenames {
-                            println!("[DUPE]   {} (File: {})", rom_name, filename);
-                        }
-                    }
-                }
-            } else {
-                println!(
-                    "[PART] {} ({} exact matches, {} partial matches. {} missing)",
-                    game_name,
-                    exact_count,
-                    partial_count,
-                    status.roms.len() - (exact_count + partial_count)
-                );
-                for (expected, partial_match) in &status.partial_matches {
-                    for filename in partial_match {
-                        println!("[NAME]   {} (Expected: {})", filename, expected);
-                    }
-                }
-                for rom in &status.roms {
-                    if !status.exact_matches.contains_key(&rom.name) && !status.partial_matches.contains_key(&rom.name) {
-                        println!("[MISS]   {}", rom.name);
-                    }
-                }
+// the report for a file as recorded in the database, or a hash mismatch if the file has changed since,
+// or a size mismatch if the size of the file is known and is not the size of the rom it matched
+fn scanned_file_report(
+    db: &database::Database,
+    hash: &str,
+    size: Option<u64>,
+    rel_file_path: &Utf8Path,
+    scanned_file: &models::ScannedFile,
+) -> Result<FileReport> {
+    if hash != scanned_file.hash.as_str() {
+        let mut report = FileReport::new(FileStatus::Hash, rel_file_path.as_str(), Some(hash));
+        report.expected_hash = Some(scanned_file.hash.clone());
+        return Ok(report);
+    }
+    let status = match scanned_file.match_type {
+        MatchType::Exact => FileStatus::Ok,
+        MatchType::Partial => FileStatus::Name,
+        MatchType::None => FileStatus::Miss,
+    };
+    let mut report = FileReport::new(status, rel_file_path.as_str(), Some(hash));
+    report.size = size;
+    if let (Some(game_name), Some(rom_name)) = (&scanned_file.game_name, &scanned_file.rom_name) {
+        let mut rom = RomReport::new(game_name, rom_name, &[]);
+        //disks have no rom entry, so there is no size to compare
+        if let (Some(size), Some(rom_size)) = (size, db.get_rom_size(game_name, rom_name)?) {
+            if !u64::try_from(rom_size).is_ok_and(|rom_size| rom_size == size) {
+                report.status = FileStatus::Size;
+                rom.expected_size = Some(rom_size);
             }
         }
+        report.matches.push(rom);
     }
+    Ok(report)
 }
 
-fn print_scanned_file(hash: &str, rel_file_path: &Utf8Path, scanned_file: &models::ScannedFile) {
-    if hash == scanned_file.hash.as_str() {
-        match scanned_file.match_type {
-            MatchType::Exact => {
-                println!(
-                    "[OK  ] {} {}\n------ Rom: {} Game: {}",
-                    &scanned_file.hash,
-                    rel_file_path,
-                    &scanned_file.rom_name.as_ref().expect("should have a rom name"),
-                    &scanned_file.game_name.as_ref().expect("should have a game name")
-                );
-            }
-            MatchType::Partial => {
-                println!(
-                    "[NAME] {} {}\n------ Rom: {} Game: {}",
-                    &scanned_file.hash,
-                    rel_file_path,
-                    &scanned_file.rom_name.as_ref().expect("should have a rom name"),
-                    &scanned_file.game_name.as_ref().expect("should have a game name")
-                );
-            }
-            MatchType::None => {
-                println!("[MISS] {} {}", scanned_file.hash, rel_file_path);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ReportOptions;
+    use crate::testing::{database_with, game, memory_database, rom, unmatched_file, TempDir};
+    use std::fs;
+
+    // the paths of every file left in the database, sorted
+    fn stored_paths(db: &database::Database) -> Result<Vec<String>> {
+        let mut paths: Vec<String> = db.get_all_files()?.into_iter().map(|file| file.path).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    // the arguments as clap fills them in from the command line given, e.g. ["-r", "--max-depth", "1"]
+    fn parse_args<T: Args + FromArgMatches>(args: &[&str]) -> T {
+        let matches = T::augment_args(Command::new("rcr2")).get_matches_from(std::iter::once("rcr2").chain(args.iter().copied()));
+        T::from_arg_matches(&matches).expect("the arguments should parse")
+    }
+
+    // scan the directory with the scan and filter options given, returning what was reported as JSON
+    fn scan(db: &database::Database, directory: &Utf8Path, options: &[&str], filter: &[&str]) -> Result<serde_json::Value> {
+        let args: ScanArgs = parse_args(&[options, &[directory.as_str()]].concat());
+        let mut filter: FileFilter = parse_args(filter);
+        filter.compile_exclusions()?;
+        let output = TempDir::new();
+        let results = output.path().join("results.json");
+        let reporter = report::create_for_output(OutputFormat::Json, ReportOptions::default(), Some(&results))?;
+        let scan = ScanContext::new(db, &args, reporter, false)?;
+        scan_directory(db, &scan, &default_args(), &filter)?;
+        scan.reporter.finish()?;
+        Ok(serde_json::from_str(&fs::read_to_string(&results)?)?)
+    }
+
+    // the status and path of each file in the results
+    fn reported_files(results: &serde_json::Value) -> Vec<(&str, &str)> {
+        let files = results["files"].as_array().map(Vec::as_slice).unwrap_or_default();
+        files
+            .iter()
+            .map(|file| (file["status"].as_str().unwrap_or_default(), file["path"].as_str().unwrap_or_default()))
+            .collect()
+    }
+
+    // the paths of the files stored, relative to the directory
+    fn stored_under(db: &database::Database, directory: &Utf8Path) -> Result<Vec<String>> {
+        let prefix = format!("{}/", directory.canonicalize_utf8()?);
+        Ok(stored_paths(db)?
+            .into_iter()
+            .map(|path| path.strip_prefix(&prefix).map_or(path.clone(), str::to_owned))
+            .collect())
+    }
+
+    #[test]
+    fn clean_leaves_sibling_directories_alone() -> Result<()> {
+        let db = memory_database();
+        //none of these are on disk, so every file under the base path is gone
+        for path in [
+            "/rcr2-gone/nes/a.bin",
+            "/rcr2-gone/nes/sub/b.bin",
+            "/rcr2-gone/nes2/c.bin",
+            "/rcr2-gone/nes-hacks/d.bin",
+        ] {
+            db.store_file(&unmatched_file(path))?;
+        }
+        clean_files(&db, Some(Utf8Path::new("/rcr2-gone/nes")), false)?;
+        assert_eq!(stored_paths(&db)?, ["/rcr2-gone/nes-hacks/d.bin", "/rcr2-gone/nes2/c.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_only_removes_gone_files_under_its_directory() -> Result<()> {
+        let db = memory_database();
+        for path in ["/rcr2-gone/a/a.bin", "/rcr2-gone/a/sub/b.bin", "/rcr2-gone/ab/c.bin"] {
+            db.store_file(&unmatched_file(path))?;
+        }
+        let reporter = report::create(OutputFormat::Human, ReportOptions::default());
+        let filter: FileFilter = default_args();
+        verify_files(&db, reporter.as_ref(), &filter, Utf8Path::new("/rcr2-gone/a"), true, true, None)?;
+        assert_eq!(stored_paths(&db)?, ["/rcr2-gone/ab/c.bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rom_names_match_exactly_by_default() {
+        let args: ScanArgs = default_args();
+        assert!(args.is_rom_name("Game.bin", "Game.bin"));
+        assert!(!args.is_rom_name("Game.BIN", "Game.bin"));
+        assert!(!args.is_rom_name("game.bin", "Game.bin"));
+    }
+
+    #[test]
+    fn rom_names_ignore_ascii_case_with_case_insensitive_names() {
+        let args = ScanArgs {
+            case_insensitive_names: true,
+            ..default_args()
+        };
+        assert!(args.is_rom_name("Game.BIN", "Game.bin"));
+        assert!(args.is_rom_name("GAME (EUROPE).bin", "Game (Europe).BIN"));
+        assert!(!args.is_rom_name("Game.bin", "Game.bin2"));
+        //other letters keep their case
+        assert!(args.is_rom_name("Élan.BIN", "Élan.bin"));
+        assert!(!args.is_rom_name("ÉLAN.bin", "élan.bin"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_finishes_with_symlink_cycles() -> Result<()> {
+        let temp = TempDir::new();
+        let root = temp.path();
+        fs::create_dir(root.join("sub"))?;
+        fs::write(root.join("sub/a.bin"), b"rom")?;
+        //a link back up the tree, and one that resolves to itself
+        std::os::unix::fs::symlink("..", root.join("sub/up"))?;
+        std::os::unix::fs::symlink("self", root.join("self"))?;
+        for filter in [&[][..], &["--follow-symlinks"]] {
+            let db = memory_database();
+            scan(&db, root, &["-r", "-m", "crc"], filter)?;
+            assert_eq!(stored_under(&db, root)?, ["sub/a.bin"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_hashes_match_hashing_the_whole_file_at_once() -> Result<()> {
+        //many chunks, and a partial one at the end
+        let contents: Vec<u8> = (0..50 * HASH_CHUNK_SIZE + 17).map(|i| (i * 31 % 251) as u8).collect();
+        let temp = TempDir::new();
+        let path = temp.path().join("disc.iso");
+        fs::write(&path, &contents)?;
+
+        let methods = [HashType::Crc, HashType::Md5, HashType::Sha1, HashType::Sha256];
+        let file_hash = read_and_hash_multi(&mut File::open(&path)?, &path, &methods, None, false)?;
+        assert_eq!(file_hash.size, contents.len() as u64);
+        assert_eq!(
+            file_hash.hashes,
+            [
+                (HashType::Crc, format!("{:08x}", crc32fast::hash(&contents))),
+                (HashType::Md5, format!("{:x}", Md5::digest(&contents))),
+                (HashType::Sha1, format!("{:x}", Sha1::digest(&contents))),
+                (HashType::Sha256, format!("{:x}", Sha256::digest(&contents))),
+            ]
+        );
+        Ok(())
+    }
+
+    // the files in the directory that the filter options given would look at, sorted
+    fn included_files(directory: &Utf8Path, filter: &[&str]) -> Result<Vec<String>> {
+        let filter: FileFilter = parse_args(filter);
+        let mut names = Vec::new();
+        for entry in directory.read_dir_utf8()? {
+            let entry = entry?;
+            if !should_skip_file(entry.path(), directory, &filter) {
+                names.push(entry.file_name().to_owned());
             }
         }
-    } else {
-        println!("[HASH] {} {} (Expected: {})", hash, rel_file_path, scanned_file.hash);
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn include_and_exclude_extensions() -> Result<()> {
+        let temp = TempDir::new();
+        for name in ["a.sfc", "b.smc", "c.bin", "d.dat"] {
+            fs::write(temp.path().join(name), b"rom")?;
+        }
+        //archives are opened whatever the include list says, so their members can be filtered
+        fs::write(temp.path().join("e.zip"), b"PK\x03\x04")?;
+        let root = temp.path();
+
+        assert_eq!(included_files(root, &[])?, ["a.sfc", "b.smc", "c.bin", "e.zip"]);
+        assert_eq!(included_files(root, &["--include-extensions", "sfc,smc"])?, ["a.sfc", "b.smc", "e.zip"]);
+        assert_eq!(included_files(root, &["-e", "bin,zip"])?, ["a.sfc", "b.smc", "d.dat"]);
+        //the include list applies first, then the exclude list removes from what it let through
+        assert_eq!(
+            included_files(root, &["--include-extensions", "sfc,smc,bin", "-e", "smc"])?,
+            ["a.sfc", "c.bin", "e.zip"]
+        );
+        //including an extension excluded by default still leaves it out
+        assert_eq!(included_files(root, &["--include-extensions", "sfc,dat"])?, ["a.sfc", "e.zip"]);
+        Ok(())
+    }
+
+    // the stored match of the file at the path relative to the directory, as match type, game and rom
+    fn stored_match(
+        db: &database::Database,
+        directory: &Utf8Path,
+        rel_path: &str,
+    ) -> Result<(MatchType, Option<String>, Option<String>)> {
+        let path = directory.canonicalize_utf8()?.join(rel_path);
+        let file = db
+            .get_all_files()?
+            .into_iter()
+            .find(|file| file.path == path.as_str())
+            .ok_or_else(|| anyhow!("{} was not stored", rel_path))?;
+        Ok((file.match_type, file.game_name, file.rom_name))
+    }
+
+    #[test]
+    fn file_matching_several_roms_is_stored_with_the_first_game() -> Result<()> {
+        const CONTENTS: &[u8] = b"sixteen bytes!!!";
+        let crc = format!("{:08x}", crc32fast::hash(CONTENTS));
+        let temp = TempDir::new();
+        fs::write(temp.path().join("a.bin"), CONTENTS)?;
+        for options in [&[][..], &["--first-match"]] {
+            //merged out of order, and with a differently named rom of the same contents
+            let db = database_with(vec![
+                game("Game B", vec![rom("a.bin", &crc)]),
+                game("Game C", vec![rom("other.bin", &crc)]),
+                game("Game A", vec![rom("a.bin", &crc)]),
+            ]);
+            scan(&db, temp.path(), &[&["-m", "crc"], options].concat(), &[])?;
+            assert_eq!(
+                stored_match(&db, temp.path(), "a.bin")?,
+                (MatchType::Exact, Some(String::from("Game A")), Some(String::from("a.bin")))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zero_byte_file_matches_an_empty_rom_by_name() -> Result<()> {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("track02.bin"), b"")?;
+        fs::write(temp.path().join("other.bin"), b"")?;
+        let empty = Rom {
+            size: 0,
+            crc: None,
+            ..rom("track02.bin", "")
+        };
+        let db = database_with(vec![game("Game", vec![rom("track01.bin", "11111111"), empty])]);
+        scan(&db, temp.path(), &[], &[])?;
+        assert_eq!(
+            stored_match(&db, temp.path(), "track02.bin")?,
+            (MatchType::Exact, Some(String::from("Game")), Some(String::from("track02.bin")))
+        );
+        //with no hash to go by, a file named otherwise matches nothing
+        assert_eq!(stored_match(&db, temp.path(), "other.bin")?, (MatchType::None, None, None));
+        Ok(())
+    }
+
+    #[test]
+    fn max_depth_limits_how_far_a_recursive_scan_goes() -> Result<()> {
+        let temp = TempDir::new();
+        let root = temp.path();
+        fs::create_dir_all(root.join("one/two/three"))?;
+        for path in ["top.bin", "one/one.bin", "one/two/two.bin", "one/two/three/three.bin"] {
+            fs::write(root.join(path), path)?;
+        }
+        let everything = ["one/one.bin", "one/two/three/three.bin", "one/two/two.bin", "top.bin"];
+        for (max_depth, expected) in [
+            (Some("0"), &["top.bin"][..]),
+            (Some("1"), &["one/one.bin", "top.bin"]),
+            (Some("2"), &["one/one.bin", "one/two/two.bin", "top.bin"]),
+            (Some("3"), &everything),
+            (None, &everything),
+        ] {
+            let db = memory_database();
+            let filter: Vec<&str> = max_depth.into_iter().flat_map(|depth| ["--max-depth", depth]).collect();
+            scan(&db, root, &["-r", "-m", "crc"], &filter)?;
+            assert_eq!(stored_under(&db, root)?, expected, "--max-depth {:?}", max_depth);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_leaves_disks_alone() -> Result<()> {
+        const CONTENTS: &[u8] = b"sixteen bytes!!!";
+        let crc = format!("{:08x}", crc32fast::hash(CONTENTS));
+        let temp = TempDir::new();
+        let output = temp.path().join("out");
+        fs::create_dir(&output)?;
+        let disk = |name: &str| Disk {
+            name: name.to_owned(),
+            sha1: Some(String::from("0000000000000000000000000000000000000001")),
+            md5: None,
+        };
+        let mut with_disk = game("Game A", vec![rom("a.bin", &crc)]);
+        with_disk.disks.push(disk("disc"));
+        let mut disk_only = game("Game B", Vec::new());
+        disk_only.disks.push(disk("other"));
+        let db = database_with(vec![with_disk, disk_only]);
+
+        //stored as a scan stores them, disks under the name of their CHD file
+        for (name, game_name, contents, hash) in [
+            ("a.bin", "Game A", CONTENTS, crc.as_str()),
+            ("disc.chd", "Game A", b"not really a chd", "0000000000000000000000000000000000000001"),
+            ("other.chd", "Game B", b"not really a chd", "0000000000000000000000000000000000000001"),
+        ] {
+            let path = temp.path().join(name);
+            fs::write(&path, contents)?;
+            let mut file = unmatched_file(path.as_str());
+            file.hash = hash.to_owned();
+            file.match_type = MatchType::Exact;
+            file.game_name = Some(game_name.to_owned());
+            file.rom_name = Some(name.to_owned());
+            db.store_file(&file)?;
+        }
+
+        rebuild_games(&db, temp.path(), &output, false, true, false)?;
+        assert!(output.join("Game A.zip").is_file());
+        assert!(!output.join("Game B.zip").exists());
+        assert!(!temp.path().join("a.bin").exists());
+        assert!(temp.path().join("disc.chd").is_file());
+        assert!(temp.path().join("other.chd").is_file());
+        let mut expected = vec![
+            output.join("Game A.zip/a.bin").into_string(),
+            temp.path().join("disc.chd").into_string(),
+            temp.path().join("other.chd").into_string(),
+        ];
+        expected.sort();
+        assert_eq!(stored_paths(&db)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn case_insensitive_names_take_a_mixed_case_file_as_named_after_its_rom() -> Result<()> {
+        const CONTENTS: &[u8] = b"sixteen bytes!!!";
+        let crc = format!("{:08x}", crc32fast::hash(CONTENTS));
+        for (options, reported, stored_as, match_type) in [
+            //without the flag the name is wrong, so it is stored as a partial match
+            (&["-m", "crc"][..], "name", "Game.BIN", MatchType::Partial),
+            (&["-m", "crc", "--case-insensitive-names"], "ok", "Game.BIN", MatchType::Exact),
+            //and --fix renames it only without the flag
+            (&["-m", "crc", "--fix"], "ok", "Game.bin", MatchType::Exact),
+            (&["-m", "crc", "--fix", "--case-insensitive-names"], "ok", "Game.BIN", MatchType::Exact),
+        ] {
+            let temp = TempDir::new();
+            fs::write(temp.path().join("Game.BIN"), CONTENTS)?;
+            let db = database_with(vec![game("Game", vec![rom("Game.bin", &crc)])]);
+            let results = scan(&db, temp.path(), options, &[])?;
+            assert_eq!(reported_files(&results), [(reported, "Game.BIN")], "{:?}", options);
+            assert_eq!(stored_under(&db, temp.path())?, [stored_as], "{:?}", options);
+            assert!(temp.path().join(stored_as).is_file());
+            assert_eq!(
+                stored_match(&db, temp.path(), stored_as)?,
+                (match_type, Some(String::from("Game")), Some(String::from("Game.bin"))),
+                "{:?}",
+                options
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn newer_than_still_counts_the_roms_of_older_files() -> Result<()> {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("a.bin"), b"older")?;
+        fs::write(temp.path().join("b.bin"), b"newer")?;
+        let crc = |contents: &[u8]| format!("{:08x}", crc32fast::hash(contents));
+        let (a, b) = (
+            Rom {
+                size: 5,
+                ..rom("a.bin", &crc(b"older"))
+            },
+            Rom {
+                size: 5,
+                ..rom("b.bin", &crc(b"newer"))
+            },
+        );
+        let db = database_with(vec![game("Game", vec![a, b])]);
+        scan(&db, temp.path(), &["-m", "crc"], &[])?;
+
+        let week_ago = SystemTime::now() - Duration::from_secs(7 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(temp.path().join("a.bin"))?
+            .set_modified(week_ago)?;
+        let results = scan(&db, temp.path(), &["-m", "crc", "--newer-than", "1d"], &[])?;
+        //only the newer file is looked at, the older one is found from what the database has
+        assert_eq!(reported_files(&results), [("ok", "b.bin")]);
+        assert_eq!(results["summary"]["games"]["full"], 1);
+        assert_eq!(stored_under(&db, temp.path())?, ["a.bin", "b.bin"]);
+        Ok(())
     }
 }
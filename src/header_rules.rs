@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+// default header rules, overridden per extension by a user supplied rules file
+const DEFAULT_RULES: &str = include_str!("header_rules.json");
+
+// sanity limit for header lengths, anything bigger is almost certainly a mistake in the rules file
+const MAX_HEADER_LENGTH: usize = 64 * 1024;
+
+#[derive(Args, Clone, Default)]
+pub struct HeaderArgs {
+    /// Strip known copier/emulator headers before hashing
+    #[arg(long)]
+    strip_headers: bool,
+
+    /// Path to a JSON file of header rules (extension -> length and optional magic), implies --strip-headers
+    #[arg(long)]
+    header_rules: Option<Utf8PathBuf>,
+
+    /// Skip a fixed number of header bytes before hashing, comma separated, either for every file
+    /// or per extension, e.g. "16" or "nes=16,fds=16,lnx=64"; a per extension value overrides any other rule
+    #[arg(long, value_delimiter = ',', value_name = "[EXT=]BYTES")]
+    skip_header: Vec<String>,
+}
+
+impl HeaderArgs {
+    /// Load the header rules requested on the command line, if any
+    pub fn load(&self) -> Result<Option<HeaderRules>> {
+        let mut rules = if let Some(path) = &self.header_rules {
+            HeaderRules::load(path)?
+        } else if self.strip_headers {
+            HeaderRules::defaults()?
+        } else if self.skip_header.is_empty() {
+            return Ok(None);
+        } else {
+            HeaderRules::default()
+        };
+        for value in &self.skip_header {
+            rules
+                .merge_skip(value)
+                .with_context(|| format!("Invalid --skip-header value '{}'", value))?;
+        }
+        Ok(Some(rules))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    length: usize,
+    magic: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeaderRule {
+    pub length: usize,
+    pub magic: Option<Vec<u8>>,
+}
+
+impl HeaderRule {
+    /// A header of a fixed length that is always stripped, whatever the file starts with
+    pub fn fixed(length: usize) -> Self {
+        HeaderRule { length, magic: None }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRules {
+    rules: HashMap<String, HeaderRule>,
+    // applies to files with no rule for their extension
+    fallback: Option<HeaderRule>,
+}
+
+impl HeaderRules {
+    pub fn defaults() -> Result<Self> {
+        let mut rules = HeaderRules::default();
+        rules.merge_json(DEFAULT_RULES).context("Invalid built-in header rules")?;
+        Ok(rules)
+    }
+
+    /// Load the default rules and override them with the rules from the given file
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let mut rules = Self::defaults()?;
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|file| BufReader::new(file).read_to_string(&mut contents))
+            .with_context(|| format!("Failed to read header rules file {}", path))?;
+        rules
+            .merge_json(&contents)
+            .with_context(|| format!("Invalid header rules file {}", path))?;
+        Ok(rules)
+    }
+
+    fn merge_json(&mut self, json: &str) -> Result<()> {
+        let raw: HashMap<String, RawRule> = serde_json::from_str(json)?;
+        for (extension, raw_rule) in raw {
+            let rule = validate_rule(&extension, raw_rule)?;
+            self.rules.insert(extension.to_ascii_lowercase(), rule);
+        }
+        Ok(())
+    }
+
+    // add a --skip-header value, either a length for every file or extension=length
+    fn merge_skip(&mut self, value: &str) -> Result<()> {
+        match value.split_once('=') {
+            Some((extension, length)) => {
+                let length = parse_length(length)?;
+                let rule = validate_rule(extension, RawRule { length, magic: None })?;
+                self.rules.insert(extension.to_ascii_lowercase(), rule);
+            }
+            None => {
+                let length = parse_length(value)?;
+                self.fallback = Some(validate_rule("*", RawRule { length, magic: None })?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the rule that applies to the given path, matched on its extension
+    pub fn for_path(&self, path: &Utf8Path) -> Option<&HeaderRule> {
+        path.extension()
+            .and_then(|extension| self.rules.get(&extension.to_ascii_lowercase()))
+            .or(self.fallback.as_ref())
+    }
+}
+
+fn parse_length(length: &str) -> Result<usize> {
+    length
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("'{}' is not a number of bytes", length))
+}
+
+fn validate_rule(extension: &str, raw_rule: RawRule) -> Result<HeaderRule> {
+    if extension.is_empty() || extension.contains('.') {
+        return Err(anyhow!("Invalid extension '{}', expected an extension without a leading dot", extension));
+    }
+    if raw_rule.length == 0 || raw_rule.length > MAX_HEADER_LENGTH {
+        return Err(anyhow!(
+            "Invalid header length {} for '{}', expected 1 to {} bytes",
+            raw_rule.length,
+            extension,
+            MAX_HEADER_LENGTH
+        ));
+    }
+    let magic = match raw_rule.magic {
+        Some(hex) => {
+            let bytes = parse_hex(&hex).with_context(|| format!("Invalid magic bytes for '{}'", extension))?;
+            if bytes.is_empty() || bytes.len() > raw_rule.length {
+                return Err(anyhow!("Magic bytes for '{}' must be between 1 and {} bytes long", extension, raw_rule.length));
+            }
+            Some(bytes)
+        }
+        None => None,
+    };
+    Ok(HeaderRule {
+        length: raw_rule.length,
+        magic,
+    })
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("'{}' has an odd number of hex digits", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow!("'{}' is not a valid hex string", hex))
+        })
+        .collect()
+}
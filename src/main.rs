@@ -1,28 +1,87 @@
-use anyhow::Result;
-use camino::Utf8PathBuf;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use report::{ReportOptions, Verbosity};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process::ExitCode;
 
+mod archive;
+mod checkpoint;
 mod database;
 mod db_commands;
 mod file_commands;
+mod header_rules;
 mod models;
+mod output;
+mod report;
+#[cfg(test)]
+mod testing;
+mod ui;
 mod xml_parser;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES)]
 struct Cli {
     /// Path to the database
     #[arg(short, long, default_value = ".rcr.db")]
     database: Utf8PathBuf,
 
-    /// Enable debug output
+    /// Enable debug output, the same as -v
     #[arg(long)]
     debug: bool,
 
+    /// Log what is being done to stderr, -v for debug and -vv for trace. RUST_LOG overrides the level
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Also append the log to this file
+    #[arg(long)]
+    log_file: Option<Utf8PathBuf>,
+
+    /// Only report problems: skip directory banners, exact matches and complete games
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// List at most this many missing roms for each partly matched game
+    #[arg(long, global = true)]
+    max_missing: Option<usize>,
+
+    /// Only print one line for each found game, without its misnamed, missing and duplicated roms
+    #[arg(long, global = true)]
+    summary_only: bool,
+
+    /// Exit with 2 for new, moved or would-be renamed files too, not just missing and misnamed ones
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Disable progress output, even when running in a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Assume yes for destructive operations instead of prompting, required when not running interactively
+    #[arg(short, long)]
+    yes: bool,
+
+    /// When to color the status tags in results
+    #[arg(long, value_enum, default_value = "auto")]
+    color: ui::ColorChoice,
+
+    /// Output format for results, commands that only have a human readable report reject the others
+    #[arg(long, value_enum, global = true, default_value = "human")]
+    format: output::OutputFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+const EXIT_CODES: &str = "Exit codes:
+  0  Every file matched exactly and every game found is complete
+  1  The command failed
+  2  Some files matched nothing or have the wrong name, or some games are missing roms
+  3  Some files have the wrong hash or size, are gone, or could not be fixed";
+
 #[derive(Subcommand)]
 enum Commands {
     /// Perform a database operation
@@ -33,25 +92,122 @@ enum Commands {
     /// Perform a file operation
     File {
         #[command(subcommand)]
-        file_command: file_commands::FileCommands,
+        file_command: Box<file_commands::FileCommands>,
 
-        /// List of file extensions to exclude, comma separated
-        #[arg(short, long, value_delimiter = ',', default_value = "m3u,dat")]
-        exclude_extensions: Vec<String>,
+        #[command(flatten)]
+        filter: file_commands::FileFilter,
     },
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let mut cli = Cli::parse();
+    let verbose = cli.verbose.max(u8::from(cli.debug));
+    //the progress line would only get in the way of log lines on stderr
+    ui::init(cli.no_progress || verbose > 0, cli.yes, cli.color, cli.format);
+    init_logging(verbose, cli.log_file.as_deref())?;
 
     match &mut cli.command {
-        Commands::Database { db_command } => db_commands::handle_command(&cli.database, cli.debug, db_command),
-        Commands::File {
-            file_command,
-            exclude_extensions,
-        } => {
-            let mut db = database::check_for_database(&cli.database, cli.debug)?;
-            file_commands::handle_command(&mut db, cli.debug, file_command, exclude_extensions)
+        Commands::Database { db_command } => {
+            db_commands::handle_command(&cli.database, cli.format, db_command)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Commands::File { file_command, filter } => {
+            filter.compile_exclusions()?;
+            //hashing files needs no database, so these work before one is initialized
+            match file_command.as_ref() {
+                file_commands::FileCommands::Hash(args) => {
+                    file_commands::hash_directory(args, filter, cli.format)?;
+                    return Ok(ExitCode::SUCCESS);
+                }
+                file_commands::FileCommands::Bench(args) => {
+                    file_commands::bench_hashes(args, filter, cli.format)?;
+                    return Ok(ExitCode::SUCCESS);
+                }
+                _ => {}
+            }
+            let mut db = database::check_for_database(&cli.database)?;
+            let options = ReportOptions {
+                verbosity: if cli.quiet { Verbosity::Quiet } else { Verbosity::Normal },
+                max_missing: cli.max_missing,
+                summary_only: cli.summary_only,
+            };
+            let outcome = file_commands::handle_command(&mut db, cli.format, options, file_command, filter)?;
+            Ok(ExitCode::from(outcome.exit_code(cli.strict)))
+        }
+    }
+}
+
+// log this crate's messages at the level asked for, other crates only when RUST_LOG asks for them
+fn init_logging(verbose: u8, log_file: Option<&Utf8Path>) -> Result<()> {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let file = log_file
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path))
+        })
+        .transpose()?;
+    let mut builder = env_logger::Builder::new();
+    //RUST_LOG replaces the level rather than adding to it, a bare level there would lose to the module one
+    if std::env::var_os("RUST_LOG").is_some() {
+        builder.parse_default_env();
+    } else {
+        builder.filter_module(module_path!(), level);
+    }
+    builder.target(env_logger::Target::Pipe(Box::new(LogWriter { file }))).init();
+    Ok(())
+}
+
+// writes each log line to stderr, and to the log file as well when there is one
+struct LogWriter {
+    file: Option<File>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(buf)?;
         }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_options_are_accepted_after_the_subcommand() {
+        let cli = Cli::try_parse_from([
+            "rcr2",
+            "file",
+            "scan",
+            "-q",
+            "--max-missing",
+            "5",
+            "--summary-only",
+            "--strict",
+            "roms",
+        ])
+        .expect("should parse");
+        assert!(cli.quiet);
+        assert_eq!(cli.max_missing, Some(5));
+        assert!(cli.summary_only);
+        assert!(cli.strict);
     }
 }
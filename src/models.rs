@@ -1,41 +1,127 @@
-use serde::Deserialize;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString, IntoStaticStr};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DataFile {
     pub header: Header,
     #[serde(rename = "game")]
     pub games: Vec<Game>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Header {
     pub name: String,
     pub description: String,
     pub version: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Game {
     #[serde(rename = "@name")]
     pub name: String,
     pub description: String,
-    #[serde(rename = "rom")]
+    #[serde(rename = "rom", default)]
     pub roms: Vec<Rom>,
+    #[serde(rename = "disk", default)]
+    pub disks: Vec<Disk>,
+    // the DAT the game was imported from, only known for games read from the database
+    #[serde(skip)]
+    pub source: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Rom {
     #[serde(rename = "@name")]
     pub name: String,
     #[serde(rename = "@size")]
     pub size: i64,
-    #[serde(rename = "@crc")]
+    #[serde(rename = "@crc", skip_serializing_if = "Option::is_none")]
     pub crc: Option<String>,
-    #[serde(rename = "@md5")]
+    #[serde(rename = "@md5", skip_serializing_if = "Option::is_none")]
     pub md5: Option<String>,
-    #[serde(rename = "@sha1")]
+    #[serde(rename = "@sha1", skip_serializing_if = "Option::is_none")]
     pub sha1: Option<String>,
+    #[serde(rename = "@sha256", skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Rom {
+    pub fn hash(&self, hash_type: HashType) -> Option<&str> {
+        match hash_type {
+            HashType::Crc => self.crc.as_deref(),
+            HashType::Md5 => self.md5.as_deref(),
+            HashType::Sha1 => self.sha1.as_deref(),
+            HashType::Sha256 => self.sha256.as_deref(),
+        }
+    }
+
+    /// A zero byte rom listed without any hashes, such as an empty track, which only a name can match
+    pub fn is_empty(&self) -> bool {
+        self.size == 0 && self.crc.is_none() && self.md5.is_none() && self.sha1.is_none() && self.sha256.is_none()
+    }
+}
+
+/// A CHD disk image, identified by the SHA1 recorded in the CHD header
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Disk {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "@sha1", skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(rename = "@md5", skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+impl Disk {
+    /// Name of the CHD file holding this disk
+    pub fn file_name(&self) -> String {
+        format!("{}.chd", self.name)
+    }
+
+    /// Disks are matched like roms named after their CHD file, they have no size to compare
+    pub fn as_rom(&self) -> Rom {
+        Rom {
+            name: self.file_name(),
+            size: 0,
+            crc: None,
+            md5: self.md5.clone(),
+            sha1: self.sha1.clone(),
+            sha256: None,
+        }
+    }
+}
+
+/// How many of a game's roms (and disks) are matched exactly by a scanned file
+#[derive(Clone, Debug)]
+pub struct SetStatus {
+    pub game_name: String,
+    pub roms: i64,
+    pub have: i64,
+}
+
+/// Totals of what the database holds, to confirm a DAT loaded fully and see which hashes it has
+#[derive(Clone, Debug, Default)]
+pub struct DatabaseStats {
+    pub games: i64,
+    pub roms: i64,
+    pub disks: i64,
+    // the sum of the rom sizes the DAT lists
+    pub rom_bytes: i64,
+    pub roms_with_crc: i64,
+    pub roms_with_md5: i64,
+    pub roms_with_sha1: i64,
+    pub roms_with_sha256: i64,
+    pub exact_files: i64,
+    pub partial_files: i64,
+    pub unmatched_files: i64,
+}
+
+/// A rom found by a hash search, along with which of the searched hashes it matched
+#[derive(Clone, Debug)]
+pub struct RomMatch {
+    pub rom: Rom,
+    pub matched_by: Vec<HashType>,
 }
 
 #[derive(Copy, Clone, Debug, Display, PartialEq, EnumString, IntoStaticStr)]
@@ -45,7 +131,8 @@ pub enum MatchType {
     None,
 }
 
-#[derive(Copy, Clone, Debug, Display, PartialEq, EnumString, IntoStaticStr)]
+// parsed case-insensitively, as stored in the database, and as the clap values crc, md5, sha1 and sha256
+#[derive(Copy, Clone, Debug, Display, PartialEq, EnumString, IntoStaticStr, ValueEnum)]
 pub enum HashType {
     #[strum(ascii_case_insensitive)]
     Crc,
@@ -53,6 +140,8 @@ pub enum HashType {
     Md5,
     #[strum(ascii_case_insensitive)]
     Sha1,
+    #[strum(ascii_case_insensitive)]
+    Sha256,
 }
 
 // Define the ScannedFile struct
@@ -65,4 +154,12 @@ pub struct ScannedFile {
     pub match_type: MatchType,
     pub game_name: Option<String>,
     pub rom_name: Option<String>,
+    // size of the file after any header was stripped, unknown for files scanned before sizes were recorded
+    pub size: Option<u64>,
+    // number of header bytes skipped before hashing, unknown for files scanned before offsets were recorded
+    pub header_offset: Option<u64>,
+    // UTC times the path was first stored and last scanned or found unchanged, set by the database,
+    // unknown for files scanned before dates were recorded
+    pub first_seen: Option<String>,
+    pub last_scanned: Option<String>,
 }
@@ -0,0 +1,225 @@
+use crate::ui;
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use strum::Display;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable report
+    #[default]
+    Human,
+    /// A JSON array of objects
+    Json,
+    /// Comma separated values with a header row
+    Csv,
+    /// Tab separated values with a header row
+    Tsv,
+    /// Aligned columns with a header row
+    Table,
+}
+
+/// Fail for commands that only produce human readable output
+pub fn require_human(format: OutputFormat, command: &str) -> Result<()> {
+    if format == OutputFormat::Human {
+        Ok(())
+    } else {
+        Err(anyhow!("--format {} is not supported by {}, only human output is available", format, command))
+    }
+}
+
+/// Fail for commands that can produce a human readable report or a JSON document, but no columns
+pub fn require_human_or_json(format: OutputFormat, command: &str) -> Result<()> {
+    if format == OutputFormat::Human || format == OutputFormat::Json {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "--format {} is not supported by {}, only human and json output are available",
+            format,
+            command
+        ))
+    }
+}
+
+/// Where structured output is written: stdout or a file, optionally gzip compressed.
+///
+/// Output is compressed when asked for, or when the output file name ends in `.gz`.
+/// Call `finish` when done so the gzip trailer is written and errors are reported.
+pub enum OutputSink {
+    Plain(BufWriter<Box<dyn Write>>),
+    Gzip(Box<GzEncoder<BufWriter<Box<dyn Write>>>>),
+}
+
+impl OutputSink {
+    pub fn create(path: Option<&Utf8Path>, compress: bool) -> Result<Self> {
+        let (inner, compress): (Box<dyn Write>, bool) = match path {
+            Some(path) => {
+                let file = File::create(path).with_context(|| format!("Failed to create output file {}", path))?;
+                let gz_extension = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+                (Box::new(file), compress || gz_extension)
+            }
+            None => (Box::new(ui::ResultsOut), compress),
+        };
+        let writer = BufWriter::new(inner);
+        if compress {
+            Ok(OutputSink::Gzip(Box::new(GzEncoder::new(writer, Compression::default()))))
+        } else {
+            Ok(OutputSink::Plain(writer))
+        }
+    }
+
+    /// Plain output to stdout
+    pub fn stdout() -> Self {
+        OutputSink::Plain(BufWriter::new(Box::new(ui::ResultsOut)))
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let mut writer = match self {
+            OutputSink::Plain(writer) => writer,
+            OutputSink::Gzip(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputSink::Plain(writer) => writer.write(buf),
+            OutputSink::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputSink::Plain(writer) => writer.flush(),
+            OutputSink::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Rows of values under named columns, written out in any of the structured formats
+pub struct Table {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Table {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        debug_assert_eq!(row.len(), self.columns.len(), "row does not match the table columns");
+        self.rows.push(row);
+    }
+
+    /// Print the table to stdout, human output is the caller's job so it is treated like a table
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        let mut out = stdout().lock();
+        self.write(format, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn write(&self, format: OutputFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Json => self.write_json(out),
+            OutputFormat::Csv => self.write_separated(out, ',', csv_field),
+            OutputFormat::Tsv => self.write_separated(out, '\t', tsv_field),
+            OutputFormat::Human | OutputFormat::Table => self.write_aligned(out),
+        }
+    }
+
+    fn write_json(&self, out: &mut impl Write) -> Result<()> {
+        let objects: Vec<Value> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let object: Map<String, Value> = self
+                    .columns
+                    .iter()
+                    .map(|column| (*column).to_owned())
+                    .zip(row.iter().cloned())
+                    .collect();
+                Value::Object(object)
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut *out, &objects)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn write_separated(&self, out: &mut impl Write, separator: char, field: fn(&str) -> String) -> Result<()> {
+        let header: Vec<String> = self.columns.iter().map(|column| field(column)).collect();
+        writeln!(out, "{}", header.join(&separator.to_string()))?;
+        for row in &self.rows {
+            let fields: Vec<String> = row.iter().map(|value| field(&plain_text(value))).collect();
+            writeln!(out, "{}", fields.join(&separator.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn write_aligned(&self, out: &mut impl Write) -> Result<()> {
+        let rows: Vec<Vec<String>> = self.rows.iter().map(|row| row.iter().map(plain_text).collect()).collect();
+        let mut widths: Vec<usize> = self.columns.iter().map(|column| column.chars().count()).collect();
+        for row in &rows {
+            for (width, text) in widths.iter_mut().zip(row) {
+                *width = (*width).max(text.chars().count());
+            }
+        }
+
+        let header: Vec<String> = self.columns.iter().map(|column| column.to_uppercase()).collect();
+        write_aligned_row(out, &header, &widths)?;
+        for row in &rows {
+            write_aligned_row(out, row, &widths)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_aligned_row(out: &mut impl Write, row: &[String], widths: &[usize]) -> Result<()> {
+    let cells: Vec<String> = row
+        .iter()
+        .zip(widths)
+        .map(|(text, width)| format!("{:<width$}", text, width = width))
+        .collect();
+    writeln!(out, "{}", cells.join("  ").trim_end())?;
+    Ok(())
+}
+
+// text of a value without JSON quoting, missing values are empty
+fn plain_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+// quote a CSV field if it contains anything that would break the row
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_owned()
+    }
+}
+
+// TSV has no quoting, so escape the characters that would break the row
+fn tsv_field(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
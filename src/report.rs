@@ -0,0 +1,868 @@
+use anyhow::Result;
+use camino::Utf8Path;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use crate::models::{HashType, Rom};
+use crate::output::{OutputFormat, OutputSink, Table};
+use crate::ui::{self, Color};
+
+/// How a file compared with the database, or what was done to it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// Matches a rom by hash, and by name (or size with --match-by hash)
+    Ok,
+    /// Matches one or more roms by hash, but has the wrong name
+    Name,
+    /// Matches no rom
+    Miss,
+    /// Matches roms by hash, but not their size
+    Size,
+    /// Has a different hash than the one recorded in the database
+    Hash,
+    /// Is not recorded in the database
+    New,
+    /// Is recorded in the database, but no longer exists
+    Gone,
+    /// Is recorded in the database, but was found under another name
+    Move,
+    /// Was renamed and verified to match the rom
+    Fixed,
+    /// Was renamed, but failed verification
+    FixFailed,
+    /// Would have been renamed, but --dry-run was given
+    Would,
+    /// Was not renamed, as another file is already where it would go
+    Skip,
+    /// An empty directory that was removed
+    Prune,
+}
+
+/// A rom matched by a file
+#[derive(Clone, Debug, Serialize)]
+pub struct RomReport {
+    pub game: String,
+    pub rom: String,
+    pub matched_by: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_size: Option<i64>,
+    // the game name without its region and revision tags, for telling variants apart when a file matches several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_title: Option<String>,
+}
+
+impl RomReport {
+    pub fn new(game: &str, rom: &str, matched_by: &[HashType]) -> Self {
+        RomReport {
+            game: game.to_owned(),
+            rom: rom.to_owned(),
+            matched_by: matched_by
+                .iter()
+                .map(|hash_type| hash_type.to_string().to_lowercase())
+                .collect(),
+            expected_size: None,
+            base_title: None,
+        }
+    }
+}
+
+/// The result for a single file, with paths relative to the directory being processed
+#[derive(Clone, Debug, Serialize)]
+pub struct FileReport {
+    pub status: FileStatus,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<RomReport>,
+    // which hash matched is only worth showing in text when more than one was searched
+    #[serde(skip)]
+    pub show_provenance: bool,
+    // when the file was first seen and last scanned, for listings that asked for them
+    #[serde(skip)]
+    pub dates: Option<(Option<String>, Option<String>)>,
+    // matched by the hash of the whole file, after the hash without its header matched nothing
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub with_header: bool,
+}
+
+impl FileReport {
+    pub fn new(status: FileStatus, path: &str, hash: Option<&str>) -> Self {
+        FileReport {
+            status,
+            path: path.to_owned(),
+            hash: hash.map(str::to_owned),
+            size: None,
+            expected_hash: None,
+            renamed_to: None,
+            error: None,
+            matches: Vec::new(),
+            show_provenance: false,
+            dates: None,
+            with_header: false,
+        }
+    }
+
+    fn write(&self, out: &mut dyn Write, colored: bool) -> std::io::Result<()> {
+        let hash = self.hash.as_deref().unwrap_or_default();
+        let renamed_to = self.renamed_to.as_deref().unwrap_or_default();
+        match self.status {
+            FileStatus::Ok if self.matches.is_empty() => writeln!(out, "{} {} {}", tag("OK  ", colored), hash, self.path)?,
+            FileStatus::Ok => {
+                for matched in &self.matches {
+                    writeln!(
+                        out,
+                        "{} {} {}{}\n------ Rom: {} Game: {}",
+                        tag("OK  ", colored),
+                        hash,
+                        self.path,
+                        self.provenance(matched),
+                        matched.rom,
+                        matched.game
+                    )?;
+                }
+            }
+            FileStatus::Name => match self.matches.as_slice() {
+                [matched] => writeln!(
+                    out,
+                    "{} {} {}{}\n------ Rom: {} Game: {}",
+                    tag("NAME", colored),
+                    hash,
+                    self.path,
+                    self.provenance(matched),
+                    matched.rom,
+                    matched.game,
+                )?,
+                matches => {
+                    writeln!(out, "{} {} {}", tag("NAME", colored), hash, self.path)?;
+                    //variants of the same game come together, under their title
+                    for group in matches.chunk_by(|a, b| a.base_title.is_some() && a.base_title == b.base_title) {
+                        let indent = if let [first, _, ..] = group {
+                            let title = first.base_title.as_deref().unwrap_or_default();
+                            writeln!(out, "------ Title: {} ({} variants)", title, group.len())?;
+                            "  "
+                        } else {
+                            ""
+                        };
+                        for matched in group {
+                            writeln!(
+                                out,
+                                "------ {}Rom: {} Game: {}{}",
+                                indent,
+                                matched.rom,
+                                matched.game,
+                                self.provenance(matched)
+                            )?;
+                        }
+                    }
+                }
+            },
+            FileStatus::Miss => writeln!(out, "{} {} {}", tag("MISS", colored), hash, self.path)?,
+            FileStatus::Size => {
+                writeln!(out, "{} {} {} (Size: {})", tag("SIZE", colored), hash, self.path, self.size.unwrap_or_default())?;
+                for matched in &self.matches {
+                    writeln!(
+                        out,
+                        "------ Rom: {} Game: {} (Expected size: {}){}",
+                        matched.rom,
+                        matched.game,
+                        matched.expected_size.unwrap_or_default(),
+                        self.provenance(matched)
+                    )?;
+                }
+            }
+            FileStatus::Hash => writeln!(
+                out,
+                "{} {} {} (Expected: {})",
+                tag("HASH", colored),
+                hash,
+                self.path,
+                self.expected_hash.as_deref().unwrap_or_default()
+            )?,
+            FileStatus::New => writeln!(out, "{} {}", tag("NEW ", colored), self.path)?,
+            FileStatus::Gone => writeln!(out, "{} {} {}", tag("GONE", colored), hash, self.path)?,
+            FileStatus::Move => writeln!(out, "{} {} {}", tag("MOVE", colored), hash, self.path)?,
+            FileStatus::Fixed => writeln!(out, "{} {} -> {}", tag("FIXED", colored), self.path, renamed_to)?,
+            FileStatus::FixFailed => writeln!(
+                out,
+                "{} {} -> {} ({})",
+                tag("FIX-FAILED", colored),
+                self.path,
+                renamed_to,
+                self.error.as_deref().unwrap_or_default()
+            )?,
+            FileStatus::Would => writeln!(out, "{} {} -> {}", tag("WOULD", colored), self.path, renamed_to)?,
+            FileStatus::Skip => {
+                writeln!(out, "{} {} -> {} (A file is already there)", tag("SKIP", colored), self.path, renamed_to)?
+            }
+            FileStatus::Prune => writeln!(out, "{} {}", tag("PRUNE", colored), self.path)?,
+        }
+        if self.with_header {
+            writeln!(out, "------ Matched with its header")?;
+        }
+        if let Some((first_seen, last_scanned)) = &self.dates {
+            writeln!(
+                out,
+                "------ First seen: {} Last scanned: {}",
+                first_seen.as_deref().unwrap_or("unknown"),
+                last_scanned.as_deref().unwrap_or("unknown")
+            )?;
+        }
+        Ok(())
+    }
+
+    // which hashes matched, e.g. " (crc, sha1)", or nothing if there is nothing to report
+    fn provenance(&self, matched: &RomReport) -> String {
+        if self.show_provenance && !matched.matched_by.is_empty() {
+            format!(" ({})", matched.matched_by.join(", "))
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// The files found for a game's roms while processing a directory
+#[derive(Default)]
+pub struct GameStatus {
+    pub roms: Vec<Rom>,
+    pub exact_matches: BTreeMap<String, BTreeSet<String>>,
+    pub partial_matches: BTreeMap<String, BTreeSet<String>>,
+}
+
+// how well the files found for a game cover its roms
+#[derive(Debug, PartialEq, Eq)]
+enum GameClass<'a> {
+    // every rom has a file with the right name, duplicates lists roms matched by more than one file
+    Full {
+        exact: usize,
+        empty: usize,
+        duplicates: Vec<(&'a str, &'a BTreeSet<String>)>,
+    },
+    // some roms are misnamed or missing
+    Partial {
+        exact: usize,
+        partial: usize,
+        empty: usize,
+        missing: Vec<&'a str>,
+    },
+    // nothing worth reporting, only misnamed files for some of the roms
+    Unmatched,
+}
+
+impl GameStatus {
+    // each rom is counted once: exact if any file matched it exactly, otherwise partial if any file
+    // matched it by hash alone, otherwise missing, so a rom matched by several files never skews the counts.
+    // empty roms without a file are counted apart from the missing, as there is often no file kept for them
+    fn classify(&self) -> GameClass<'_> {
+        let roms: BTreeMap<&str, &Rom> = self.roms.iter().map(|rom| (rom.name.as_str(), rom)).collect();
+        let mut exact = 0;
+        let mut partial = 0;
+        let mut empty = 0;
+        let mut missing = Vec::new();
+        for (rom_name, rom) in &roms {
+            if self.exact_matches.contains_key(*rom_name) {
+                exact += 1;
+            } else if self.partial_matches.contains_key(*rom_name) {
+                partial += 1;
+            } else if rom.is_empty() {
+                empty += 1;
+            } else {
+                missing.push(*rom_name);
+            }
+        }
+
+        if exact + empty == roms.len() {
+            let duplicates = self
+                .exact_matches
+                .iter()
+                .filter(|(_, filenames)| filenames.len() > 1)
+                .map(|(rom_name, filenames)| (rom_name.as_str(), filenames))
+                .collect();
+            GameClass::Full {
+                exact,
+                empty,
+                duplicates,
+            }
+        } else if exact > 0 || missing.is_empty() {
+            //only count the game as matched if we have at least one exact match or all the roms are matched
+            GameClass::Partial {
+                exact,
+                partial,
+                empty,
+                missing,
+            }
+        } else {
+            GameClass::Unmatched
+        }
+    }
+}
+
+// how many empty roms a game has no file for, e.g. " (2 empty rom(s) without a file)", or nothing if there are none
+fn empty_note(empty: usize) -> String {
+    if empty > 0 {
+        format!(" ({} empty rom(s) without a file)", empty)
+    } else {
+        String::new()
+    }
+}
+
+// a misnamed file and the rom name it should have
+#[derive(Serialize)]
+struct MisnamedFile {
+    file: String,
+    expected: String,
+}
+
+// a matched game in the summary, with the same data as the text report
+#[derive(Serialize)]
+struct GameReport {
+    game: String,
+    status: &'static str,
+    exact: usize,
+    partial: usize,
+    empty: usize,
+    missing: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    duplicates: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    misnamed: Vec<MisnamedFile>,
+}
+
+fn game_reports(found_games: &BTreeMap<String, GameStatus>) -> Vec<GameReport> {
+    let mut reports = Vec::new();
+    for (game_name, status) in found_games {
+        match status.classify() {
+            GameClass::Full {
+                exact,
+                empty,
+                duplicates,
+            } => reports.push(GameReport {
+                game: game_name.clone(),
+                status: "full",
+                exact,
+                partial: 0,
+                empty,
+                missing: Vec::new(),
+                duplicates: duplicates
+                    .into_iter()
+                    .map(|(rom_name, filenames)| (rom_name.to_owned(), filenames.iter().cloned().collect()))
+                    .collect(),
+                misnamed: Vec::new(),
+            }),
+            GameClass::Partial {
+                exact,
+                partial,
+                empty,
+                missing,
+            } => reports.push(GameReport {
+                game: game_name.clone(),
+                status: "partial",
+                exact,
+                partial,
+                empty,
+                missing: missing.into_iter().map(str::to_owned).collect(),
+                duplicates: BTreeMap::new(),
+                misnamed: status
+                    .partial_matches
+                    .iter()
+                    .flat_map(|(expected, filenames)| {
+                        filenames.iter().map(|filename| MisnamedFile {
+                            file: filename.clone(),
+                            expected: expected.clone(),
+                        })
+                    })
+                    .collect(),
+            }),
+            GameClass::Unmatched => {}
+        }
+    }
+    reports
+}
+
+/// The worst result a report saw, which the process exits with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// Every file matched exactly and every game found is complete
+    #[default]
+    Complete,
+    /// Files that are new, moved or would be renamed, which only count against the collection with --strict
+    Unsettled,
+    /// Files with no match or the wrong name, or games with roms missing
+    Incomplete,
+    /// Files whose hash or size is not what was expected, that are gone, or failed to fix
+    Mismatched,
+}
+
+impl Outcome {
+    /// 0 when complete, 2 when incomplete and 3 when mismatched, 1 being left for errors
+    pub fn exit_code(self, strict: bool) -> u8 {
+        match self {
+            Outcome::Complete => 0,
+            Outcome::Unsettled if !strict => 0,
+            Outcome::Unsettled | Outcome::Incomplete => 2,
+            Outcome::Mismatched => 3,
+        }
+    }
+
+    fn of_file(status: FileStatus) -> Self {
+        match status {
+            FileStatus::Ok | FileStatus::Fixed | FileStatus::Prune => Outcome::Complete,
+            FileStatus::New | FileStatus::Move | FileStatus::Would | FileStatus::Skip => Outcome::Unsettled,
+            FileStatus::Name | FileStatus::Miss => Outcome::Incomplete,
+            FileStatus::Size | FileStatus::Hash | FileStatus::Gone | FileStatus::FixFailed => Outcome::Mismatched,
+        }
+    }
+}
+
+// overall counts, printed after everything else as an at-a-glance health check
+#[derive(Default, Serialize)]
+struct Summary {
+    // only known once the found games are reported, which not every command does
+    #[serde(skip_serializing_if = "Option::is_none")]
+    games: Option<GameTally>,
+    files: FileTally,
+    #[serde(skip)]
+    outcome: Outcome,
+}
+
+#[derive(Default, Serialize)]
+struct GameTally {
+    full: usize,
+    partial: usize,
+    // files were found for some roms, but none of them had the right name
+    missing: usize,
+}
+
+#[derive(Default, Serialize)]
+struct FileTally {
+    exact: usize,
+    named: usize,
+    miss: usize,
+}
+
+impl Summary {
+    fn count_file(&mut self, status: FileStatus) {
+        self.outcome = self.outcome.max(Outcome::of_file(status));
+        match status {
+            FileStatus::Ok => self.files.exact += 1,
+            FileStatus::Name => self.files.named += 1,
+            FileStatus::Miss => self.files.miss += 1,
+            _ => {}
+        }
+    }
+
+    fn count_games(&mut self, found_games: &BTreeMap<String, GameStatus>) {
+        let games = self.games.get_or_insert_with(GameTally::default);
+        for status in found_games.values() {
+            match status.classify() {
+                GameClass::Full { .. } => games.full += 1,
+                GameClass::Partial { .. } => {
+                    games.partial += 1;
+                    self.outcome = self.outcome.max(Outcome::Incomplete);
+                }
+                GameClass::Unmatched => {
+                    games.missing += 1;
+                    self.outcome = self.outcome.max(Outcome::Incomplete);
+                }
+            }
+        }
+    }
+
+    fn write(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let files = format!("Files: {} exact, {} named, {} miss", self.files.exact, self.files.named, self.files.miss);
+        match &self.games {
+            Some(games) => {
+                writeln!(out, "\nGames: {} full, {} partial, {} missing; {}", games.full, games.partial, games.missing, files)
+            }
+            None => writeln!(out, "\n{}", files),
+        }
+    }
+}
+
+// the bracketed status tag, colored by what it means for the collection when color is on and wanted
+fn tag(label: &str, colored: bool) -> String {
+    let tag = format!("[{}]", label);
+    if !colored {
+        return tag;
+    }
+    let color = match label.trim_end() {
+        "OK" | "FULL" | "FIXED" => Color::Green,
+        "NAME" | "PART" => Color::Yellow,
+        "MISS" | "GONE" | "HASH" | "SIZE" | "FIX-FAILED" => Color::Red,
+        "NEW" | "MOVE" => Color::Blue,
+        _ => return tag,
+    };
+    ui::paint(&tag, color)
+}
+
+/// How much of the text report to print
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only problems: no directory banners, exact matches or complete games
+    Quiet,
+    #[default]
+    Normal,
+}
+
+/// What the text report prints, the structured formats always have everything
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReportOptions {
+    pub verbosity: Verbosity,
+    /// List at most this many missing roms for each partly matched game, then how many more there are
+    pub max_missing: Option<usize>,
+    /// Only print the headline of each game, without its misnamed, missing and duplicated roms
+    pub summary_only: bool,
+}
+
+/// Receives file results as they are produced and the found games at the end, so that the
+/// text report and the structured formats are built from the same data
+pub trait Reporter {
+    /// A progress line such as the directory being processed, only part of the text report
+    fn info(&self, line: &str);
+    fn file(&self, report: FileReport);
+    /// Count a result in the summary without reporting it, for results that are not displayed
+    fn count(&self, status: FileStatus);
+    fn games(&self, found_games: &BTreeMap<String, GameStatus>);
+    /// Write out the summary and anything held back, returning the worst result reported
+    fn finish(&self) -> Result<Outcome>;
+}
+
+/// Create the reporter for the output format, writing to stdout. The options only apply to the text report
+pub fn create(format: OutputFormat, options: ReportOptions) -> Box<dyn Reporter> {
+    with_sink(format, options, OutputSink::stdout(), true)
+}
+
+/// Create the reporter for the output format, writing to the output file if there is one. Only the results
+/// go to the file, progress, debug output and errors stay on the terminal
+pub fn create_for_output(format: OutputFormat, options: ReportOptions, output: Option<&Utf8Path>) -> Result<Box<dyn Reporter>> {
+    match output {
+        //colors are for the terminal, not a file
+        Some(path) => Ok(with_sink(format, options, OutputSink::create(Some(path), false)?, false)),
+        None => Ok(create(format, options)),
+    }
+}
+
+fn with_sink(format: OutputFormat, options: ReportOptions, out: OutputSink, colored: bool) -> Box<dyn Reporter> {
+    if format == OutputFormat::Human {
+        Box::new(TextReporter {
+            options,
+            summary: RefCell::default(),
+            out: RefCell::new(Some(out)),
+            colored,
+            error: RefCell::default(),
+        })
+    } else {
+        Box::new(StructuredReporter {
+            format,
+            files: RefCell::default(),
+            games: RefCell::default(),
+            summary: RefCell::default(),
+            out: RefCell::new(Some(out)),
+        })
+    }
+}
+
+// prints each result as soon as it is known
+struct TextReporter {
+    options: ReportOptions,
+    summary: RefCell<Summary>,
+    // taken when finished
+    out: RefCell<Option<OutputSink>>,
+    colored: bool,
+    // the first failure to write, returned from finish as reporting a result has nowhere to return it
+    error: RefCell<Option<std::io::Error>>,
+}
+
+impl TextReporter {
+    fn quiet(&self) -> bool {
+        self.options.verbosity == Verbosity::Quiet
+    }
+
+    // flushed every time, so results show as they happen even when the sink is buffered
+    fn write(&self, write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) {
+        if self.error.borrow().is_some() {
+            return;
+        }
+        if let Some(out) = self.out.borrow_mut().as_mut() {
+            if let Err(e) = write(out).and_then(|()| out.flush()) {
+                *self.error.borrow_mut() = Some(e);
+            }
+        }
+    }
+
+    fn write_games(&self, out: &mut dyn Write, found_games: &BTreeMap<String, GameStatus>) -> std::io::Result<()> {
+        let colored = self.colored;
+        if !self.quiet() {
+            writeln!(out, "\nFound Games:")?;
+        }
+        for (game_name, status) in found_games {
+            match status.classify() {
+                GameClass::Full { .. } if self.quiet() => {}
+                GameClass::Full { empty, duplicates, .. } => {
+                    writeln!(out, "{} {}{}", tag("FULL", colored), game_name, empty_note(empty))?;
+                    if self.options.summary_only {
+                        continue;
+                    }
+                    for (rom_name, filenames) in duplicates {
+                        for filename in filenames {
+                            writeln!(out, "{}   {} (File: {})", tag("DUPE", colored), rom_name, filename)?;
+                        }
+                    }
+                }
+                GameClass::Partial {
+                    exact,
+                    partial,
+                    empty,
+                    missing,
+                } => {
+                    writeln!(
+                        out,
+                        "{} {} ({} exact matches, {} partial matches. {} missing){}",
+                        tag("PART", colored),
+                        game_name,
+                        exact,
+                        partial,
+                        missing.len(),
+                        empty_note(empty)
+                    )?;
+                    if self.options.summary_only {
+                        continue;
+                    }
+                    for (expected, partial_match) in &status.partial_matches {
+                        for filename in partial_match {
+                            writeln!(out, "{}   {} (Expected: {})", tag("NAME", colored), filename, expected)?;
+                        }
+                    }
+                    //missing roms are in name order, so the same ones are listed every time
+                    let listed = self.options.max_missing.unwrap_or(missing.len()).min(missing.len());
+                    for rom_name in &missing[..listed] {
+                        writeln!(out, "{}   {}", tag("MISS", colored), rom_name)?;
+                    }
+                    if listed < missing.len() {
+                        writeln!(out, "{}   (... and {} more)", tag("MISS", colored), missing.len() - listed)?;
+                    }
+                }
+                GameClass::Unmatched => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Reporter for TextReporter {
+    fn info(&self, line: &str) {
+        if !self.quiet() {
+            self.write(|out| writeln!(out, "{}", line));
+        }
+    }
+
+    fn file(&self, report: FileReport) {
+        self.summary.borrow_mut().count_file(report.status);
+        if self.quiet() && report.status == FileStatus::Ok {
+            return;
+        }
+        self.write(|out| report.write(out, self.colored));
+    }
+
+    fn count(&self, status: FileStatus) {
+        self.summary.borrow_mut().count_file(status);
+    }
+
+    fn games(&self, found_games: &BTreeMap<String, GameStatus>) {
+        self.summary.borrow_mut().count_games(found_games);
+        self.write(|out| self.write_games(out, found_games));
+    }
+
+    fn finish(&self) -> Result<Outcome> {
+        self.write(|out| self.summary.borrow().write(out));
+        if let Some(e) = self.error.take() {
+            return Err(anyhow::Error::new(e).context("Failed to write report"));
+        }
+        if let Some(out) = self.out.take() {
+            out.finish()?;
+        }
+        Ok(self.summary.borrow().outcome)
+    }
+}
+
+// collects everything and writes it out once done: JSON as a single document with the files and games,
+// the column formats only have room for the games summary
+struct StructuredReporter {
+    format: OutputFormat,
+    files: RefCell<Vec<FileReport>>,
+    games: RefCell<Vec<GameReport>>,
+    summary: RefCell<Summary>,
+    // taken when finished
+    out: RefCell<Option<OutputSink>>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    files: &'a [FileReport],
+    games: &'a [GameReport],
+    summary: &'a Summary,
+}
+
+impl Reporter for StructuredReporter {
+    fn info(&self, _line: &str) {}
+
+    fn file(&self, report: FileReport) {
+        self.summary.borrow_mut().count_file(report.status);
+        self.files.borrow_mut().push(report);
+    }
+
+    fn count(&self, status: FileStatus) {
+        self.summary.borrow_mut().count_file(status);
+    }
+
+    fn games(&self, found_games: &BTreeMap<String, GameStatus>) {
+        self.summary.borrow_mut().count_games(found_games);
+        self.games.borrow_mut().extend(game_reports(found_games));
+    }
+
+    fn finish(&self) -> Result<Outcome> {
+        let outcome = self.summary.borrow().outcome;
+        let Some(mut out) = self.out.take() else {
+            return Ok(outcome);
+        };
+        let files = self.files.take();
+        let games = self.games.take();
+        if self.format == OutputFormat::Json {
+            serde_json::to_writer_pretty(
+                &mut out,
+                &Document {
+                    files: &files,
+                    games: &games,
+                    summary: &self.summary.borrow(),
+                },
+            )?;
+            writeln!(out)?;
+            out.finish()?;
+            return Ok(outcome);
+        }
+
+        let mut table = Table::new(&["game", "status", "exact", "partial", "missing"]);
+        for game in games {
+            table.push(vec![
+                game.game.into(),
+                game.status.into(),
+                game.exact.into(),
+                game.partial.into(),
+                game.missing.len().into(),
+            ]);
+        }
+        table.write(self.format, &mut out)?;
+        out.finish()?;
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::rom;
+
+    fn files(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    // a game of two roms, a.bin and b.bin
+    fn game() -> GameStatus {
+        GameStatus {
+            roms: vec![rom("a.bin", "11111111"), rom("b.bin", "22222222")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exactly_full_game_has_no_duplicates() {
+        let mut status = game();
+        status.exact_matches.insert("a.bin".into(), files(&["/roms/a.bin"]));
+        status.exact_matches.insert("b.bin".into(), files(&["/roms/b.bin"]));
+        assert_eq!(
+            status.classify(),
+            GameClass::Full {
+                exact: 2,
+                empty: 0,
+                duplicates: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn full_game_lists_roms_matched_by_several_files() {
+        let mut status = game();
+        let copies = files(&["/roms/a.bin", "/roms/copy/a.bin"]);
+        status.exact_matches.insert("a.bin".into(), copies.clone());
+        status.exact_matches.insert("b.bin".into(), files(&["/roms/b.bin"]));
+        //a misnamed copy of a rom already matched does not make the game partial
+        status.partial_matches.insert("b.bin".into(), files(&["/roms/b (1).bin"]));
+        assert_eq!(
+            status.classify(),
+            GameClass::Full {
+                exact: 2,
+                empty: 0,
+                duplicates: vec![("a.bin", &copies)],
+            }
+        );
+    }
+
+    #[test]
+    fn partial_game_counts_each_rom_once() {
+        let mut status = game();
+        status.roms.push(rom("c.bin", "33333333"));
+        status
+            .exact_matches
+            .insert("a.bin".into(), files(&["/roms/a.bin", "/roms/copy/a.bin"]));
+        status
+            .partial_matches
+            .insert("b.bin".into(), files(&["/roms/x.bin", "/roms/y.bin"]));
+        assert_eq!(
+            status.classify(),
+            GameClass::Partial {
+                exact: 1,
+                partial: 1,
+                empty: 0,
+                missing: vec!["c.bin"],
+            }
+        );
+    }
+
+    #[test]
+    fn misnamed_files_alone_leave_a_game_unmatched() {
+        let mut status = game();
+        status.partial_matches.insert("a.bin".into(), files(&["/roms/x.bin"]));
+        assert_eq!(status.classify(), GameClass::Unmatched);
+    }
+
+    #[test]
+    fn empty_roms_without_a_file_leave_a_game_full() {
+        let mut status = game();
+        status.roms.push(Rom {
+            size: 0,
+            crc: None,
+            ..rom("track02.bin", "")
+        });
+        status.exact_matches.insert("a.bin".into(), files(&["/roms/a.bin"]));
+        status.exact_matches.insert("b.bin".into(), files(&["/roms/b.bin"]));
+        assert_eq!(
+            status.classify(),
+            GameClass::Full {
+                exact: 2,
+                empty: 1,
+                duplicates: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Helpers shared by the unit tests
+
+use crate::database::Database;
+use crate::models::{Game, HashType, MatchType, Rom, ScannedFile};
+use crate::output::OutputFormat;
+use crate::ui::{self, ColorChoice};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// confirmations are answered yes, as with --yes, and no progress is drawn. The first call fixes this for the whole
+// run, so it is made before anything a test does can ask
+fn init_ui() {
+    ui::init(true, true, ColorChoice::Never, OutputFormat::Json);
+}
+
+/// An empty database at the current schema, held in memory
+pub fn memory_database() -> Database {
+    init_ui();
+    let mut db = Database::new(Utf8Path::new(":memory:")).expect("should open an in-memory database");
+    db.initialize(false).expect("should initialize the database");
+    db
+}
+
+/// A DAT of three games, two with roms and one with a disk
+pub const DAT: &str = r#"<?xml version="1.0"?>
+<datafile>
+	<header>
+		<name>Test</name>
+		<description>Test DAT</description>
+		<version>1</version>
+	</header>
+	<game name="Game A">
+		<description>Game A (Europe)</description>
+		<rom name="a.bin" size="16" crc="11111111"/>
+		<rom name="b.bin" size="16" crc="22222222" sha1="0000000000000000000000000000000000000001"/>
+	</game>
+	<!-- a game whose roms are all on a disk -->
+	<game name="Game B">
+		<description>Game B</description>
+		<disk name="disc" sha1="0000000000000000000000000000000000000002"/>
+	</game>
+	<game name="Game C">
+		<description>Game C</description>
+		<rom name="c.bin" size="0"/>
+	</game>
+</datafile>
+"#;
+
+/// A database at the current schema holding the games, held in memory
+pub fn database_with(games: Vec<Game>) -> Database {
+    let mut db = memory_database();
+    let merge = db.begin_merge("test").expect("should start a merge");
+    for game in games {
+        merge.game(game).expect("should merge the game");
+    }
+    merge.finish().expect("should finish the merge");
+    db
+}
+
+/// A 16 byte rom known only by its CRC
+pub fn rom(name: &str, crc: &str) -> Rom {
+    Rom {
+        name: name.to_owned(),
+        size: 16,
+        crc: Some(crc.to_owned()),
+        md5: None,
+        sha1: None,
+        sha256: None,
+    }
+}
+
+/// A game of the roms, described by its name
+pub fn game(name: &str, roms: Vec<Rom>) -> Game {
+    Game {
+        name: name.to_owned(),
+        description: name.to_owned(),
+        roms,
+        disks: Vec::new(),
+        source: None,
+    }
+}
+
+/// A file that matched nothing, scanned from the directory it is in
+pub fn unmatched_file(path: &str) -> ScannedFile {
+    let base_path = Utf8Path::new(path).parent().expect("should have a parent directory");
+    ScannedFile {
+        base_path: base_path.as_str().to_owned(),
+        path: path.to_owned(),
+        hash: String::from("00000000"),
+        hash_type: HashType::Crc,
+        match_type: MatchType::None,
+        game_name: None,
+        rom_name: None,
+        size: Some(0),
+        header_offset: Some(0),
+        first_seen: None,
+        last_scanned: None,
+    }
+}
+
+/// A new empty directory under the system temporary directory, removed when dropped
+pub struct TempDir {
+    path: Utf8PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("rcr2-test-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+        let path = Utf8PathBuf::try_from(std::env::temp_dir().join(name)).expect("temporary directory should be UTF-8");
+        fs::create_dir_all(&path).expect("should create the temporary directory");
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::output::OutputFormat;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::env;
+use std::io::{self, stderr, stdin, stdout, BufRead, IsTerminal, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the progress line is allowed to redraw
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+// longest item text drawn on the progress line, longer items keep their tail
+const MAX_ITEM_WIDTH: usize = 60;
+
+/// When to color output
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set or `TERM=dumb`
+    #[default]
+    Auto,
+    /// Always color, even when output is piped
+    Always,
+    /// Never color
+    Never,
+}
+
+/// Colors for the status tags in results
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+    Blue,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Policy {
+    progress: bool,
+    color: bool,
+    color_stdout: bool,
+    interactive: bool,
+    assume_yes: bool,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+static PROGRESS: Mutex<Option<ProgressState>> = Mutex::new(None);
+static ANSWERS: Mutex<Answers> = Mutex::new(Answers { all: false, quit: false });
+// lines read from stdin on a thread of their own so waiting for an answer can time out, None once stdin is closed
+static STDIN_LINES: OnceLock<Mutex<Receiver<Option<String>>>> = OnceLock::new();
+
+// remembered "all" and "quit" answers so a batch of confirmations can be answered once
+struct Answers {
+    all: bool,
+    quit: bool,
+}
+
+// what came of waiting for a line of input
+enum Answer {
+    Line(String),
+    Closed,
+    TimedOut,
+}
+
+struct ProgressState {
+    label: &'static str,
+    count: u64,
+    last_draw: Option<Instant>,
+    drawn: bool,
+}
+
+/// Decide once at startup whether interactive output (progress, color) may be used.
+///
+/// Progress is drawn on stderr only when stderr is a terminal and the results are human readable, so it never
+/// ends up in log files or next to structured output. Results written through `ResultsOut` remove the progress
+/// line first, so the two do not run together when stdout is the same terminal.
+/// `TERM=dumb` disables both progress and color, `NO_COLOR` disables color, and a `color` other than
+/// auto overrides both for color.
+/// Destructive operations prompt for confirmation when stdin is a terminal, unless `assume_yes` is set.
+pub fn init(no_progress: bool, assume_yes: bool, color: ColorChoice, format: OutputFormat) {
+    let dumb_terminal = env::var("TERM").is_ok_and(|term| term == "dumb");
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let stderr_tty = stderr().is_terminal();
+    let stdout_tty = stdout().is_terminal();
+    let color_on = |tty: bool| match color {
+        ColorChoice::Auto => !no_color && !dumb_terminal && tty,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+
+    let policy = Policy {
+        progress: !no_progress && !dumb_terminal && stderr_tty && format == OutputFormat::Human,
+        color: color_on(stderr_tty),
+        color_stdout: color_on(stdout_tty),
+        interactive: stdin().is_terminal(),
+        assume_yes,
+    };
+    // first initialization wins
+    let _ = POLICY.set(policy);
+}
+
+fn policy() -> Policy {
+    *POLICY.get_or_init(|| Policy {
+        progress: false,
+        color: false,
+        color_stdout: false,
+        interactive: false,
+        assume_yes: false,
+    })
+}
+
+/// The text in the color when results on stdout are colored, otherwise unchanged
+pub fn paint(text: &str, color: Color) -> String {
+    if !policy().color_stdout {
+        return text.to_owned();
+    }
+    let code = match color {
+        Color::Red => 31,
+        Color::Green => 32,
+        Color::Yellow => 33,
+        Color::Blue => 34,
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Start a progress counter with the given label, replacing any previous one
+pub fn progress_start(label: &'static str) {
+    if !policy().progress {
+        return;
+    }
+    if let Ok(mut progress) = PROGRESS.lock() {
+        *progress = Some(ProgressState {
+            label,
+            count: 0,
+            last_draw: None,
+            drawn: false,
+        });
+    }
+}
+
+/// Count one processed item and redraw the progress line if enough time has passed
+pub fn progress_tick(item: &str) {
+    if !policy().progress {
+        return;
+    }
+    let Ok(mut progress) = PROGRESS.lock() else {
+        return;
+    };
+    if let Some(state) = progress.as_mut() {
+        state.count += 1;
+        if state.last_draw.is_some_and(|last| last.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        state.last_draw = Some(Instant::now());
+        state.drawn = true;
+
+        let item = truncate_left(item, MAX_ITEM_WIDTH);
+        let line = format!("{} {} file(s): {}", state.label, state.count, item);
+        let mut err = stderr().lock();
+        if policy().color {
+            let _ = write!(err, "\r\x1b[2K\x1b[2m{}\x1b[0m", line);
+        } else {
+            let _ = write!(err, "\r\x1b[2K{}", line);
+        }
+        let _ = err.flush();
+    }
+}
+
+/// Remove the progress line, if one was drawn, and stop counting
+pub fn progress_finish() {
+    let Ok(mut progress) = PROGRESS.lock() else {
+        return;
+    };
+    if let Some(state) = progress.take() {
+        if state.drawn {
+            clear_progress_line();
+        }
+    }
+}
+
+/// Stdout for results, which removes the progress line before writing so results never start part way along it.
+/// Progress is drawn again below them once it is due
+pub struct ResultsOut;
+
+impl Write for ResultsOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        hide_progress();
+        stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        stdout().flush()
+    }
+}
+
+// remove the progress line, if one is drawn, leaving it to be drawn again once the redraw interval has passed
+fn hide_progress() {
+    if !policy().progress {
+        return;
+    }
+    let Ok(mut progress) = PROGRESS.lock() else {
+        return;
+    };
+    if let Some(state) = progress.as_mut().filter(|state| state.drawn) {
+        state.drawn = false;
+        clear_progress_line();
+    }
+}
+
+fn clear_progress_line() {
+    let mut err = stderr().lock();
+    let _ = write!(err, "\r\x1b[2K");
+    let _ = err.flush();
+}
+
+/// Fail unless a destructive operation can be confirmed, either interactively or by `--yes`
+pub fn require_confirmation(operation: &str) -> Result<()> {
+    let policy = policy();
+    if policy.assume_yes || policy.interactive {
+        Ok(())
+    } else {
+        Err(anyhow!("Refusing to {} without --yes when not running interactively", operation))
+    }
+}
+
+/// Ask whether to go ahead with a destructive action, reading the answer from stdin.
+///
+/// Answering `a` accepts this and every following action, `q` declines this and every following one.
+pub fn confirm(question: &str) -> Result<bool> {
+    let policy = policy();
+    if policy.assume_yes {
+        return Ok(true);
+    }
+    let mut answers = ANSWERS.lock().map_err(|_| anyhow!("Confirmation state is unavailable"))?;
+    if answers.all {
+        return Ok(true);
+    }
+    if answers.quit || !policy.interactive {
+        return Ok(false);
+    }
+
+    if policy.progress {
+        clear_progress_line();
+    }
+    loop {
+        eprint!("{} [y]es/[n]o/[a]ll/[q]uit: ", question);
+        let _ = stderr().flush();
+        let Answer::Line(answer) = read_answer(None)? else {
+            //stdin closed, treat as declining everything from now on
+            answers.quit = true;
+            return Ok(false);
+        };
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" | "" => return Ok(false),
+            "a" | "all" => {
+                answers.all = true;
+                return Ok(true);
+            }
+            "q" | "quit" => {
+                answers.quit = true;
+                return Ok(false);
+            }
+            _ => eprintln!("Please answer y, n, a or q"),
+        }
+    }
+}
+
+/// Ask which of the options to go with, reading its number from stdin.
+///
+/// Nothing is chosen when stdin is not a terminal, the answer is empty or `s`, or no answer comes within the timeout.
+/// Answering `q` skips this and every following question, confirmations included.
+pub fn choose(question: &str, options: &[String], timeout: Option<Duration>) -> Result<Option<usize>> {
+    let policy = policy();
+    let mut answers = ANSWERS.lock().map_err(|_| anyhow!("Confirmation state is unavailable"))?;
+    if answers.quit || !policy.interactive {
+        return Ok(None);
+    }
+
+    if policy.progress {
+        clear_progress_line();
+    }
+    eprintln!("{}", question);
+    for (number, option) in (1..).zip(options) {
+        eprintln!("  {}) {}", number, option);
+    }
+    loop {
+        eprint!("Choose 1-{} or [s]kip/[q]uit: ", options.len());
+        let _ = stderr().flush();
+        let answer = match read_answer(timeout)? {
+            Answer::Line(answer) => answer,
+            Answer::Closed => {
+                answers.quit = true;
+                return Ok(None);
+            }
+            Answer::TimedOut => {
+                eprintln!("no answer, skipping");
+                return Ok(None);
+            }
+        };
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "s" | "skip" | "" => return Ok(None),
+            "q" | "quit" => {
+                answers.quit = true;
+                return Ok(None);
+            }
+            choice => match choice.parse::<usize>() {
+                Ok(number) if (1..=options.len()).contains(&number) => return Ok(Some(number - 1)),
+                _ => eprintln!("Please answer a number from 1 to {}, s or q", options.len()),
+            },
+        }
+    }
+}
+
+// the next line typed, waiting at most the timeout if there is one
+fn read_answer(timeout: Option<Duration>) -> Result<Answer> {
+    let lines = STDIN_LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut line = String::new();
+            let line = match stdin().lock().read_line(&mut line) {
+                Ok(read) if read > 0 => Some(line),
+                _ => None,
+            };
+            let closed = line.is_none();
+            if sender.send(line).is_err() || closed {
+                break;
+            }
+        });
+        Mutex::new(receiver)
+    });
+    let lines = lines.lock().map_err(|_| anyhow!("Standard input is unavailable"))?;
+    let line = match timeout {
+        Some(timeout) => match lines.recv_timeout(timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => return Ok(Answer::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => None,
+        },
+        None => lines.recv().ok().flatten(),
+    };
+    Ok(line.map_or(Answer::Closed, Answer::Line))
+}
+
+fn truncate_left(text: &str, width: usize) -> String {
+    let count = text.chars().count();
+    if count <= width {
+        text.to_owned()
+    } else {
+        let tail: String = text.chars().skip(count - width + 3).collect();
+        format!("...{}", tail)
+    }
+}
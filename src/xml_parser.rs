@@ -1,11 +1,148 @@
-use crate::models::DataFile;
-use anyhow::Result;
+use crate::archive::GZIP_MAGIC;
+use crate::models::{DataFile, Game, Header};
+use anyhow::{anyhow, Result};
 use camino::Utf8Path;
-use quick_xml::de::from_reader;
-use std::{fs::File, io::BufReader};
+use flate2::read::GzDecoder;
+use quick_xml::de::from_str;
+use quick_xml::events::Event;
+use quick_xml::se::Serializer;
+use quick_xml::{Reader, Writer};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
 
-pub fn parse_file(path: &Utf8Path) -> Result<DataFile> {
-    let file = File::open(path)?;
-    let data: DataFile = from_reader(BufReader::new(file))?;
-    Ok(data)
+const DAT_PROLOG: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+"#;
+
+/// A top level element of a DAT, in the order they appear in the file
+pub enum DatElement {
+    Header(Header),
+    Game(Game),
+}
+
+/// Read the DAT one element at a time, so only a single game is held in memory however large the file.
+/// A path of `-` reads the DAT from stdin, and a gzip compressed DAT is decompressed whatever its name.
+/// Elements other than the header and games are skipped
+pub fn for_each_element(path: &Utf8Path, mut each: impl FnMut(DatElement) -> Result<()>) -> Result<()> {
+    let mut reader = Reader::from_reader(open_dat(path)?);
+    let mut buf = Vec::new();
+    loop {
+        let event = reader.read_event_into(&mut buf)?.into_owned();
+        buf.clear();
+        match &event {
+            Event::Start(start) | Event::Empty(start) => match start.name().as_ref() {
+                b"header" => each(DatElement::Header(from_str(&read_element(&mut reader, event)?)?))?,
+                b"game" => each(DatElement::Game(from_str(&read_element(&mut reader, event)?)?))?,
+                _ => {}
+            },
+            Event::Eof => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn open_dat(path: &Utf8Path) -> Result<Box<dyn BufRead>> {
+    let source: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut reader = BufReader::new(source);
+    //told apart by its magic bytes, as a DAT from stdin has no name to go by
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// the xml of the element that starts with the given event, including everything inside it
+fn read_element(reader: &mut Reader<impl BufRead>, start: Event<'static>) -> Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    let mut depth = usize::from(matches!(start, Event::Start(_)));
+    writer.write_event(start)?;
+    let mut buf = Vec::new();
+    while depth > 0 {
+        let event = reader.read_event_into(&mut buf)?;
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            Event::Eof => return Err(anyhow!("Unexpected end of file inside an element")),
+            _ => {}
+        }
+        writer.write_event(event)?;
+        buf.clear();
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Write the data as a Logiqx XML DAT that `for_each_element` reads back
+pub fn write_datafile(data: &DataFile, out: &mut impl Write) -> Result<()> {
+    let mut xml = String::from(DAT_PROLOG);
+    let mut serializer = Serializer::with_root(&mut xml, Some("datafile"))?;
+    serializer.indent('\t', 1);
+    data.serialize(serializer)?;
+    xml.push('\n');
+    out.write_all(xml.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, DAT};
+    use std::fs;
+
+    // the number of games, roms and disks streamed from the DAT
+    fn streamed_counts(path: &Utf8Path) -> Result<(usize, usize, usize)> {
+        let mut counts = (0, 0, 0);
+        for_each_element(path, |element| {
+            if let DatElement::Game(game) = element {
+                counts.0 += 1;
+                counts.1 += game.roms.len();
+                counts.2 += game.disks.len();
+            }
+            Ok(())
+        })?;
+        Ok(counts)
+    }
+
+    #[test]
+    fn streamed_games_match_reading_the_whole_dat() -> Result<()> {
+        let temp = TempDir::new();
+        let path = temp.path().join("test.dat");
+        fs::write(&path, DAT)?;
+
+        let data: DataFile = from_str(DAT)?;
+        let roms = data.games.iter().map(|game| game.roms.len()).sum();
+        let disks = data.games.iter().map(|game| game.disks.len()).sum();
+        assert_eq!((data.games.len(), roms, disks), (3, 3, 1));
+        assert_eq!(streamed_counts(&path)?, (data.games.len(), roms, disks));
+
+        let mut names = Vec::new();
+        for_each_element(&path, |element| {
+            match element {
+                DatElement::Header(header) => names.push(header.name),
+                DatElement::Game(game) => names.push(game.name),
+            }
+            Ok(())
+        })?;
+        assert_eq!(names, ["Test", "Game A", "Game B", "Game C"]);
+        Ok(())
+    }
+
+    #[test]
+    fn gzipped_dats_are_read_by_their_contents() -> Result<()> {
+        let temp = TempDir::new();
+        let plain = temp.path().join("test.dat");
+        fs::write(&plain, DAT)?;
+        //named like a plain DAT, so only the magic bytes tell it is compressed
+        let gzipped = temp.path().join("compressed.dat");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&gzipped)?, flate2::Compression::default());
+        encoder.write_all(DAT.as_bytes())?;
+        encoder.finish()?;
+        assert_eq!(streamed_counts(&gzipped)?, streamed_counts(&plain)?);
+        Ok(())
+    }
 }